@@ -1,15 +1,18 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use clap::builder::{PossibleValuesParser, ValueParser};
 use clap::{
     Arg, ArgAction, ArgGroup, ArgMatches, Command, crate_authors, crate_description, crate_name,
-    crate_version,
+    crate_version, value_parser,
 };
-use shavee_core::structs::TwoFactorMode;
+use shavee_core::password::KdfParams;
+use shavee_core::structs::{KeyFormat, TwoFactorMode};
 use shavee_core::zfs::Dataset;
 
 // CLAP Args Validation
 const YUBI_SLOTS: [&str; 2] = ["1", "2"];
+const KEY_FORMATS: [&str; 3] = ["passphrase", "raw", "hex"];
 // CLAP ENV Args
 const SHAVEE_CREATE: &str = "SHAVEE_CREATE";
 const SHAVEE_YUBIKEY: &str = "SHAVEE_YUBIKEY";
@@ -18,24 +21,76 @@ const SHAVEE_RECURSIVE: &str = "SHAVEE_MODE_RECURSIVE";
 const SHAVEE_AUTO_DETECT: &str = "SHAVEE_AUTO_DETECT";
 const SHAVEE_MODE_PRINT_WITH_NAME: &str = "SHAVEE_MODE_PRINT_WITH_NAME";
 const SHAVEE_MODE_MOUNT: &str = "SHAVEE_MODE_MOUNT";
+const SHAVEE_MODE_LOCK: &str = "SHAVEE_MODE_LOCK";
+const SHAVEE_FORCE: &str = "SHAVEE_FORCE";
+const SHAVEE_MOUNT_LEGACY: &str = "SHAVEE_MOUNT_LEGACY";
+const SHAVEE_DRY_RUN: &str = "SHAVEE_DRY_RUN";
+const SHAVEE_KEY_CHECK: &str = "SHAVEE_KEY_CHECK";
+const SHAVEE_JSON: &str = "SHAVEE_JSON";
 const SHAVEE_YUBIKEY_SLOT: &str = "SHAVEE_YUBIKEY_SLOT";
 const SHAVEE_ZFS_DATASET: &str = "SHAVEE_ZFS_DATASET";
 const SHAVEE_ZFS_KEYFILE: &str = "SHAVEE_ZFS_KEYFILE";
 const SHAVEE_FILE_PORT: &str = "SHAVEE_FILE_PORT";
+const SHAVEE_KEYFORMAT: &str = "SHAVEE_KEYFORMAT";
+const SHAVEE_KDF_MEMORY: &str = "SHAVEE_KDF_MEMORY";
+const SHAVEE_KDF_ITERATIONS: &str = "SHAVEE_KDF_ITERATIONS";
+const SHAVEE_KDF_LANES: &str = "SHAVEE_KDF_LANES";
+const SHAVEE_KDF_TARGET: &str = "SHAVEE_KDF_TARGET";
+const SHAVEE_KDF_WORKERS: &str = "SHAVEE_KDF_WORKERS";
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Operations {
     Create {
         datasets: Arc<[Dataset]>,
+        key_format: KeyFormat,
+        kdf: KdfParams,
+        /// Tune `kdf` on this machine for this hash time before creating.
+        kdf_target: Option<Duration>,
+        /// Print the planned ZFS operations instead of creating.
+        dry_run: bool,
+        /// Store a key check value, see `shavee_core::logic::key_check`.
+        key_check: bool,
     },
     Mount {
         datasets: Arc<[Dataset]>,
         recursive: bool,
+        /// Concurrent Argon2 hashes, the `Unlocker` default if not set.
+        kdf_workers: Option<usize>,
+        /// Also mount datasets with `mountpoint=legacy` through mount(8).
+        mount_legacy: bool,
+        dry_run: bool,
     },
     PrintDataset {
         datasets: Arc<[Dataset]>,
         recursive: bool,
         printwithname: bool,
+        kdf_workers: Option<usize>,
+    },
+    Lock {
+        datasets: Arc<[Dataset]>,
+        recursive: bool,
+        force: bool,
+    },
+    Rekey {
+        datasets: Arc<[Dataset]>,
+        key_format: Option<KeyFormat>,
+        dry_run: bool,
+        key_check: bool,
+    },
+    Passwd {
+        datasets: Arc<[Dataset]>,
+    },
+    Status {
+        datasets: Arc<[Dataset]>,
+        recursive: bool,
+        /// Print JSON instead of a table.
+        json: bool,
+    },
+    BenchKdf {
+        target: Duration,
+        /// Largest Argon2 memory size to try, in KiB.
+        max_memory: u32,
+        lanes: u32,
     },
     PrintHelp,
 }
@@ -59,7 +114,7 @@ pub struct CliArgs {
 /// "Command line parsing with clap" https://www.fpcomplete.com/rust/command-line-parsing-clap/
 impl CliArgs {
     pub fn new() -> Self {
-        Self::new_from(std::env::args_os().into_iter()).unwrap_or_else(|e| e.exit())
+        Self::new_from(std::env::args_os()).unwrap_or_else(|e| e.exit())
     }
 
     /// new_from() function parses and validates the inputs
@@ -73,22 +128,106 @@ impl CliArgs {
     }
 
     fn from_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        if let Some(("rekey", sub_matches)) = matches.subcommand() {
+            return Ok(CliArgs {
+                operation: OperationMode::Manual {
+                    operation: Operations::Rekey {
+                        datasets: parse_datasets(sub_matches)?.into(),
+                        key_format: sub_matches
+                            .contains_id("keyformat")
+                            .then(|| parse_key_format(sub_matches))
+                            .transpose()?,
+                        dry_run: sub_matches.get_flag("dryrun"),
+                        key_check: sub_matches.get_flag("keycheck"),
+                    },
+                },
+                second_factor: parse_second_factor(sub_matches)?,
+            });
+        }
+
+        if let Some(("passwd", sub_matches)) = matches.subcommand() {
+            return Ok(CliArgs {
+                operation: OperationMode::Manual {
+                    operation: Operations::Passwd {
+                        datasets: parse_datasets(sub_matches)?.into(),
+                    },
+                },
+                second_factor: TwoFactorMode::Password,
+            });
+        }
+
+        if let Some(("status", sub_matches)) = matches.subcommand() {
+            return Ok(CliArgs {
+                operation: OperationMode::Manual {
+                    operation: Operations::Status {
+                        datasets: parse_datasets(sub_matches)?.into(),
+                        recursive: sub_matches.get_flag("recursive"),
+                        json: sub_matches.get_flag("json"),
+                    },
+                },
+                second_factor: TwoFactorMode::Password,
+            });
+        }
+
+        if let Some(("bench-kdf", sub_matches)) = matches.subcommand() {
+            return Ok(CliArgs {
+                operation: OperationMode::Manual {
+                    operation: Operations::BenchKdf {
+                        target: parse_millis(sub_matches, "target"),
+                        max_memory: sub_matches
+                            .get_one::<u32>("maxmemory")
+                            .map_or(0, |mib| mib * 1024),
+                        lanes: sub_matches
+                            .get_one::<u32>("lanes")
+                            .copied()
+                            .unwrap_or_default(),
+                    },
+                },
+                second_factor: TwoFactorMode::Password,
+            });
+        }
+
         let datasets = parse_datasets(matches)?;
         let datasets: Arc<[Dataset]> = datasets.into();
 
         let operation = if matches.get_flag("create") {
-            Operations::Create { datasets }
+            Operations::Create {
+                datasets,
+                key_format: parse_key_format(matches)?,
+                kdf: parse_kdf_params(matches)?,
+                kdf_target: matches
+                    .get_flag("apply")
+                    .then(|| parse_millis(matches, "kdftarget")),
+                dry_run: matches.get_flag("dryrun"),
+                key_check: matches.get_flag("keycheck"),
+            }
         } else if matches.get_flag("mount") {
             Operations::Mount {
                 datasets,
                 recursive: matches.get_flag("recursive"),
+                kdf_workers: matches.get_one::<u16>("kdfworkers").map(|&n| n.into()),
+                mount_legacy: matches.get_flag("mountlegacy"),
+                dry_run: matches.get_flag("dryrun"),
+            }
+        } else if matches.get_flag("lock") {
+            Operations::Lock {
+                datasets,
+                recursive: matches.get_flag("recursive"),
+                force: matches.get_flag("force"),
             }
         } else if matches.get_flag("print") {
             Operations::PrintDataset {
                 datasets,
                 recursive: matches.get_flag("recursive"),
                 printwithname: matches.get_flag("printwithname"),
+                kdf_workers: matches.get_one::<u16>("kdfworkers").map(|&n| n.into()),
             }
+        } else if matches.get_flag("dryrun") {
+            // Checked here, grouping create and mount in clap would stop enforcing `requires("create")`
+            return Err(clap::Error::raw(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--dry-run must be used with --create or --mount",
+            ));
         } else {
             Operations::PrintHelp
         };
@@ -123,38 +262,11 @@ fn cli() -> Command {
                 .requires("zset")
                 .next_line_help(true)
                 .help("Create/Change key of a ZFS dataset with the derived encryption key. Must be used with --zset"),
-            Arg::new("zset")
-                .short('z')
-                .long("zset")
-                .env(SHAVEE_ZFS_DATASET)
-                .num_args(1..)
-                .value_name("ZFS dataset")
+            zset_arg()
                 .required(true)
-                .next_line_help(true)
                 .help("ZFS Dataset eg. \"zroot/data/home\"\n\
                        If present in conjunction with any of the other options, it will try to unlock and mount the \
                        given dataset with the derived key instead of printing it. Takes zfs dataset path as argument."),
-            Arg::new("yubikey")
-                .short('y')
-                .long("yubi")
-                .env(SHAVEE_YUBIKEY)
-                .num_args(0..=1)
-                .value_name("Yubikey Serial")
-                .value_parser(ValueParser::new(yubikey_serial_parser))
-                .help("Use Yubikey HMAC as second factor")
-                .hide(!cfg!(feature = "yubikey"))
-                .conflicts_with("keyfile"),
-            Arg::new("slot")
-                .short('s')
-                .long("slot")
-                .env(SHAVEE_YUBIKEY_SLOT)
-                .num_args(1)
-                .help("Yubikey HMAC Slot")
-                .value_name("HMAC slot")
-                .default_value("2")
-                .value_parser(PossibleValuesParser::new(YUBI_SLOTS))
-                .hide(!cfg!(feature = "yubikey"))
-                .requires("yubikey"),
             Arg::new("print")
                 .short('p')
                 .long("print")
@@ -169,6 +281,27 @@ fn cli() -> Command {
                 .action(ArgAction::SetTrue)
                 .help("Unlock and Mount Dataset")
                 .requires("zset"),
+            Arg::new("lock")
+                .short('u')
+                .long("lock")
+                .env(SHAVEE_MODE_LOCK)
+                .action(ArgAction::SetTrue)
+                .help("Unmount Dataset and unload its key")
+                .requires("zset")
+                .conflicts_with_all(["create", "auto"]),
+            Arg::new("force")
+                .long("force")
+                .env(SHAVEE_FORCE)
+                .action(ArgAction::SetTrue)
+                .help("Force unmounting busy Datasets when locking")
+                .requires("lock"),
+            Arg::new("mountlegacy")
+                .long("mount-legacy")
+                .env(SHAVEE_MOUNT_LEGACY)
+                .action(ArgAction::SetTrue)
+                .help("Also mount Datasets with mountpoint=legacy using mount(8) and /etc/fstab")
+                .requires("mount")
+                .conflicts_with("print"),
             Arg::new("printwithname")
                 .short('d')
                 .long("dataset")
@@ -191,38 +324,259 @@ fn cli() -> Command {
                 .long("recursive")
                 .env(SHAVEE_RECURSIVE)
                 .action(ArgAction::SetTrue)
-                .help("Perform Mount or Print Operations recursively")
+                .help("Perform Mount, Lock or Print Operations recursively")
                 .requires("zset")
                 .requires("recursivegroup"),
-            Arg::new("keyfile")
-                .short('f')
-                .long("file")
-                .env(SHAVEE_ZFS_KEYFILE)
-                .help("Use any file as second factor, takes filepath, SFTP or a HTTP(S) location as an argument. \
-                       If SIZE is entered, the first SIZE in bytes will be used to generate hash. It must be number between \
-                       1 and 2^(64).")
-                .hide(!cfg!(feature = "file"))
-                .value_name("FILE|ADDRESS [SIZE]")
-                .num_args(1..=2)
-                .conflicts_with("yubikey"),
-            Arg::new("port")
-                .short('P')
-                .long("port")
-                .env(SHAVEE_FILE_PORT)
+            Arg::new("keyformat")
+                .long("keyformat")
+                .env(SHAVEE_KEYFORMAT)
+                .num_args(1)
+                .value_name("format")
+                .default_value("passphrase")
+                .value_parser(PossibleValuesParser::new(KEY_FORMATS))
+                .requires("create")
+                .help("ZFS keyformat used for the derived key when creating a dataset. \
+                       \"raw\" and \"hex\" use the first 32 bytes of the derived key"),
+            Arg::new("kdfmemory")
+                .long("kdf-memory")
+                .env(SHAVEE_KDF_MEMORY)
+                .num_args(1)
+                .value_name("MiB")
+                .value_parser(value_parser!(u32).range(1..=(u32::MAX / 1024) as i64))
+                .requires("create")
+                .help("Argon2 memory size in MiB used to derive the key when creating a dataset. Defaults to 512"),
+            Arg::new("kdfiterations")
+                .long("kdf-iterations")
+                .env(SHAVEE_KDF_ITERATIONS)
+                .num_args(1)
+                .value_name("count")
+                .value_parser(value_parser!(u32).range(1..))
+                .requires("create")
+                .help("Argon2 iterations used to derive the key when creating a dataset. Defaults to 4"),
+            Arg::new("kdflanes")
+                .long("kdf-lanes")
+                .env(SHAVEE_KDF_LANES)
                 .num_args(1)
-                .value_name("port number")
-                .hide(!cfg!(feature = "file"))
-                .requires("keyfile")
-                .value_parser(clap::value_parser!(u16))
-                .help("Set port for HTTP(S) and SFTP requests"),
+                .value_name("count")
+                .value_parser(value_parser!(u32).range(1..))
+                .requires("create")
+                .help("Argon2 lanes used to derive the key when creating a dataset. Defaults to 4"),
+            Arg::new("apply")
+                .long("apply")
+                .action(ArgAction::SetTrue)
+                .requires("create")
+                .conflicts_with_all(["kdfmemory", "kdfiterations", "kdflanes"])
+                .help("Benchmark Argon2 like bench-kdf and create the dataset with the recommended parameters"),
+            Arg::new("kdftarget")
+                .long("kdf-target")
+                .env(SHAVEE_KDF_TARGET)
+                .num_args(1)
+                .value_name("ms")
+                .default_value("1000")
+                .value_parser(value_parser!(u64).range(1..))
+                .requires("apply")
+                .help("Target Argon2 hash time in milliseconds for --apply"),
+            Arg::new("kdfworkers")
+                .long("kdf-workers")
+                .env(SHAVEE_KDF_WORKERS)
+                .num_args(1)
+                .value_name("count")
+                .value_parser(value_parser!(u16).range(1..))
+                .requires("recursivegroup")
+                .conflicts_with("lock")
+                .help("Maximum number of Argon2 hashes computed at once when mounting or printing. \
+                       Each one uses the Argon2 memory size of its dataset. Defaults to the number of CPUs, at most 4"),
+            key_check_arg()
+                .requires("create")
+                .help("Store a key check value to detect a wrong password before asking ZFS. Any local \
+                       user can read it and test password guesses offline, see the README"),
+            dry_run_arg()
+                .requires("zset")
+                .conflicts_with_all(["print", "lock"])
+                .help("Print the ZFS operations a create or mount would perform without deriving keys \
+                       or changing the pool. The second factor is still checked"),
         ])
+        .args(second_factor_args())
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            Command::new("rekey")
+                .about("Re-wrap datasets with a new second factor. The current key is derived from the \
+                        stored Shavee properties, the new one from the given second factor options")
+                .arg_required_else_help(true)
+                .arg(
+                    zset_arg()
+                        .required(true)
+                        .help("ZFS Dataset(s) to rekey eg. \"zroot/data/home\""),
+                )
+                .args(second_factor_args())
+                .arg(
+                    Arg::new("keyformat")
+                        .long("keyformat")
+                        .env(SHAVEE_KEYFORMAT)
+                        .num_args(1)
+                        .value_name("format")
+                        .value_parser(PossibleValuesParser::new(KEY_FORMATS))
+                        .help("ZFS keyformat for the new key. Defaults to the dataset's current keyformat"),
+                )
+                .arg(key_check_arg().help(
+                    "Store a key check value to detect a wrong password before asking ZFS. Any \
+                     local user can read it and test password guesses offline, see the README",
+                ))
+                .arg(dry_run_arg().help(
+                    "Print the ZFS operations the rekey would perform without deriving keys or \
+                     changing the pool. The second factor is still checked",
+                )),
+        )
+        .subcommand(
+            Command::new("passwd")
+                .about("Change the password of datasets, keeping their second factor. Prompts for the \
+                        old and the new password")
+                .arg_required_else_help(true)
+                .arg(
+                    zset_arg()
+                        .required(true)
+                        .help("ZFS Dataset(s) to change the password of eg. \"zroot/data/home\""),
+                ),
+        )
+        .subcommand(
+            Command::new("status")
+                .about("Show the Shavee configuration and encryption of datasets, with warnings about \
+                        settings worth a look like the static fallback salt or missing properties")
+                .arg_required_else_help(true)
+                .arg(
+                    zset_arg()
+                        .required(true)
+                        .help("ZFS Dataset(s) to inspect eg. \"zroot/data/home\""),
+                )
+                .arg(
+                    Arg::new("recursive")
+                        .short('r')
+                        .long("recursive")
+                        .env(SHAVEE_RECURSIVE)
+                        .action(ArgAction::SetTrue)
+                        .help("Also inspect all child datasets"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .env(SHAVEE_JSON)
+                        .action(ArgAction::SetTrue)
+                        .help("Print JSON instead of a table"),
+                ),
+        )
+        .subcommand(
+            Command::new("bench-kdf")
+                .about("Measure Argon2 hash time and memory on this machine and recommend the \
+                        --kdf-* parameters hitting a target unlock time. File and Yubikey \
+                        datasets hash two to three times per unlock")
+                .arg(
+                    Arg::new("target")
+                        .long("target")
+                        .num_args(1)
+                        .value_name("ms")
+                        .default_value("1000")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .help("Target hash time in milliseconds"),
+                )
+                .arg(
+                    Arg::new("maxmemory")
+                        .long("max-memory")
+                        .num_args(1)
+                        .value_name("MiB")
+                        .default_value("512")
+                        .value_parser(value_parser!(u32).range(1..=(u32::MAX / 1024) as i64))
+                        .help("Largest memory size to try in MiB"),
+                )
+                .arg(
+                    Arg::new("lanes")
+                        .long("lanes")
+                        .num_args(1)
+                        .value_name("count")
+                        .default_value("4")
+                        .value_parser(value_parser!(u32).range(1..))
+                        .help("Argon2 lanes"),
+                ),
+        )
         .group(
             ArgGroup::new("recursivegroup")
-                .args(["mount", "print"])
+                .args(["mount", "lock", "print"])
                 .multiple(false),
         )
 }
 
+fn zset_arg() -> Arg {
+    Arg::new("zset")
+        .short('z')
+        .long("zset")
+        .env(SHAVEE_ZFS_DATASET)
+        .num_args(1..)
+        .value_name("ZFS dataset")
+        .next_line_help(true)
+}
+
+fn dry_run_arg() -> Arg {
+    Arg::new("dryrun")
+        .long("dry-run")
+        .env(SHAVEE_DRY_RUN)
+        .action(ArgAction::SetTrue)
+}
+
+fn key_check_arg() -> Arg {
+    Arg::new("keycheck")
+        .long("key-check")
+        .env(SHAVEE_KEY_CHECK)
+        .action(ArgAction::SetTrue)
+}
+
+/// Options selecting the second factor, shared by the top level command and subcommands.
+fn second_factor_args() -> [Arg; 4] {
+    [
+        Arg::new("yubikey")
+            .short('y')
+            .long("yubi")
+            .env(SHAVEE_YUBIKEY)
+            .num_args(0..=1)
+            .value_name("Yubikey Serial")
+            .value_parser(ValueParser::new(yubikey_serial_parser))
+            .help("Use Yubikey HMAC as second factor")
+            .hide(!cfg!(feature = "yubikey"))
+            .conflicts_with("keyfile"),
+        Arg::new("slot")
+            .short('s')
+            .long("slot")
+            .env(SHAVEE_YUBIKEY_SLOT)
+            .num_args(1)
+            .help("Yubikey HMAC Slot")
+            .value_name("HMAC slot")
+            .default_value("2")
+            .value_parser(PossibleValuesParser::new(YUBI_SLOTS))
+            .hide(!cfg!(feature = "yubikey"))
+            .requires("yubikey"),
+        Arg::new("keyfile")
+            .short('f')
+            .long("file")
+            .env(SHAVEE_ZFS_KEYFILE)
+            .help("Use any file as second factor, takes filepath, SFTP or a HTTP(S) location as an argument. \
+                   If SIZE is entered, the first SIZE in bytes will be used to generate hash. It must be number between \
+                   1 and 2^(64).")
+            .hide(!cfg!(feature = "file"))
+            .value_name("FILE|ADDRESS [SIZE]")
+            .num_args(1..=2)
+            .conflicts_with("yubikey"),
+        Arg::new("port")
+            .short('P')
+            .long("port")
+            .env(SHAVEE_FILE_PORT)
+            .num_args(1)
+            .value_name("port number")
+            .hide(!cfg!(feature = "file"))
+            .requires("keyfile")
+            .value_parser(clap::value_parser!(u16))
+            .help("Set port for HTTP(S) and SFTP requests"),
+    ]
+}
+
 fn parse_datasets(matches: &ArgMatches) -> Result<Vec<Dataset>, clap::Error> {
     matches
         .get_many::<String>("zset")
@@ -288,6 +642,36 @@ fn parse_second_factor(matches: &ArgMatches) -> Result<TwoFactorMode, clap::Erro
     Ok(TwoFactorMode::Password)
 }
 
+fn parse_key_format(matches: &ArgMatches) -> Result<KeyFormat, clap::Error> {
+    matches
+        .get_one::<String>("keyformat")
+        .map_or(Ok(KeyFormat::default()), |format| format.parse())
+        .map_err(|_| clap::Error::new(clap::error::ErrorKind::InvalidValue))
+}
+
+/// Reads a duration given in milliseconds.
+fn parse_millis(matches: &ArgMatches, id: &str) -> Duration {
+    Duration::from_millis(matches.get_one::<u64>(id).copied().unwrap_or_default())
+}
+
+/// Reads the Argon2 parameters for create, using the defaults for the ones not given.
+fn parse_kdf_params(matches: &ArgMatches) -> Result<KdfParams, clap::Error> {
+    let default = KdfParams::default();
+    let memory = matches
+        .get_one::<u32>("kdfmemory")
+        .map_or(default.memory, |mib| mib * 1024);
+    let iterations = matches
+        .get_one::<u32>("kdfiterations")
+        .copied()
+        .unwrap_or(default.iterations);
+    let lanes = matches
+        .get_one::<u32>("kdflanes")
+        .copied()
+        .unwrap_or(default.lanes);
+    KdfParams::new(memory, iterations, lanes)
+        .map_err(|e| clap::Error::raw(clap::error::ErrorKind::InvalidValue, e.to_string()))
+}
+
 fn yubikey_serial_parser(serial: &str) -> Result<u32, std::io::Error> {
     if serial.len() != 8 {
         return Err(std::io::Error::new(
@@ -312,6 +696,30 @@ mod tests {
         }
 
         let valid_arguments_results_pairs = [
+            ArgResultPair {
+                arg: vec![
+                    "-m",
+                    "-r",
+                    "--kdf-workers",
+                    "2",
+                    "--mount-legacy",
+                    "--dry-run",
+                    "-z",
+                    "zroot/test",
+                ],
+                result: CliArgs {
+                    operation: OperationMode::Manual {
+                        operation: Operations::Mount {
+                            datasets: vec![Dataset::new("zroot/test".to_string()).unwrap()].into(),
+                            recursive: true,
+                            kdf_workers: Some(2),
+                            mount_legacy: true,
+                            dry_run: true,
+                        },
+                    },
+                    second_factor: TwoFactorMode::Password,
+                },
+            },
             ArgResultPair {
                 arg: vec!["-m", "-z", "zroot/test"],
                 result: CliArgs {
@@ -319,6 +727,22 @@ mod tests {
                         operation: Operations::Mount {
                             datasets: vec![Dataset::new("zroot/test".to_string()).unwrap()].into(),
                             recursive: false,
+                            kdf_workers: None,
+                            mount_legacy: false,
+                            dry_run: false,
+                        },
+                    },
+                    second_factor: TwoFactorMode::Password,
+                },
+            },
+            ArgResultPair {
+                arg: vec!["-u", "-r", "--force", "-z", "zroot/test"],
+                result: CliArgs {
+                    operation: OperationMode::Manual {
+                        operation: Operations::Lock {
+                            datasets: vec![Dataset::new("zroot/test".to_string()).unwrap()].into(),
+                            recursive: true,
+                            force: true,
                         },
                     },
                     second_factor: TwoFactorMode::Password,
@@ -330,11 +754,199 @@ mod tests {
                     operation: OperationMode::Manual {
                         operation: Operations::Create {
                             datasets: vec![Dataset::new("zroot/test".to_string()).unwrap()].into(),
+                            key_format: KeyFormat::Passphrase,
+                            kdf: KdfParams::default(),
+                            kdf_target: None,
+                            dry_run: false,
+                            key_check: false,
                         },
                     },
                     second_factor: TwoFactorMode::Password,
                 },
             },
+            ArgResultPair {
+                arg: vec![
+                    "-c",
+                    "--kdf-memory",
+                    "64",
+                    "--kdf-iterations",
+                    "2",
+                    "-z",
+                    "zroot/test",
+                ],
+                result: CliArgs {
+                    operation: OperationMode::Manual {
+                        operation: Operations::Create {
+                            datasets: vec![Dataset::new("zroot/test".to_string()).unwrap()].into(),
+                            key_format: KeyFormat::Passphrase,
+                            kdf: KdfParams::new(65536, 2, 4).unwrap(),
+                            kdf_target: None,
+                            dry_run: false,
+                            key_check: false,
+                        },
+                    },
+                    second_factor: TwoFactorMode::Password,
+                },
+            },
+            ArgResultPair {
+                arg: vec!["-c", "--apply", "--dry-run", "-z", "zroot/test"],
+                result: CliArgs {
+                    operation: OperationMode::Manual {
+                        operation: Operations::Create {
+                            datasets: vec![Dataset::new("zroot/test".to_string()).unwrap()].into(),
+                            key_format: KeyFormat::Passphrase,
+                            kdf: KdfParams::default(),
+                            kdf_target: Some(Duration::from_secs(1)),
+                            dry_run: true,
+                            key_check: false,
+                        },
+                    },
+                    second_factor: TwoFactorMode::Password,
+                },
+            },
+            ArgResultPair {
+                arg: vec!["bench-kdf"],
+                result: CliArgs {
+                    operation: OperationMode::Manual {
+                        operation: Operations::BenchKdf {
+                            target: Duration::from_secs(1),
+                            max_memory: 524288,
+                            lanes: 4,
+                        },
+                    },
+                    second_factor: TwoFactorMode::Password,
+                },
+            },
+            ArgResultPair {
+                arg: vec![
+                    "bench-kdf",
+                    "--target",
+                    "250",
+                    "--max-memory",
+                    "64",
+                    "--lanes",
+                    "2",
+                ],
+                result: CliArgs {
+                    operation: OperationMode::Manual {
+                        operation: Operations::BenchKdf {
+                            target: Duration::from_millis(250),
+                            max_memory: 65536,
+                            lanes: 2,
+                        },
+                    },
+                    second_factor: TwoFactorMode::Password,
+                },
+            },
+            ArgResultPair {
+                arg: vec![
+                    "-c",
+                    "--keyformat",
+                    "hex",
+                    "--key-check",
+                    "-z",
+                    "zroot/test",
+                ],
+                result: CliArgs {
+                    operation: OperationMode::Manual {
+                        operation: Operations::Create {
+                            datasets: vec![Dataset::new("zroot/test".to_string()).unwrap()].into(),
+                            key_format: KeyFormat::Hex,
+                            kdf: KdfParams::default(),
+                            kdf_target: None,
+                            dry_run: false,
+                            key_check: true,
+                        },
+                    },
+                    second_factor: TwoFactorMode::Password,
+                },
+            },
+            ArgResultPair {
+                arg: vec!["rekey", "-z", "zroot/a", "zroot/b"],
+                result: CliArgs {
+                    operation: OperationMode::Manual {
+                        operation: Operations::Rekey {
+                            datasets: vec![
+                                Dataset::new("zroot/a".to_string()).unwrap(),
+                                Dataset::new("zroot/b".to_string()).unwrap(),
+                            ]
+                            .into(),
+                            key_format: None,
+                            dry_run: false,
+                            key_check: false,
+                        },
+                    },
+                    second_factor: TwoFactorMode::Password,
+                },
+            },
+            ArgResultPair {
+                arg: vec![
+                    "rekey",
+                    "--keyformat",
+                    "raw",
+                    "--dry-run",
+                    "--key-check",
+                    "-z",
+                    "zroot/test",
+                ],
+                result: CliArgs {
+                    operation: OperationMode::Manual {
+                        operation: Operations::Rekey {
+                            datasets: vec![Dataset::new("zroot/test".to_string()).unwrap()].into(),
+                            key_format: Some(KeyFormat::Raw),
+                            dry_run: true,
+                            key_check: true,
+                        },
+                    },
+                    second_factor: TwoFactorMode::Password,
+                },
+            },
+            ArgResultPair {
+                arg: vec!["passwd", "-z", "zroot/test"],
+                result: CliArgs {
+                    operation: OperationMode::Manual {
+                        operation: Operations::Passwd {
+                            datasets: vec![Dataset::new("zroot/test".to_string()).unwrap()].into(),
+                        },
+                    },
+                    second_factor: TwoFactorMode::Password,
+                },
+            },
+            ArgResultPair {
+                arg: vec!["status", "-r", "--json", "-z", "zroot/a", "zroot/b"],
+                result: CliArgs {
+                    operation: OperationMode::Manual {
+                        operation: Operations::Status {
+                            datasets: vec![
+                                Dataset::new("zroot/a".to_string()).unwrap(),
+                                Dataset::new("zroot/b".to_string()).unwrap(),
+                            ]
+                            .into(),
+                            recursive: true,
+                            json: true,
+                        },
+                    },
+                    second_factor: TwoFactorMode::Password,
+                },
+            },
+            #[cfg(feature = "yubikey")]
+            ArgResultPair {
+                arg: vec!["rekey", "-y", "-z", "zroot/test"],
+                result: CliArgs {
+                    operation: OperationMode::Manual {
+                        operation: Operations::Rekey {
+                            datasets: vec![Dataset::new("zroot/test".to_string()).unwrap()].into(),
+                            key_format: None,
+                            dry_run: false,
+                            key_check: false,
+                        },
+                    },
+                    second_factor: TwoFactorMode::Yubikey {
+                        yslot: Some(2),
+                        serial: None,
+                    },
+                },
+            },
             #[cfg(feature = "yubikey")]
             ArgResultPair {
                 arg: vec!["-y", "-s", "1", "-c", "-z", "zroot/test"],
@@ -342,6 +954,11 @@ mod tests {
                     operation: OperationMode::Manual {
                         operation: Operations::Create {
                             datasets: vec![Dataset::new("zroot/test".to_string()).unwrap()].into(),
+                            key_format: KeyFormat::Passphrase,
+                            kdf: KdfParams::default(),
+                            kdf_target: None,
+                            dry_run: false,
+                            key_check: false,
                         },
                     },
                     second_factor: TwoFactorMode::Yubikey {
@@ -358,6 +975,9 @@ mod tests {
                         operation: Operations::Mount {
                             datasets: vec![Dataset::new("zroot/test".to_string()).unwrap()].into(),
                             recursive: false,
+                            kdf_workers: None,
+                            mount_legacy: false,
+                            dry_run: false,
                         },
                     },
                     second_factor: TwoFactorMode::File {
@@ -385,6 +1005,7 @@ mod tests {
                             datasets: vec![Dataset::new("zroot/test".to_string()).unwrap()].into(),
                             recursive: false,
                             printwithname: false,
+                            kdf_workers: None,
                         },
                     },
                     second_factor: TwoFactorMode::File {
@@ -403,9 +1024,45 @@ mod tests {
         }
 
         let invalid_arguments = [
-            vec![],         // No args
-            vec!["--zset"], // Missing zset value
-            vec!["-c"],     // Missing zset
+            vec![],                                                  // No args
+            vec!["--zset"],                                          // Missing zset value
+            vec!["-c"],                                              // Missing zset
+            vec!["-c", "--keyformat", "base64", "-z", "zroot/test"], // Invalid keyformat
+            vec!["-m", "--keyformat", "raw", "-z", "zroot/test"],    // Keyformat without create
+            vec!["--force", "-z", "zroot/test"],                     // Force without lock
+            vec!["-p", "--mount-legacy", "-z", "zroot/test"],        // Legacy without mount
+            vec!["-p", "--dry-run", "-z", "zroot/test"],             // Dry run of print
+            vec!["-u", "--dry-run", "-z", "zroot/test"],             // Dry run of lock
+            vec!["--dry-run", "-z", "zroot/test"],                   // Dry run without operation
+            vec!["passwd", "--dry-run", "-z", "zroot/test"],         // Dry run of passwd
+            vec!["status"],                                          // Missing zset
+            vec!["status", "-m", "-z", "zroot/test"],                // Status only inspects
+            vec!["-u", "-m", "-z", "zroot/test"],                    // Lock and mount
+            vec!["-a", "-u", "-z", "zroot/test"],                    // Auto lock
+            vec!["-m", "--kdf-lanes", "2", "-z", "zroot/test"],      // KDF without create
+            vec!["-c", "--kdf-iterations", "0", "-z", "zroot/test"], // Zero iterations
+            vec![
+                "-c",
+                "--kdf-memory",
+                "1",
+                "--kdf-lanes",
+                "1024",
+                "-z",
+                "zroot/test",
+            ], // Memory below 8 KiB per lane
+            vec!["--apply", "-z", "zroot/test"],                     // Apply without create
+            vec!["-c", "--apply", "--kdf-memory", "64", "-z", "zroot/test"], // Apply with fixed parameters
+            vec!["-c", "--kdf-target", "500", "-z", "zroot/test"],           // Target without apply
+            vec!["bench-kdf", "--target", "0"],                              // Zero target
+            vec!["-m", "--kdf-workers", "0", "-z", "zroot/test"],            // Zero workers
+            vec!["-c", "--kdf-workers", "2", "-z", "zroot/test"],            // Workers with create
+            vec!["-u", "--kdf-workers", "2", "-z", "zroot/test"],            // Workers with lock
+            vec!["rekey"],                                                   // Missing zset
+            vec!["passwd"],                                                  // Missing zset
+            vec!["passwd", "-y", "-z", "zroot/test"], // Passwd keeps the second factor
+            vec!["passwd", "--key-check", "-z", "zroot/test"], // Passwd keeps the key check
+            vec!["-m", "--key-check", "-z", "zroot/test"], // Key check without create
+            vec!["rekey", "-m", "-z", "zroot/test"],  // Mount is not a rekey option
             #[cfg(feature = "yubikey")]
             vec!["-y", "-s", "3", "-z", "zroot/test"], // Invalid slot
             #[cfg(feature = "file")]
@@ -0,0 +1,118 @@
+//! Error type of the shavee binary and the exit codes it maps to.
+//!
+//! Exit codes (also listed in the README):
+//!
+//! | Code | Meaning                                                      |
+//! |------|--------------------------------------------------------------|
+//! | 0    | Success                                                      |
+//! | 1    | Any other error                                              |
+//! | 2    | Invalid command line arguments                               |
+//! | 3    | Wrong password or second factor, the key was rejected by ZFS |
+//! | 4    | Yubikey not found or not responding                          |
+//! | 5    | Key file can't be read or downloaded                         |
+//! | 6    | ZFS operation failed                                         |
+//! | 7    | Shavee properties of a dataset are missing or invalid        |
+//!
+//! When several datasets fail the code of the first failure is used.
+
+use shavee_core::zfs::Dataset;
+use std::fmt;
+
+pub const EXIT_FAILURE: u8 = 1;
+pub const EXIT_INCORRECT_KEY: u8 = 3;
+pub const EXIT_DEVICE: u8 = 4;
+pub const EXIT_FILE: u8 = 5;
+pub const EXIT_ZFS: u8 = 6;
+pub const EXIT_PROPERTIES: u8 = 7;
+
+#[derive(Debug)]
+pub enum CliError {
+    /// A shavee_core operation failed.
+    Core(shavee_core::Error),
+    /// Some datasets failed, with the error of each one in order.
+    Datasets(Vec<(Dataset, shavee_core::Error)>),
+    /// Invalid input, e.g. the retyped password doesn't match.
+    Input(String),
+    /// Reading the password failed.
+    Io(std::io::Error),
+}
+
+impl CliError {
+    /// Returns the process exit code for this error.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Core(error) => core_exit_code(error),
+            CliError::Datasets(errors) => errors
+                .first()
+                .map_or(EXIT_FAILURE, |(_, error)| core_exit_code(error)),
+            CliError::Input(_) | CliError::Io(_) => EXIT_FAILURE,
+        }
+    }
+}
+
+fn core_exit_code(error: &shavee_core::Error) -> u8 {
+    match error {
+        shavee_core::Error::IncorrectKey(_) => EXIT_INCORRECT_KEY,
+        shavee_core::Error::Yubikey(_) => EXIT_DEVICE,
+        shavee_core::Error::Io(_) | shavee_core::Error::Curl(_) => EXIT_FILE,
+        shavee_core::Error::Zfs(_) => EXIT_ZFS,
+        shavee_core::Error::Properties(_) => EXIT_PROPERTIES,
+        _ => EXIT_FAILURE,
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Core(error) => write!(f, "{}", error),
+            CliError::Datasets(_) => write!(f, "Failed to process some Datasets"),
+            CliError::Input(message) => write!(f, "{}", message),
+            CliError::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+impl From<shavee_core::Error> for CliError {
+    fn from(error: shavee_core::Error) -> Self {
+        CliError::Core(error)
+    }
+}
+
+impl From<std::io::Error> for CliError {
+    fn from(error: std::io::Error) -> Self {
+        CliError::Io(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        use shavee_core::Error;
+        let dataset = Dataset::new("zroot/test".to_string()).unwrap();
+        let cases = [
+            (CliError::Core(Error::IncorrectKey("wrong".into())), 3),
+            (CliError::Core(Error::Yubikey("missing".into())), 4),
+            (CliError::Core(Error::Io(std::io::Error::other("gone"))), 5),
+            (CliError::Core(Error::Zfs("busy".into())), 6),
+            (CliError::Core(Error::Properties("no salt".into())), 7),
+            (CliError::Core(Error::Crypto("argon2".into())), 1),
+            (CliError::Input("Passwords do not match.".into()), 1),
+            (
+                CliError::Datasets(vec![
+                    (dataset.clone(), Error::Properties("no salt".into())),
+                    (dataset, Error::IncorrectKey("wrong".into())),
+                ]),
+                7,
+            ),
+            (CliError::Datasets(vec![]), 1),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.exit_code(), code, "{:?}", error);
+        }
+    }
+}
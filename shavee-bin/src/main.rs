@@ -1,20 +1,24 @@
 mod args;
+mod error;
 use args::*;
 use atty::Stream;
-use challenge_response::{ChallengeResponse, Device};
-#[cfg(feature = "file")]
-use shavee_core::filehash;
-use shavee_core::structs::TwoFactorMode;
+use error::CliError;
+use shavee_core::kdf::KdfScheme;
+use shavee_core::password::{self, KdfParams, KdfSession};
+use shavee_core::secret::{SecretBytes, Zeroizing};
+use shavee_core::status::{self, DatasetStatus};
+use shavee_core::structs::{KeyFormat, TwoFactorMode};
+use shavee_core::unlock::{self, UnlockAction, UnlockReport, UnlockStatus, Unlocker};
 use shavee_core::yubikey;
-use shavee_core::zfs::{self, Dataset};
-use std::collections::HashMap;
+use shavee_core::zfs::{self, Dataset, Encryption, ShaveeProperties};
+use std::fmt;
 use std::io::stdin;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::time::Duration;
 
 /// main() collect the arguments from command line, pass them to run() and print any
 /// messages upon exiting the program
-#[tokio::main]
-async fn main() -> std::process::ExitCode {
+fn main() -> std::process::ExitCode {
     //initializing the logger
     shavee_core::trace_init(true);
     // parse the arguments
@@ -22,312 +26,1023 @@ async fn main() -> std::process::ExitCode {
     let args = CliArgs::new();
     shavee_core::trace("Arguments parsed successfully.");
     // Only main() will terminate the executable with proper message and code
-    let code = match run(args).await {
+    match run(args) {
         Ok(None) => {
             shavee_core::trace("Exited successfully with no message!");
-            0
+            std::process::ExitCode::SUCCESS
         } // exit with no error code
         Ok(Some(passphrase)) => {
             shavee_core::trace("Exited successfully with a message!");
             println!("{}", passphrase); // print password if asked
-            0 // then exit with no error code
+            std::process::ExitCode::SUCCESS // then exit with no error code
         }
         Err(error) => {
             shavee_core::error("Exited with an error message!");
             eprintln!("Error: {}", error); // print error message
-            1 // then exit with generic error code 1
+            std::process::ExitCode::from(error.exit_code()) // then exit with the code of the error
         }
-    };
-    std::process::exit(code);
+    }
 }
 
-async fn run(args: CliArgs) -> Result<Option<String>, Box<dyn std::error::Error>> {
+fn run(args: CliArgs) -> Result<Option<String>, CliError> {
+    // Changing the password needs both the old and the new one
+    if let OperationMode::Manual {
+        operation: Operations::Passwd { datasets },
+    } = args.operation
+    {
+        return process_passwd(datasets);
+    }
+
+    // Inspecting datasets only reads their properties
+    if let OperationMode::Manual {
+        operation:
+            Operations::Status {
+                datasets,
+                recursive,
+                json,
+            },
+    } = args.operation
+    {
+        return process_status(datasets, recursive, json);
+    }
+
+    // Locking needs no key, so there is no password to ask for
+    if let OperationMode::Manual {
+        operation:
+            Operations::Lock {
+                datasets,
+                recursive,
+                force,
+            },
+    } = args.operation
+    {
+        return process_lock(datasets, recursive, force);
+    }
+
+    if let OperationMode::Manual {
+        operation:
+            Operations::BenchKdf {
+                target,
+                max_memory,
+                lanes,
+            },
+    } = args.operation
+    {
+        return process_bench_kdf(target, max_memory, lanes);
+    }
+
+    // A dry run derives no key, so there is no password to ask for
+    match args.operation {
+        OperationMode::Auto {
+            operation: operation @ Operations::Mount { dry_run: true, .. },
+        } => return process_dry_run(operation, None),
+        OperationMode::Manual {
+            operation:
+                operation @ (Operations::Create { dry_run: true, .. }
+                | Operations::Mount { dry_run: true, .. }
+                | Operations::Rekey { dry_run: true, .. }),
+        } => return process_dry_run(operation, Some(args.second_factor)),
+        _ => (),
+    }
+
     let password = get_password("Dataset Password: ")?;
     shavee_core::trace("Password has been entered successfully.");
     shavee_core::trace("Operation Mode:");
 
     match args.operation {
-        OperationMode::Auto { operation } => process_mount_print(operation, password, None).await,
+        OperationMode::Auto { operation } => process_mount_print(operation, password, None),
         OperationMode::Manual { operation } => match operation {
-            Operations::Create { datasets } => {
-                process_create(datasets, password, args.second_factor).await
-            }
-            _ => process_mount_print(operation, password, Some(args.second_factor)).await,
+            Operations::Create {
+                datasets,
+                key_format,
+                kdf,
+                kdf_target,
+                key_check,
+                ..
+            } => process_create(
+                datasets,
+                password,
+                args.second_factor,
+                key_format,
+                kdf,
+                kdf_target,
+                key_check,
+            ),
+            Operations::Rekey {
+                datasets,
+                key_format,
+                key_check,
+                ..
+            } => process_rekey(
+                datasets,
+                password,
+                args.second_factor,
+                key_format,
+                key_check,
+            ),
+            _ => process_mount_print(operation, password, Some(args.second_factor)),
         },
     }
 }
 
-fn get_password(prompt: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let password = if atty::is(Stream::Stdin) {
-        rpassword::prompt_password(prompt).map_err(|e| e.to_string())?
+fn get_password(prompt: &str) -> Result<SecretBytes, CliError> {
+    let password = Zeroizing::new(if atty::is(Stream::Stdin) {
+        rpassword::prompt_password(prompt)?
     } else {
         let mut input = String::new();
         stdin().read_line(&mut input)?;
         input
-    };
-    Ok(password.trim().to_string())
+    });
+    Ok(SecretBytes::from_slice(password.trim().as_bytes()))
 }
 
-async fn process_create(
-    datasets: Arc<[Dataset]>,
-    password: String,
-    second_factor: TwoFactorMode,
-) -> Result<Option<String>, Box<dyn std::error::Error>> {
+/// Asks for `password` again on a terminal and fails if the two don't match.
+fn confirm_password(prompt: &str, password: &[u8]) -> Result<(), CliError> {
     if atty::is(Stream::Stdin) {
-        let confirm =
-            rpassword::prompt_password("Retype  Password: ").map_err(|e| e.to_string())?;
-        if password != confirm.trim() {
-            return Err("Passwords do not match.".into());
+        let confirm = Zeroizing::new(rpassword::prompt_password(prompt)?);
+        if password != confirm.trim().as_bytes() {
+            return Err(CliError::Input("Passwords do not match.".to_string()));
         }
     }
+    Ok(())
+}
+
+fn process_create(
+    datasets: Arc<[Dataset]>,
+    password: SecretBytes,
+    second_factor: TwoFactorMode,
+    key_format: KeyFormat,
+    kdf: KdfParams,
+    kdf_target: Option<Duration>,
+    key_check: bool,
+) -> Result<Option<String>, CliError> {
+    confirm_password("Retype  Password: ", &password)?;
+
+    let kdf = match kdf_target {
+        Some(target) => tune_kdf(target)?,
+        None => kdf,
+    };
 
     for dataset in datasets.iter() {
         shavee_core::trace(&format!(
             "\tCreate ZFS dataset: \"{}\" using \"{:?}\" method.",
-            dataset.to_string(),
-            second_factor
+            dataset, second_factor
         ));
     }
 
+    let yubikeys = yubikey::fetch_yubikeys().ok();
+    let session = KdfSession::new(&password, 1);
+
     for dataset in datasets.iter() {
         let salt = shavee_core::logic::generate_salt();
-        let mut current_sf = second_factor.clone();
-
-        match &second_factor {
-            #[cfg(feature = "yubikey")]
-            TwoFactorMode::Yubikey { yslot, serial } => {
-                let yubikey = if serial.is_none() {
-                    let key = ChallengeResponse::new()?.find_device()?;
-                    current_sf = TwoFactorMode::Yubikey {
-                        yslot: *yslot,
-                        serial: key.serial,
-                    };
-                    key
-                } else {
-                    ChallengeResponse::new()?.find_device_from_serial(serial.unwrap())?
-                };
-                let yubikey = Mutex::new(yubikey);
-                dataset.yubi_create(password.as_bytes(), *yslot, &yubikey, &salt)?;
-            }
-            #[cfg(feature = "file")]
-            TwoFactorMode::File { file, port, size } => {
-                let filehash = shavee_core::filehash::get_filehash(file, *port, *size, &salt)?;
-                dataset.file_create(password.as_bytes(), filehash, &salt)?;
-            }
-            TwoFactorMode::Password => {
-                let hash = shavee_core::logic::password_mode_hash(password.as_bytes(), &salt)?;
-                dataset.create(&hash)?;
-            }
-        }
+        let (passphrase, current_sf) = unlock::derive_key(
+            &session,
+            &second_factor,
+            &salt,
+            KdfScheme::CURRENT,
+            &kdf,
+            yubikeys.as_deref(),
+        )?;
 
-        dataset.set_properties_2fa(
+        // Key and properties are written together so a failure can't leave them out of sync
+        dataset.create_2fa(
+            &passphrase,
             current_sf,
             &base64::Engine::encode(&shavee_core::logic::BASE64_ENGINE, salt),
+            key_format,
+            &kdf,
+            key_check,
+        )?;
+    }
+
+    Ok(None)
+}
+
+/// Picks the Argon2 parameters for `--apply` with the `bench-kdf` defaults.
+fn tune_kdf(target: Duration) -> Result<KdfParams, CliError> {
+    let default = KdfParams::default();
+    let tuning = password::tune_argon2(target, default.memory, default.lanes)?;
+    let kdf = tuning.recommended;
+    println!(
+        "Using Argon2 parameters: --kdf-memory {} --kdf-iterations {} --kdf-lanes {}",
+        kdf.memory / 1024,
+        kdf.iterations,
+        kdf.lanes
+    );
+    Ok(kdf)
+}
+
+/// Re-wraps each dataset from the second factor stored in its properties to `second_factor`.
+fn process_rekey(
+    datasets: Arc<[Dataset]>,
+    password: SecretBytes,
+    second_factor: TwoFactorMode,
+    key_format: Option<KeyFormat>,
+    key_check: bool,
+) -> Result<Option<String>, CliError> {
+    let yubikeys = yubikey::fetch_yubikeys().ok();
+
+    for dataset in datasets.iter() {
+        shavee_core::trace(&format!(
+            "\tRekey ZFS dataset: \"{}\" to \"{:?}\" method.",
+            dataset, second_factor
+        ));
+        unlock::rekey(
+            dataset,
+            &password,
+            &password,
+            Some(&second_factor),
+            key_format,
+            Some(key_check),
+            yubikeys.as_deref(),
+        )?;
+    }
+
+    Ok(None)
+}
+
+/// Changes the password of each dataset, keeping the second factor stored in its properties.
+fn process_passwd(datasets: Arc<[Dataset]>) -> Result<Option<String>, CliError> {
+    let old_password = get_password("Old Password: ")?;
+    let new_password = get_password("New Password: ")?;
+    confirm_password("Retype New Password: ", &new_password)?;
+
+    let yubikeys = yubikey::fetch_yubikeys().ok();
+
+    for dataset in datasets.iter() {
+        shavee_core::trace(&format!(
+            "\tChange password of ZFS dataset: \"{}\".",
+            dataset
+        ));
+        unlock::rekey(
+            dataset,
+            &old_password,
+            &new_password,
+            None,
+            None,
+            None,
+            yubikeys.as_deref(),
         )?;
     }
 
     Ok(None)
 }
 
-async fn process_mount_print(
+fn process_mount_print(
     operation: Operations,
-    password: String,
+    password: SecretBytes,
     second_factor: Option<TwoFactorMode>,
-) -> Result<Option<String>, Box<dyn std::error::Error>> {
-    let (datasets, recursive, print_with_name) = match operation {
+) -> Result<Option<String>, CliError> {
+    let (datasets, recursive, kdf_workers, mount_legacy, print_with_name) = match operation {
         Operations::Mount {
             datasets,
             recursive,
-        } => (datasets, recursive, None),
+            kdf_workers,
+            mount_legacy,
+            ..
+        } => (datasets, recursive, kdf_workers, mount_legacy, None),
         Operations::PrintDataset {
             datasets,
             recursive,
             printwithname,
-        } => (datasets, recursive, Some(printwithname)),
+            kdf_workers,
+        } => (datasets, recursive, kdf_workers, false, Some(printwithname)),
         _ => return Ok(None),
     };
 
-    let sets = if recursive {
-        zfs::resolve_recursive(&datasets)?
+    let action = if print_with_name.is_some() {
+        UnlockAction::Print
     } else {
-        datasets
+        UnlockAction::Mount
     };
+    let mut unlocker = Unlocker::new(&datasets, &password)
+        .second_factor(second_factor)
+        .recursive(recursive)
+        .action(action)
+        .mount_legacy(mount_legacy);
+    if let Some(kdf_workers) = kdf_workers {
+        unlocker = unlocker.kdf_workers(kdf_workers);
+    }
+    let report = unlocker.run()?;
+
+    let sets: Vec<Dataset> = report.outcomes.iter().map(|o| o.dataset.clone()).collect();
+    let maxlength = zfs::get_max_namesize(&sets);
+
+    if print_with_name.is_some() {
+        print_keys(&report, print_with_name == Some(true), maxlength);
+    } else {
+        print_outcomes(&report, maxlength);
+    }
 
+    report_errors(report)?;
+
+    Ok(None)
+}
+
+/// Prints the ZFS operations of a create, mount or rekey without changing the pool. Stored
+/// properties and the second factor are checked, but no key is derived.
+fn process_dry_run(
+    operation: Operations,
+    second_factor: Option<TwoFactorMode>,
+) -> Result<Option<String>, CliError> {
     let yubikeys = yubikey::fetch_yubikeys().ok();
-    let sethashes = get_key_hash(&sets, password, yubikeys, second_factor).await?;
 
-    let mut errors = Vec::new();
-    let maxlength = zfs::get_max_namesize(&sets);
+    match operation {
+        Operations::Create {
+            datasets,
+            key_format,
+            kdf,
+            kdf_target,
+            key_check,
+            ..
+        } => {
+            let kdf = match kdf_target {
+                Some(target) => tune_kdf(target)?,
+                None => kdf,
+            };
+            let dry_run = unlock::dry_run_create(
+                &datasets,
+                &second_factor.unwrap_or(TwoFactorMode::Password),
+                key_format,
+                &kdf,
+                key_check,
+                yubikeys.as_deref(),
+            );
+            print_operations(&dry_run.operations);
+            report_errors(dry_run.report)?;
+        }
+        Operations::Rekey {
+            datasets,
+            key_format,
+            key_check,
+            ..
+        } => {
+            let dry_run = unlock::dry_run_rekey(
+                &datasets,
+                second_factor.as_ref(),
+                key_format,
+                Some(key_check),
+                yubikeys.as_deref(),
+            );
+            print_operations(&dry_run.operations);
+            report_errors(dry_run.report)?;
+        }
+        Operations::Mount {
+            datasets,
+            recursive,
+            mount_legacy,
+            ..
+        } => {
+            let dry_run = Unlocker::new(&datasets, b"")
+                .second_factor(second_factor)
+                .recursive(recursive)
+                .mount_legacy(mount_legacy)
+                .dry_run()?;
 
-    if let Some(true) = print_with_name {
-        println!("\x1b[1m{:<maxlength$}    {}\x1b[0m", "Dataset", "Key");
-        println!();
+            let sets: Vec<Dataset> = dry_run
+                .report
+                .outcomes
+                .iter()
+                .map(|o| o.dataset.clone())
+                .collect();
+            print_operations(&dry_run.operations);
+            println!();
+            print_outcomes(&dry_run.report, zfs::get_max_namesize(&sets));
+            report_errors(dry_run.report)?;
+        }
+        _ => (),
     }
 
-    for dataset in sets.iter() {
-        let name = dataset.to_string();
-        if let Some(pass) = sethashes.get(&name) {
-            if pass.len() == 86 {
-                if let Some(with_name) = print_with_name {
-                    if with_name {
-                        println!("{:<maxlength$}    {}", name, pass);
-                    } else {
-                        println!("{}", pass);
-                    }
-                } else {
-                    if dataset.load_key(pass).is_ok() {
-                        let _ = dataset.mount();
-                    }
-                }
-            } else {
-                errors.push((name, pass.clone()));
-            }
+    Ok(None)
+}
+
+fn process_lock(
+    datasets: Arc<[Dataset]>,
+    recursive: bool,
+    force: bool,
+) -> Result<Option<String>, CliError> {
+    let report = unlock::lock(&datasets, recursive, force)?;
+
+    let sets: Vec<Dataset> = report.outcomes.iter().map(|o| o.dataset.clone()).collect();
+    print_outcomes(&report, zfs::get_max_namesize(&sets));
+    report_errors(report)?;
+
+    Ok(None)
+}
+
+/// Prints the Shavee configuration and encryption of each dataset, as a table or as JSON.
+fn process_status(
+    datasets: Arc<[Dataset]>,
+    recursive: bool,
+    json: bool,
+) -> Result<Option<String>, CliError> {
+    let statuses = status::status(&datasets, recursive)?;
+
+    if json {
+        let statuses: Vec<serde_json::Value> = statuses.iter().map(status_json).collect();
+        // The alternate format of a JSON value is pretty printed
+        return Ok(Some(format!("{:#}", serde_json::Value::from(statuses))));
+    }
+
+    let header = [
+        "Dataset",
+        "Encryption",
+        "Key",
+        "Keyformat",
+        "Mounted",
+        "Second factor",
+        "Version",
+    ];
+    let rows: Vec<[String; 7]> = statuses
+        .iter()
+        .map(|status| {
+            [
+                status.dataset.to_string(),
+                encryption_name(&status.encryption),
+                key_status(&status.encryption).to_string(),
+                status
+                    .key_format
+                    .map_or("-".to_string(), |format| format.to_string()),
+                if status.mounted { "yes" } else { "no" }.to_string(),
+                second_factor_name(&status.properties),
+                status.properties.version.clone().unwrap_or("-".to_string()),
+            ]
+        })
+        .collect();
+    let mut widths = header.map(str::len);
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
         }
     }
+    let line = |cells: &[&str]| {
+        cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell))
+            .collect::<Vec<_>>()
+            .join("    ")
+            .trim_end()
+            .to_string()
+    };
 
-    if !errors.is_empty() {
-        eprintln!("\x1b[1m{:<maxlength$}    {}\x1b[0m", "Dataset", "Error");
-        eprintln!();
-        for (name, err) in errors {
-            eprintln!("{:<maxlength$}    {}", name, err);
+    println!("\x1b[1m{}\x1b[0m", line(&header));
+    println!();
+    for (status, row) in statuses.iter().zip(rows.iter()) {
+        println!("{}", line(&row.each_ref().map(String::as_str)));
+        for warning in status.warnings.iter() {
+            println!("    warning: {}", warning);
         }
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Failed to process some Datasets",
-        )));
     }
 
     Ok(None)
 }
 
-async fn get_key_hash(
-    datasets: &Arc<[Dataset]>,
-    password: String,
-    yubikeys: Option<Arc<[Mutex<Device>]>>,
-    second_factor: Option<TwoFactorMode>,
-) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-    let mut sethashes: HashMap<String, String> = HashMap::new();
-    let mut handles = vec![];
-    for d in datasets.iter() {
-        let password = password.clone();
-        let second_factor = second_factor.clone();
-        let yubikeys = yubikeys.clone();
-        let d = d.clone();
-        let handle = tokio::spawn(async move { get_keys(d, password, second_factor, yubikeys) });
-        handles.push(handle);
-    }
-
-    for handle in handles {
-        let val = match handle.await.unwrap() {
-            Ok(val) => val,
-            Err(e) => [e.0, e.1.to_string()],
-        };
-        sethashes.insert(val[0].clone(), val[1].clone());
+/// Describes the native encryption of a dataset in the `status` table.
+fn encryption_name(encryption: &Encryption) -> String {
+    match encryption {
+        Encryption::Off => "off".to_string(),
+        Encryption::Root { .. } => "root".to_string(),
+        Encryption::Inherited { root, .. } => format!("inherited from {}", root),
     }
-    Ok(sethashes)
 }
 
-fn get_keys(
-    dataset: Dataset,
-    password: String,
-    second_factor: Option<TwoFactorMode>,
-    yubikeys: Option<Arc<[Mutex<Device>]>>,
-) -> Result<[String; 2], (String, Box<dyn std::error::Error + Send>)> {
-    let salt = shavee_core::logic::get_salt(Some(&dataset)).map_err(|e| {
-        (
-            dataset.to_string(),
-            Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                e.to_string(),
-            )) as Box<dyn std::error::Error + Send>,
-        )
-    })?;
-
-    let second_factor = match second_factor {
-        Some(sf) => sf,
-        None => dataset.get_property_2fa().map_err(|e| {
-            (
-                dataset.to_string(),
-                Box::new(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    e.to_string(),
-                )) as Box<dyn std::error::Error + Send>,
-            )
-        })?,
+/// Returns the `keystatus` of a dataset, "-" if it isn't encrypted.
+fn key_status(encryption: &Encryption) -> &'static str {
+    match encryption {
+        Encryption::Off => "-",
+        _ if encryption.key_loaded() => "available",
+        _ => "unavailable",
+    }
+}
+
+/// Describes the stored second factor with its settings, like "Yubikey (serial 1234, slot 2)".
+fn second_factor_name(properties: &ShaveeProperties) -> String {
+    let Some(second_factor) = properties.second_factor.as_deref() else {
+        return "-".to_string();
+    };
+    let details: Vec<String> = match second_factor {
+        "Yubikey" => [
+            properties.yubikey_serial.map(|s| format!("serial {}", s)),
+            properties.yubikey_slot.map(|s| format!("slot {}", s)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect(),
+        "File" => [
+            properties.file_path.clone(),
+            properties.file_port.map(|p| format!("port {}", p)),
+            properties.file_size.map(|s| format!("{} bytes", s)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect(),
+        _ => Vec::new(),
     };
+    if details.is_empty() {
+        second_factor.to_string()
+    } else {
+        format!("{} ({})", second_factor, details.join(", "))
+    }
+}
+
+/// Returns the `status` of a dataset as a JSON object.
+fn status_json(status: &DatasetStatus) -> serde_json::Value {
+    let properties = &status.properties;
+    let (encryption, encryption_root) = match &status.encryption {
+        Encryption::Off => ("off", None),
+        Encryption::Root { .. } => ("root", Some(status.dataset.name())),
+        Encryption::Inherited { root, .. } => ("inherited", Some(root.as_str())),
+    };
+    serde_json::json!({
+        "dataset": status.dataset.name(),
+        "managed": status.is_managed(),
+        "encryption": encryption,
+        "encryption_root": encryption_root,
+        "key_loaded": status.encryption.key_loaded(),
+        "keyformat": status.key_format.map(|format| format.to_string()),
+        "mounted": status.mounted,
+        "second_factor": properties.second_factor,
+        "yubikey_serial": properties.yubikey_serial,
+        "yubikey_slot": properties.yubikey_slot,
+        "file_path": properties.file_path,
+        "file_port": properties.file_port,
+        "file_size": properties.file_size,
+        "version": properties.version,
+        "salt": status.salt_source().map(|source| source.to_string()),
+        "key_check": properties.key_check.is_some(),
+        "kdf": properties.kdf,
+        "kdf_memory": properties.kdf_memory,
+        "kdf_iterations": properties.kdf_iterations,
+        "kdf_lanes": properties.kdf_lanes,
+        "warnings": status
+            .warnings
+            .iter()
+            .map(|warning| warning.to_string())
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn process_bench_kdf(
+    target: Duration,
+    max_memory: u32,
+    lanes: u32,
+) -> Result<Option<String>, CliError> {
+    let tuning = password::tune_argon2(target, max_memory, lanes)?;
+
+    println!(
+        "\x1b[1m{:<10}    {:<10}    {:<5}    {:<10}    Peak process RSS\x1b[0m",
+        "Memory", "Iterations", "Lanes", "Time"
+    );
+    println!();
+    for candidate in tuning.candidates.iter() {
+        let peak = candidate
+            .peak_memory
+            .map_or("-".to_string(), |kib| format!("{} MiB", kib / 1024));
+        println!(
+            "{:<10}    {:<10}    {:<5}    {:<10}    {}",
+            format!("{} MiB", candidate.params.memory / 1024),
+            candidate.params.iterations,
+            candidate.params.lanes,
+            format!("{} ms", candidate.duration.as_millis()),
+            peak
+        );
+    }
 
-    let passphrase = match second_factor {
-        #[cfg(feature = "yubikey")]
-        TwoFactorMode::Yubikey { yslot, serial } => {
-            let yubikey = yubikeys
-                .as_ref()
-                .and_then(|keys| {
-                    if let Some(s) = serial {
-                        shavee_core::yubikey::yubikey_get_from_serial(&keys[..], s)
-                            .ok()
-                            .or_else(|| keys.first())
-                    } else {
-                        keys.first()
-                    }
-                })
-                .ok_or_else(|| {
-                    (
-                        dataset.to_string(),
-                        Box::new(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "Device Not Found",
-                        )) as Box<dyn std::error::Error + Send>,
-                    )
-                })?;
-
-            let yubihash = yubikey::yubikey_get_hash(password.as_bytes(), yslot, &salt, yubikey)
-                .map_err(|e| {
-                    (
-                        dataset.to_string(),
-                        Box::new(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            e.to_string(),
-                        )) as Box<dyn std::error::Error + Send>,
-                    )
-                })?;
-
-            base64::Engine::encode(&shavee_core::logic::BASE64_ENGINE, yubihash)
+    let kdf = tuning.recommended;
+    println!();
+    println!(
+        "Recommended for {} ms: --kdf-memory {} --kdf-iterations {} --kdf-lanes {}",
+        target.as_millis(),
+        kdf.memory / 1024,
+        kdf.iterations,
+        kdf.lanes
+    );
+
+    Ok(None)
+}
+
+/// Returns the errors of the datasets that failed, if any.
+fn report_errors<T>(report: UnlockReport<T>) -> Result<(), CliError> {
+    if report.is_success() {
+        return Ok(());
+    }
+    let errors = report
+        .outcomes
+        .into_iter()
+        .filter_map(|outcome| outcome.result.err().map(|e| (outcome.dataset, e)))
+        .collect();
+    Err(CliError::Datasets(errors))
+}
+
+/// Prints the derived keys, followed by a table of the datasets that failed on stderr.
+fn print_keys(report: &UnlockReport, with_name: bool, maxlength: usize) {
+    if with_name {
+        println!("\x1b[1m{:<maxlength$}    Key\x1b[0m", "Dataset");
+        println!();
+    }
+
+    for outcome in report.outcomes.iter() {
+        // Keys are printed the way `zfs load-key` expects them for each dataset
+        if let Ok(UnlockStatus::Printed(key)) = &outcome.result {
+            if with_name {
+                println!("{:<maxlength$}    {}", outcome.dataset, key.as_str());
+            } else {
+                println!("{}", key.as_str());
+            }
         }
-        #[cfg(feature = "file")]
-        TwoFactorMode::File { file, port, size } => {
-            let filehash = filehash::get_filehash(&file, port, size, &salt).map_err(|e| {
-                (
-                    dataset.to_string(),
-                    Box::new(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        e.to_string(),
-                    )) as Box<dyn std::error::Error + Send>,
-                )
-            })?;
-            shavee_core::logic::file_key_calculation(password.as_bytes(), filehash, &salt).map_err(
-                |e| {
-                    (
-                        dataset.to_string(),
-                        Box::new(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            e.to_string(),
-                        )) as Box<dyn std::error::Error + Send>,
-                    )
-                },
-            )?
+    }
+
+    if !report.is_success() {
+        eprintln!("\x1b[1m{:<maxlength$}    Error\x1b[0m", "Dataset");
+        eprintln!();
+        for outcome in report.failures() {
+            if let Err(err) = &outcome.result {
+                eprintln!("{:<maxlength$}    {}", outcome.dataset, err);
+            }
         }
-        TwoFactorMode::Password => {
-            shavee_core::logic::password_mode_hash(password.as_bytes(), &salt).map_err(|e| {
-                (
-                    dataset.to_string(),
-                    Box::new(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        e.to_string(),
-                    )) as Box<dyn std::error::Error + Send>,
-                )
-            })?
+    }
+}
+
+/// Prints the planned ZFS operations of a dry run, one command per line.
+fn print_operations(operations: &[zfs::Operation]) {
+    println!("\x1b[1mDry run, the following operations would be performed:\x1b[0m");
+    println!();
+    for operation in operations {
+        println!("{}", operation);
+    }
+}
+
+/// Prints what was done for every dataset, including the ones that failed.
+fn print_outcomes<T: fmt::Display>(report: &UnlockReport<T>, maxlength: usize) {
+    println!("\x1b[1m{:<maxlength$}    Status\x1b[0m", "Dataset");
+    println!();
+    for outcome in report.outcomes.iter() {
+        match &outcome.result {
+            Ok(status) => println!("{:<maxlength$}    {}", outcome.dataset, status),
+            Err(err) => println!("{:<maxlength$}    failed: {}", outcome.dataset, err),
         }
-    };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shavee_core::ZFS_RAW_KEY_LEN;
+    use shavee_core::zfs::{InMemoryBackend, ZfsShaveeProperties};
+
+    /// Small Argon2 parameters, so the tests don't spend their time hashing.
+    fn test_kdf() -> KdfParams {
+        KdfParams::new(1024, 1, 1).unwrap()
+    }
+
+    fn memory_datasets(backend: &Arc<InMemoryBackend>, names: &[&str]) -> Arc<[Dataset]> {
+        names
+            .iter()
+            .map(|name| Dataset::with_backend(name.to_string(), backend.clone()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn create_then_auto_mount_with_password() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let datasets = memory_datasets(&backend, &["pool/home"]);
+
+        process_create(
+            datasets.clone(),
+            SecretBytes::from_slice(b"hunter2"),
+            TwoFactorMode::Password,
+            KeyFormat::Passphrase,
+            test_kdf(),
+            None,
+            false,
+        )
+        .unwrap();
+
+        let created = backend.dataset("pool/home").unwrap();
+        assert_eq!(
+            created
+                .properties
+                .get(&ZfsShaveeProperties::SecondFactor.to_string())
+                .map(String::as_str),
+            Some("Password")
+        );
+        assert!(
+            created
+                .properties
+                .contains_key(&ZfsShaveeProperties::Salt.to_string())
+        );
+        assert_eq!(
+            created
+                .properties
+                .get(&ZfsShaveeProperties::KdfMemory.to_string())
+                .map(String::as_str),
+            Some("1024")
+        );
+
+        datasets[0].unmount().unwrap();
+        datasets[0].unload_key(false).unwrap();
+
+        process_mount_print(
+            Operations::Mount {
+                datasets: datasets.clone(),
+                recursive: true,
+                kdf_workers: None,
+                mount_legacy: false,
+                dry_run: false,
+            },
+            SecretBytes::from_slice(b"hunter2"),
+            None,
+        )
+        .unwrap();
 
-    Ok([dataset.to_string(), passphrase])
+        let mounted = backend.dataset("pool/home").unwrap();
+        assert!(mounted.key_loaded);
+        assert!(mounted.mounted);
+    }
+
+    #[test]
+    fn lock_busy_dataset_needs_force() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let datasets = memory_datasets(&backend, &["pool/home"]);
+        process_create(
+            datasets.clone(),
+            SecretBytes::from_slice(b"hunter2"),
+            TwoFactorMode::Password,
+            KeyFormat::Passphrase,
+            test_kdf(),
+            None,
+            false,
+        )
+        .unwrap();
+        backend.set_busy("pool/home", true);
+
+        let error = process_lock(datasets.clone(), true, false).unwrap_err();
+        assert_eq!(error.exit_code(), error::EXIT_ZFS);
+        assert!(backend.dataset("pool/home").unwrap().key_loaded);
+
+        process_lock(datasets, true, true).unwrap();
+        let locked = backend.dataset("pool/home").unwrap();
+        assert!(!locked.mounted);
+        assert!(!locked.key_loaded);
+    }
+
+    #[test]
+    fn create_then_auto_mount_with_hex_key() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let datasets = memory_datasets(&backend, &["pool/home"]);
+
+        process_create(
+            datasets.clone(),
+            SecretBytes::from_slice(b"hunter2"),
+            TwoFactorMode::Password,
+            KeyFormat::Hex,
+            test_kdf(),
+            None,
+            false,
+        )
+        .unwrap();
+
+        let created = backend.dataset("pool/home").unwrap();
+        assert_eq!(created.key_format, KeyFormat::Hex);
+        assert_eq!(
+            created.key.as_ref().map(Vec::len),
+            Some(2 * ZFS_RAW_KEY_LEN)
+        );
+        assert_eq!(
+            created
+                .properties
+                .get(&ZfsShaveeProperties::KeyFormat.to_string())
+                .map(String::as_str),
+            Some("hex")
+        );
+
+        datasets[0].unmount().unwrap();
+        datasets[0].unload_key(false).unwrap();
+
+        process_mount_print(
+            Operations::Mount {
+                datasets: datasets.clone(),
+                recursive: false,
+                kdf_workers: None,
+                mount_legacy: false,
+                dry_run: false,
+            },
+            SecretBytes::from_slice(b"hunter2"),
+            None,
+        )
+        .unwrap();
+
+        let mounted = backend.dataset("pool/home").unwrap();
+        assert!(mounted.key_loaded);
+        assert!(mounted.mounted);
+    }
+
+    #[test]
+    fn rekey_then_auto_mount() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let datasets = memory_datasets(&backend, &["pool/home"]);
+        let salt = ZfsShaveeProperties::Salt.to_string();
+
+        process_create(
+            datasets.clone(),
+            SecretBytes::from_slice(b"hunter2"),
+            TwoFactorMode::Password,
+            KeyFormat::Passphrase,
+            test_kdf(),
+            None,
+            false,
+        )
+        .unwrap();
+        let created = backend.dataset("pool/home").unwrap();
+        datasets[0].unmount().unwrap();
+        datasets[0].unload_key(false).unwrap();
+
+        // The old key is derived from the stored properties, so a wrong password fails
+        let result = process_rekey(
+            datasets.clone(),
+            SecretBytes::from_slice(b"wrong"),
+            TwoFactorMode::Password,
+            None,
+            false,
+        );
+        assert_eq!(result.unwrap_err().exit_code(), error::EXIT_INCORRECT_KEY);
+        assert_eq!(backend.dataset("pool/home").unwrap().key, created.key);
+
+        process_rekey(
+            datasets.clone(),
+            SecretBytes::from_slice(b"hunter2"),
+            TwoFactorMode::Password,
+            Some(KeyFormat::Raw),
+            false,
+        )
+        .unwrap();
+        let rekeyed = backend.dataset("pool/home").unwrap();
+        assert_eq!(rekeyed.key_format, KeyFormat::Raw);
+        assert_ne!(rekeyed.properties.get(&salt), created.properties.get(&salt));
+
+        datasets[0].unload_key(false).unwrap();
+        process_mount_print(
+            Operations::Mount {
+                datasets: datasets.clone(),
+                recursive: false,
+                kdf_workers: None,
+                mount_legacy: false,
+                dry_run: false,
+            },
+            SecretBytes::from_slice(b"hunter2"),
+            None,
+        )
+        .unwrap();
+        let mounted = backend.dataset("pool/home").unwrap();
+        assert!(mounted.key_loaded);
+        assert!(mounted.mounted);
+    }
+
+    #[test]
+    fn dry_run_changes_nothing() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let datasets = memory_datasets(&backend, &["pool/home"]);
+
+        let create = Operations::Create {
+            datasets: datasets.clone(),
+            key_format: KeyFormat::Passphrase,
+            kdf: test_kdf(),
+            kdf_target: None,
+            dry_run: true,
+            key_check: false,
+        };
+        process_dry_run(create, Some(TwoFactorMode::Password)).unwrap();
+        assert!(backend.dataset("pool/home").is_none());
+
+        process_create(
+            datasets.clone(),
+            SecretBytes::from_slice(b"hunter2"),
+            TwoFactorMode::Password,
+            KeyFormat::Passphrase,
+            test_kdf(),
+            None,
+            false,
+        )
+        .unwrap();
+        datasets[0].unmount().unwrap();
+        datasets[0].unload_key(false).unwrap();
+        let created = backend.dataset("pool/home").unwrap();
+
+        let mount = Operations::Mount {
+            datasets: datasets.clone(),
+            recursive: false,
+            kdf_workers: None,
+            mount_legacy: false,
+            dry_run: true,
+        };
+        process_dry_run(mount, None).unwrap();
+        let rekey = Operations::Rekey {
+            datasets: datasets.clone(),
+            key_format: Some(KeyFormat::Raw),
+            dry_run: true,
+            key_check: false,
+        };
+        process_dry_run(rekey, Some(TwoFactorMode::Password)).unwrap();
+        assert_eq!(backend.dataset("pool/home").unwrap(), created);
+    }
+
+    #[test]
+    fn status_of_created_dataset() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let datasets = memory_datasets(&backend, &["pool/home"]);
+        process_create(
+            datasets.clone(),
+            SecretBytes::from_slice(b"hunter2"),
+            TwoFactorMode::Password,
+            KeyFormat::Hex,
+            test_kdf(),
+            None,
+            false,
+        )
+        .unwrap();
+
+        let json = process_status(datasets.clone(), false, true)
+            .unwrap()
+            .unwrap();
+        let statuses: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let home = &statuses[0];
+        assert_eq!(home["dataset"], "pool/home");
+        assert_eq!(home["encryption"], "root");
+        assert_eq!(home["keyformat"], "hex");
+        assert_eq!(home["second_factor"], "Password");
+        assert_eq!(home["salt"], "dataset");
+        assert_eq!(home["kdf_memory"], 1024);
+        assert_eq!(home["warnings"], serde_json::json!([]));
+
+        assert!(process_status(datasets, false, false).unwrap().is_none());
+    }
+
+    #[test]
+    fn change_password_keeps_second_factor() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let datasets = memory_datasets(&backend, &["pool/home"]);
+
+        process_create(
+            datasets.clone(),
+            SecretBytes::from_slice(b"old"),
+            TwoFactorMode::Password,
+            KeyFormat::Hex,
+            test_kdf(),
+            None,
+            false,
+        )
+        .unwrap();
+
+        // The key is loaded, the old password is still verified
+        assert!(unlock::rekey(&datasets[0], b"wrong", b"new", None, None, None, None).is_err());
+        unlock::rekey(&datasets[0], b"old", b"new", None, None, None, None).unwrap();
+
+        let changed = datasets[0].get_shavee_properties().unwrap();
+        assert_eq!(
+            changed.two_factor_mode("pool/home").unwrap(),
+            TwoFactorMode::Password
+        );
+        assert_eq!(changed.key_format, Some(KeyFormat::Hex));
+
+        datasets[0].unmount().unwrap();
+        datasets[0].unload_key(false).unwrap();
+        let mount = Operations::Mount {
+            datasets: datasets.clone(),
+            recursive: false,
+            kdf_workers: None,
+            mount_legacy: false,
+            dry_run: false,
+        };
+        let _ = process_mount_print(mount.clone(), SecretBytes::from_slice(b"old"), None);
+        assert!(!backend.dataset("pool/home").unwrap().key_loaded);
+        process_mount_print(mount, SecretBytes::from_slice(b"new"), None).unwrap();
+        assert!(backend.dataset("pool/home").unwrap().mounted);
+    }
+
+    #[test]
+    fn auto_print_fails_without_shavee_properties() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_encrypted_dataset("pool", "not-a-shavee-key");
+        let datasets = memory_datasets(&backend, &["pool"]);
+
+        let result = process_mount_print(
+            Operations::PrintDataset {
+                datasets,
+                recursive: false,
+                printwithname: false,
+                kdf_workers: None,
+            },
+            SecretBytes::from_slice(b"hunter2"),
+            None,
+        );
+        assert_eq!(result.unwrap_err().exit_code(), error::EXIT_PROPERTIES);
+    }
 }
@@ -1,88 +1,99 @@
 //! File-based 2FA: hashing a file's content to derive an encryption key.
 //!
 //! This module supports both local files and remote files via HTTP, HTTPS, and SFTP.
-//! It uses `curl` for remote transfers and `BufReader` for efficient local I/O.
+//! It uses `curl` for remote transfers. The file content is key material, so it is only kept
+//! in zeroizing buffers.
 
 use crate::Result;
+use crate::password::KdfParams;
+use crate::secret::Zeroizing;
+use crate::trace::Secret;
 use curl::easy::Easy;
-use std::io::{BufReader, Read};
-
-/// Maximum buffer capacity for reading files (16 MB).
-/// Prevents excessive memory usage for very large files.
-const MAX_BUFFER_CAPACITY: usize = 1 << 24;
+use std::io::Read;
 
 /// Generates a hash from a file's content (local or remote).
 ///
-/// This is a high-level entry point that dispatches to either `get_filehash_local`
-/// or `get_filehash_remote` based on the file path prefix.
+/// This is a high-level entry point reading the file with `read_file` and hashing its content
+/// with Argon2.
 ///
 /// # Arguments
 /// * `file` - The file path or URL (http://, https://, sftp://).
 /// * `port` - Optional port for remote files.
 /// * `size` - Optional maximum number of bytes to read from the file.
 /// * `salt` - The salt used for final Argon2 hashing.
+/// * `kdf` - The Argon2 cost parameters.
 ///
 /// # Returns
-/// A `Result` containing the derived key as a `Vec<u8>`.
+/// A `Result` containing the derived key, zeroized when dropped.
 pub fn get_filehash(
     file: &str,
     port: Option<u16>,
     size: Option<u64>,
     salt: &[u8],
-) -> Result<Vec<u8>> {
+    kdf: &KdfParams,
+) -> Result<Zeroizing<Vec<u8>>> {
+    // Derive final key using Argon2
+    crate::password::hash_argon2(&read_file(file, port, size)?, salt, kdf)
+}
+
+/// Reads up to `size` bytes of a local or remote file, the input hashed by `get_filehash`.
+///
+/// Dispatches to a local read or a cURL transfer based on the file path prefix.
+pub fn read_file(file: &str, port: Option<u16>, size: Option<u64>) -> Result<Zeroizing<Vec<u8>>> {
     crate::trace(&format!(
         "Generating hash from file: {} (size limit: {:?})",
-        file, size
+        Secret::new(file),
+        size
     ));
 
-    // Determine if the file is remote based on its protocol prefix
-    if file.starts_with("https://") || file.starts_with("http://") || file.starts_with("sftp://") {
+    if is_remote(file) {
         crate::trace("File location is remote.");
-        get_filehash_remote(file, port, size, salt)
+        read_remote(file, port, size)
     } else {
         crate::trace("File location is local.");
-        get_filehash_local(file, size, salt)
+        read_local(file, size)
     }
 }
 
-/// Reads and hashes a local file.
-///
-/// Uses `BufReader` with a capacity optimized for the requested `size` limit
-/// to ensure efficient reading.
-fn get_filehash_local(path: &str, size: Option<u64>, salt: &[u8]) -> Result<Vec<u8>> {
-    let file = std::fs::File::open(path)?;
+/// Checks that a local file can be opened, without reading it. Remote files are not checked,
+/// as that would mean downloading them.
+pub fn check_file(file: &str) -> Result<()> {
+    if !is_remote(file) {
+        std::fs::File::open(file)?;
+    }
+    Ok(())
+}
 
-    // Optimize buffer capacity: don't allocate more than needed if size is small
-    let buffer_capacity = match size {
-        Some(s) if s < MAX_BUFFER_CAPACITY as u64 => s as usize,
-        _ => MAX_BUFFER_CAPACITY,
-    };
+/// Determines if the file is remote based on its protocol prefix.
+fn is_remote(file: &str) -> bool {
+    file.starts_with("https://") || file.starts_with("http://") || file.starts_with("sftp://")
+}
+
+/// Reads a local file.
+///
+/// The file is read without an intermediate buffer, into a vector sized for the requested
+/// `size` limit so no copy of the content is left behind by reallocations.
+fn read_local(path: &str, size: Option<u64>) -> Result<Zeroizing<Vec<u8>>> {
+    let mut file = std::fs::File::open(path)?;
 
-    let mut reader = BufReader::with_capacity(buffer_capacity, file);
-    let mut hash_input = Vec::new();
+    let len = file.metadata()?.len();
+    let capacity = size.map_or(len, |limit| limit.min(len));
+    let mut hash_input = Zeroizing::new(Vec::with_capacity(capacity as usize));
 
     // Read up to 'size' bytes or until EOF
     if let Some(limit) = size {
-        let mut handle = reader.take(limit);
-        handle.read_to_end(&mut hash_input)?;
+        file.take(limit).read_to_end(&mut hash_input)?;
     } else {
-        reader.read_to_end(&mut hash_input)?;
+        file.read_to_end(&mut hash_input)?;
     }
-
-    // Derive final key using Argon2
-    crate::password::hash_argon2(&hash_input, salt)
+    Ok(hash_input)
 }
 
-/// Reads and hashes a remote file using cURL.
+/// Reads a remote file using cURL.
 ///
 /// Supports HTTP, HTTPS, and SFTP. The transfer stops early if `size` is reached.
-fn get_filehash_remote(
-    url: &str,
-    port: Option<u16>,
-    size: Option<u64>,
-    salt: &[u8],
-) -> Result<Vec<u8>> {
-    let mut hash_input = Vec::new();
+fn read_remote(url: &str, port: Option<u16>, size: Option<u64>) -> Result<Zeroizing<Vec<u8>>> {
+    let mut hash_input = Zeroizing::new(Vec::new());
     let mut handle = Easy::new();
     handle.url(url)?;
 
@@ -115,9 +126,7 @@ fn get_filehash_remote(
         // Execute the transfer
         transfer.perform()?;
     }
-
-    // Derive final key using Argon2
-    crate::password::hash_argon2(&hash_input, salt)
+    Ok(hash_input)
 }
 
 #[cfg(test)]
@@ -133,10 +142,11 @@ mod tests {
         temp_file.write_all(file_content).unwrap();
 
         let salt = b"somesalt123";
-        let expected_hash = crate::password::hash_argon2(file_content, salt).unwrap();
+        let expected_hash =
+            crate::password::hash_argon2(file_content, salt, &KdfParams::default()).unwrap();
 
         let path = temp_file.path().to_str().unwrap();
-        let actual_hash = get_filehash_local(path, None, salt).unwrap();
+        let actual_hash = get_filehash(path, None, None, salt, &KdfParams::default()).unwrap();
 
         assert_eq!(actual_hash, expected_hash);
     }
@@ -149,22 +159,30 @@ mod tests {
 
         let salt = b"somesalt123";
         // It should only read the first 5 bytes "hello"
-        let expected_hash = crate::password::hash_argon2(b"hello", salt).unwrap();
+        let expected_hash =
+            crate::password::hash_argon2(b"hello", salt, &KdfParams::default()).unwrap();
 
         let path = temp_file.path().to_str().unwrap();
-        let actual_hash = get_filehash_local(path, Some(5), salt).unwrap();
+        let actual_hash = get_filehash(path, None, Some(5), salt, &KdfParams::default()).unwrap();
 
         assert_eq!(actual_hash, expected_hash);
     }
 
     #[test]
-    fn test_get_filehash_local_file_not_found() {
-        let salt = b"somesalt123";
-        let result =
-            get_filehash_local("/non/existent/file/path/that/should/not/exist", None, salt);
+    fn test_read_local_file_not_found() {
+        let result = read_local("/non/existent/file/path/that/should/not/exist", None);
         assert!(result.is_err(), "Should return error for non-existent file");
     }
 
+    #[test]
+    fn test_check_file() {
+        let temp_file = NamedTempFile::new().unwrap();
+        assert!(check_file(temp_file.path().to_str().unwrap()).is_ok());
+        assert!(check_file("/non/existent/file/path/that/should/not/exist").is_err());
+        // Remote files would have to be downloaded
+        assert!(check_file("https://example.com/keyfile").is_ok());
+    }
+
     #[test]
     fn test_get_filehash_dispatch_local() {
         let mut temp_file = NamedTempFile::new().unwrap();
@@ -173,7 +191,7 @@ mod tests {
         let salt = b"somesalt123";
 
         let path = temp_file.path().to_str().unwrap();
-        let hash = get_filehash(path, None, None, salt);
+        let hash = get_filehash(path, None, None, salt, &KdfParams::default());
         assert!(hash.is_ok());
     }
 }
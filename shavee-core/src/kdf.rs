@@ -0,0 +1,155 @@
+//! Versioned key derivation schemes.
+//!
+//! The scheme a dataset's key was derived with is stored in `com.github.shavee:kdf`, so the
+//! derivation of new keys can change while existing datasets keep unlocking with the scheme
+//! they were created with. Datasets created before the property existed use `LEGACY`.
+
+use crate::password::{KdfParams, KdfSession};
+use crate::secret::Zeroizing;
+use crate::structs::TwoFactorMode;
+use crate::{Error, Result, logic};
+use base64::Engine;
+use challenge_response::Device;
+use std::sync::Mutex;
+use strum_macros::{Display, EnumString};
+
+/// Registry of the supported key derivation schemes, by their stored identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString)]
+pub enum KdfScheme {
+    /// Argon2id with `STATIC_SALT` as secret, combined with the second factor as in shavee
+    /// 1.x (see `logic::password_mode_hash`, `logic::file_key_calculation` and
+    /// `logic::yubi_key_calculation`).
+    #[strum(serialize = "argon2id-v1")]
+    Argon2idV1,
+}
+
+impl KdfScheme {
+    /// Scheme used to derive new keys.
+    pub const CURRENT: KdfScheme = KdfScheme::Argon2idV1;
+
+    /// Scheme of datasets without a stored scheme identifier.
+    pub const LEGACY: KdfScheme = KdfScheme::Argon2idV1;
+
+    /// Returns the scheme for a stored identifier, `LEGACY` if there is none.
+    pub fn from_property(value: Option<&str>) -> Result<Self> {
+        match value {
+            None => Ok(Self::LEGACY),
+            Some(id) => id.parse().map_err(|_| {
+                Error::Properties(format!(
+                    "Unknown KDF scheme {}, it may need a newer shavee",
+                    id
+                ))
+            }),
+        }
+    }
+
+    /// Derives the key (base64) for `second_factor` from the password of `session` and `salt`.
+    ///
+    /// `yubikey` is the device answering the challenge for `TwoFactorMode::Yubikey`. The
+    /// Argon2 hashes run in `session`, which shares the password stage between datasets.
+    pub fn derive(
+        self,
+        session: &KdfSession,
+        second_factor: &TwoFactorMode,
+        yubikey: Option<&Mutex<Device>>,
+        salt: &[u8],
+        kdf: &KdfParams,
+    ) -> Result<Zeroizing<String>> {
+        match self {
+            KdfScheme::Argon2idV1 => {
+                let key = match second_factor {
+                    #[cfg(feature = "yubikey")]
+                    TwoFactorMode::Yubikey { yslot, .. } => {
+                        let yubikey = yubikey
+                            .ok_or_else(|| Error::Yubikey("Device Not Found".to_string()))?;
+                        let passhash = session.password_hash(salt, kdf)?;
+                        let hmac = crate::yubikey::challenge_response(&passhash, *yslot, yubikey)?;
+                        session.hash(&hmac, salt, kdf)?
+                    }
+                    #[cfg(feature = "file")]
+                    TwoFactorMode::File { file, port, size } => {
+                        let content = crate::filehash::read_file(file, *port, *size)?;
+                        let filehash = session.hash(&content, salt, kdf)?;
+                        let passhash = session.password_hash(salt, kdf)?;
+                        let mut combined =
+                            Zeroizing::new(Vec::with_capacity(filehash.len() + passhash.len()));
+                        combined.extend_from_slice(&filehash);
+                        combined.extend_from_slice(&passhash);
+                        session.hash(&combined, salt, kdf)?
+                    }
+                    TwoFactorMode::Password => {
+                        Zeroizing::new(session.password_hash(salt, kdf)?.to_vec())
+                    }
+                };
+                Ok(Zeroizing::new(logic::BASE64_ENGINE.encode(key)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_property() {
+        assert_eq!(KdfScheme::from_property(None).unwrap(), KdfScheme::LEGACY);
+        assert_eq!(
+            KdfScheme::from_property(Some(&KdfScheme::CURRENT.to_string())).unwrap(),
+            KdfScheme::CURRENT
+        );
+        assert!(matches!(
+            KdfScheme::from_property(Some("scrypt-v9")),
+            Err(Error::Properties(_))
+        ));
+    }
+
+    #[test]
+    fn test_argon2id_v1_matches_password_mode_hash() {
+        // The v1 scheme must keep deriving the keys of existing datasets
+        let kdf = KdfParams::new(1024, 1, 1).unwrap();
+        let key = KdfScheme::Argon2idV1
+            .derive(
+                &KdfSession::new(b"hunter2", 1),
+                &TwoFactorMode::Password,
+                None,
+                b"somesalt",
+                &kdf,
+            )
+            .unwrap();
+        assert_eq!(
+            key,
+            logic::password_mode_hash(b"hunter2", b"somesalt", &kdf).unwrap()
+        );
+    }
+
+    #[cfg(feature = "file")]
+    #[test]
+    fn test_argon2id_v1_matches_file_key_calculation() {
+        use std::io::Write;
+
+        let mut keyfile = tempfile::NamedTempFile::new().unwrap();
+        keyfile.write_all(b"second factor").unwrap();
+        let file = keyfile.path().to_str().unwrap().to_string();
+
+        let kdf = KdfParams::new(1024, 1, 1).unwrap();
+        let key = KdfScheme::Argon2idV1
+            .derive(
+                &KdfSession::new(b"hunter2", 1),
+                &TwoFactorMode::File {
+                    file: file.clone(),
+                    port: None,
+                    size: None,
+                },
+                None,
+                b"somesalt",
+                &kdf,
+            )
+            .unwrap();
+        let filehash = crate::filehash::get_filehash(&file, None, None, b"somesalt", &kdf).unwrap();
+        assert_eq!(
+            key,
+            logic::file_key_calculation(b"hunter2", &filehash, b"somesalt", &kdf).unwrap()
+        );
+    }
+}
@@ -4,14 +4,21 @@
 //! using various 2FA methods like Yubikeys and files.
 
 pub mod filehash;
+pub mod kdf;
 pub mod logic;
 pub mod password;
+pub mod secret;
+pub mod status;
 pub mod structs;
+pub mod trace;
+pub mod unlock;
 pub mod yubikey;
 pub mod zfs;
 
 use std::fmt;
 
+pub use trace::{error, trace, trace_init};
+
 /// Predefined message for unreachable code paths.
 pub const UNREACHABLE_CODE: &str =
     "Panic! Something unexpected happened! Please help by reporting it as a bug.";
@@ -26,11 +33,18 @@ pub const ENV_SALT_VARIABLE: &str = "SHAVEE_SALT";
 /// Must be at least 16 bytes for security.
 pub const RANDOM_SALT_LEN: usize = 32;
 
+/// Length in bytes of ZFS raw and hex wrapping keys.
+pub const ZFS_RAW_KEY_LEN: usize = 32;
+
 /// Core error type for the Shavee library.
 #[derive(Debug)]
 pub enum Error {
     /// Errors originating from ZFS command execution.
     Zfs(String),
+    /// ZFS rejected the encryption key, e.g. because of a wrong password or second factor.
+    IncorrectKey(String),
+    /// Shavee properties of a dataset are missing or invalid.
+    Properties(String),
     /// Errors related to password hashing or KDF operations.
     Crypto(String),
     /// Errors related to Yubikey interaction.
@@ -49,6 +63,8 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::Zfs(m) => write!(f, "ZFS error: {}", m),
+            Error::IncorrectKey(m) => write!(f, "Incorrect key: {}", m),
+            Error::Properties(m) => write!(f, "Shavee properties error: {}", m),
             Error::Crypto(m) => write!(f, "Crypto error: {}", m),
             Error::Yubikey(m) => write!(f, "Yubikey error: {}", m),
             Error::Io(e) => write!(f, "I/O error: {}", e),
@@ -106,33 +122,6 @@ pub fn parse_file_size_arguments(args: &[String]) -> Result<(String, Option<u64>
     Ok((file, size))
 }
 
-/// Initializes the logging system if the "trace" feature is enabled.
-///
-/// # Arguments
-/// * `_is_test` - Whether the logger is being initialized for a test environment.
-pub fn trace_init(_is_test: bool) {
-    #[cfg(feature = "trace")]
-    {
-        if _is_test {
-            let _ = env_logger::builder().is_test(true).try_init();
-        } else {
-            env_logger::init();
-        }
-    }
-}
-
-/// Logs a trace message if the "trace" feature is enabled.
-pub fn trace(_message: &str) {
-    #[cfg(feature = "trace")]
-    log::trace!("{}", _message);
-}
-
-/// Logs an error message if the "trace" feature is enabled.
-pub fn error(_message: &str) {
-    #[cfg(feature = "trace")]
-    log::error!("{}", _message);
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
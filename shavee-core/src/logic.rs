@@ -4,10 +4,16 @@
 //! to provide high-level operations for dataset management.
 
 use crate::{
-    Error, Result, password, yubikey,
+    Error, Result,
+    password::{self, KdfParams},
+    secret::Zeroizing,
+    structs::KeyFormat,
+    yubikey,
     zfs::{Dataset, ZfsShaveeProperties},
 };
 use base64::{Engine, engine::general_purpose::NO_PAD};
+use blake2::Blake2bMac;
+use blake2::digest::{KeyInit, Mac, consts::U32};
 use challenge_response::Device;
 use rand::{RngCore, SeedableRng, rngs};
 use std::sync::Mutex;
@@ -40,7 +46,8 @@ impl Dataset {
         ));
 
         // Calculate the derived passphrase using Yubikey HMAC-SHA1
-        let derived_passphrase = yubi_key_calculation(passphrase, yubi_slot, salt, yubikey)?;
+        let derived_passphrase =
+            yubi_key_calculation(passphrase, yubi_slot, salt, &KdfParams::default(), yubikey)?;
 
         // Instruct ZFS to create the dataset with the derived passphrase
         self.create(&derived_passphrase)?;
@@ -58,14 +65,15 @@ impl Dataset {
     ///
     /// # Returns
     /// `Result<()>` indicating success or failure of the creation process.
-    pub fn file_create(&self, passphrase: &[u8], filehash: Vec<u8>, salt: &[u8]) -> Result<()> {
+    pub fn file_create(&self, passphrase: &[u8], filehash: &[u8], salt: &[u8]) -> Result<()> {
         crate::trace(&format!(
             "Creating ZFS dataset \"{}\" with File 2FA",
             self.name()
         ));
 
         // Calculate the derived passphrase using the file hash and user password
-        let derived_passphrase = file_key_calculation(passphrase, filehash, salt)?;
+        let derived_passphrase =
+            file_key_calculation(passphrase, filehash, salt, &KdfParams::default())?;
 
         // Instruct ZFS to create the dataset with the derived passphrase
         self.create(&derived_passphrase)?;
@@ -83,40 +91,136 @@ pub fn yubi_key_calculation(
     pass: &[u8],
     yubi_slot: Option<u8>,
     salt: &[u8],
+    kdf: &KdfParams,
     yubikey: &Mutex<Device>,
-) -> Result<String> {
+) -> Result<Zeroizing<String>> {
     crate::trace("Calculating key using Yubikey");
-    let key = yubikey::yubikey_get_hash(pass, yubi_slot, salt, yubikey)?;
-    Ok(BASE64_ENGINE.encode(key))
+    let key = yubikey::yubikey_get_hash(pass, yubi_slot, salt, kdf, yubikey)?;
+    Ok(Zeroizing::new(BASE64_ENGINE.encode(key)))
 }
 
 /// Derives a ZFS passphrase using a file hash.
 ///
 /// This method combines the file's hash with the Argon2 hash of the user's password,
 /// then performs a final Argon2 hash on the concatenated result.
-pub fn file_key_calculation(pass: &[u8], filehash: Vec<u8>, salt: &[u8]) -> Result<String> {
+pub fn file_key_calculation(
+    pass: &[u8],
+    filehash: &[u8],
+    salt: &[u8],
+    kdf: &KdfParams,
+) -> Result<Zeroizing<String>> {
     crate::trace("Calculating key using File hash");
 
     // First, hash the user password
-    let passhash = password::hash_argon2(pass, salt)?;
+    let passhash = password::hash_argon2(pass, salt, kdf)?;
 
-    // Concatenate file hash and password hash for the final KDF step
-    let mut combined = filehash;
+    // Concatenate file hash and password hash for the final KDF step, allocated once so no
+    // copy is left behind by a reallocation
+    let mut combined = Zeroizing::new(Vec::with_capacity(filehash.len() + passhash.len()));
+    combined.extend_from_slice(filehash);
     combined.extend_from_slice(&passhash);
 
     // Hash the combined data to derive the final key
-    let key = password::hash_argon2(&combined, salt)?;
+    let key = password::hash_argon2(&combined, salt, kdf)?;
     crate::trace("Key calculated successfully");
-    Ok(BASE64_ENGINE.encode(key))
+    Ok(Zeroizing::new(BASE64_ENGINE.encode(key)))
 }
 
 /// Derives a ZFS passphrase without a second factor.
 ///
 /// Performs a single Argon2id pass on the user password with the provided salt.
-pub fn password_mode_hash(password: &[u8], salt: &[u8]) -> Result<String> {
+pub fn password_mode_hash(
+    password: &[u8],
+    salt: &[u8],
+    kdf: &KdfParams,
+) -> Result<Zeroizing<String>> {
     crate::trace("Calculating key (password-only mode)");
-    let key = password::hash_argon2(password, salt)?;
-    Ok(BASE64_ENGINE.encode(key))
+    let key = password::hash_argon2(password, salt, kdf)?;
+    Ok(Zeroizing::new(BASE64_ENGINE.encode(key)))
+}
+
+/// Encodes a derived key (as returned by the `*_key_calculation` functions) into the key
+/// material ZFS expects for `format`.
+///
+/// `Passphrase` uses the base64 string itself, `Raw` the first `ZFS_RAW_KEY_LEN` bytes of the
+/// decoded key and `Hex` the same bytes as lowercase hex.
+pub fn encode_key(derived_key: &str, format: KeyFormat) -> Result<Zeroizing<Vec<u8>>> {
+    Ok(match format {
+        KeyFormat::Passphrase => Zeroizing::new(derived_key.as_bytes().to_vec()),
+        KeyFormat::Raw => raw_key(derived_key)?,
+        KeyFormat::Hex => Zeroizing::new(hex_key(&raw_key(derived_key)?).as_bytes().to_vec()),
+    })
+}
+
+/// Decodes the first `ZFS_RAW_KEY_LEN` bytes of a derived key.
+fn raw_key(derived_key: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let mut decoded = Zeroizing::new(
+        BASE64_ENGINE
+            .decode(derived_key.as_bytes())
+            .map_err(|e| Error::Crypto(format!("Failed to decode derived key: {}", e)))?,
+    );
+    if decoded.len() < crate::ZFS_RAW_KEY_LEN {
+        return Err(Error::Crypto(format!(
+            "Derived key is shorter than {} bytes",
+            crate::ZFS_RAW_KEY_LEN
+        )));
+    }
+    decoded.truncate(crate::ZFS_RAW_KEY_LEN);
+    Ok(decoded)
+}
+
+/// Encodes key bytes as lowercase hex, without intermediate strings.
+fn hex_key(raw: &[u8]) -> Zeroizing<String> {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut hex = Zeroizing::new(String::with_capacity(2 * raw.len()));
+    for byte in raw {
+        hex.push(DIGITS[usize::from(byte >> 4)] as char);
+        hex.push(DIGITS[usize::from(byte & 0xf)] as char);
+    }
+    hex
+}
+
+/// Computes the key check value stored in `com.github.shavee:keycheck`.
+///
+/// It is a BLAKE2b MAC of the dataset's salt and the derived key, so a wrong password or second
+/// factor can be detected before ZFS is asked to load the key. Like the salt, the stored value
+/// is readable by any local user with `zfs get`, who can then test password guesses against it
+/// offline at the cost of one Argon2 hash each, without access to the disk. For the Password
+/// mode this is as sensitive as a password hash, so it is only stored on request.
+pub fn key_check(derived_key: &str, salt: &str) -> Result<String> {
+    Ok(BASE64_ENGINE.encode(key_check_mac(derived_key, salt)?.finalize().into_bytes()))
+}
+
+/// Checks a derived key against a stored key check value.
+///
+/// Returns `Error::IncorrectKey` if the key doesn't match.
+pub fn verify_key_check(derived_key: &str, salt: &str, key_check: &str) -> Result<()> {
+    let expected = BASE64_ENGINE
+        .decode(key_check)
+        .map_err(|e| Error::Properties(format!("Failed to decode key check: {}", e)))?;
+    key_check_mac(derived_key, salt)?
+        .verify_slice(&expected)
+        .map_err(|_| {
+            Error::IncorrectKey("Derived key does not match the stored key check".to_string())
+        })
+}
+
+fn key_check_mac(derived_key: &str, salt: &str) -> Result<Blake2bMac<U32>> {
+    let context = ZfsShaveeProperties::KeyCheck.to_string();
+    let mut mac = <Blake2bMac<U32> as KeyInit>::new_from_slice(context.as_bytes())
+        .map_err(|e| Error::Crypto(e.to_string()))?;
+    mac.update(salt.as_bytes());
+    mac.update(derived_key.as_bytes());
+    Ok(mac)
+}
+
+/// Returns a derived key in printable form: the passphrase itself, or the hex encoding for
+/// "raw" and "hex" datasets since raw bytes cannot be printed.
+pub fn printable_key(derived_key: &str, format: KeyFormat) -> Result<Zeroizing<String>> {
+    match format {
+        KeyFormat::Passphrase => Ok(Zeroizing::new(derived_key.to_string())),
+        KeyFormat::Raw | KeyFormat::Hex => Ok(hex_key(&raw_key(derived_key)?)),
+    }
 }
 
 /// Retrieves the salt for a dataset according to the precedence rules:
@@ -129,15 +233,23 @@ pub fn password_mode_hash(password: &[u8], salt: &[u8]) -> Result<String> {
 pub fn get_salt(dataset: Option<&Dataset>) -> Result<Vec<u8>> {
     crate::trace("Retrieving salt");
 
+    let salt = match dataset {
+        Some(ds) => ds.get_property(&ZfsShaveeProperties::Salt.to_string())?,
+        None => None,
+    };
+    salt_from_property(salt.as_deref())
+}
+
+/// Resolves the salt from an already fetched salt property (see `zfs::ShaveeProperties`),
+/// applying the same precedence rules as `get_salt`.
+pub fn salt_from_property(salt: Option<&str>) -> Result<Vec<u8>> {
     // Precedence 1: Dataset property
-    if let Some(ds) = dataset {
-        if let Some(prop) = ds.get_property(&ZfsShaveeProperties::Salt.to_string())? {
-            crate::trace("Using salt from ZFS dataset property");
-            // Salt is stored base64-encoded in ZFS properties
-            return BASE64_ENGINE
-                .decode(prop.as_bytes())
-                .map_err(|e| Error::Crypto(format!("Failed to decode salt from ZFS: {}", e)));
-        }
+    if let Some(prop) = salt {
+        crate::trace("Using salt from ZFS dataset property");
+        // Salt is stored base64-encoded in ZFS properties
+        return BASE64_ENGINE
+            .decode(prop.as_bytes())
+            .map_err(|e| Error::Crypto(format!("Failed to decode salt from ZFS: {}", e)));
     }
 
     // Precedence 2: Environment variable
@@ -165,6 +277,28 @@ pub fn generate_salt() -> Vec<u8> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_key_check() {
+        let key = BASE64_ENGINE.encode([7u8; 64]);
+        let other = BASE64_ENGINE.encode([8u8; 64]);
+        let check = key_check(&key, "c2FsdA").unwrap();
+
+        assert_eq!(check, key_check(&key, "c2FsdA").unwrap());
+        assert!(verify_key_check(&key, "c2FsdA", &check).is_ok());
+        assert!(matches!(
+            verify_key_check(&other, "c2FsdA", &check),
+            Err(Error::IncorrectKey(_))
+        ));
+        assert!(matches!(
+            verify_key_check(&key, "bmV3", &check),
+            Err(Error::IncorrectKey(_))
+        ));
+        assert!(matches!(
+            verify_key_check(&key, "c2FsdA", "not base64!"),
+            Err(Error::Properties(_))
+        ));
+    }
+
     #[test]
     fn test_generate_salt() {
         let salt1 = generate_salt();
@@ -178,8 +312,8 @@ mod tests {
     fn test_password_mode_hash_deterministic() {
         let password = b"my_password";
         let salt = b"somesalt123";
-        let hash1 = password_mode_hash(password, salt).unwrap();
-        let hash2 = password_mode_hash(password, salt).unwrap();
+        let hash1 = password_mode_hash(password, salt, &KdfParams::default()).unwrap();
+        let hash2 = password_mode_hash(password, salt, &KdfParams::default()).unwrap();
         assert_eq!(
             hash1, hash2,
             "Hashing same password and salt should produce the same output"
@@ -191,8 +325,8 @@ mod tests {
         let password = b"my_password";
         let filehash = vec![1, 2, 3, 4, 5];
         let salt = b"somesalt123";
-        let hash1 = file_key_calculation(password, filehash.clone(), salt).unwrap();
-        let hash2 = file_key_calculation(password, filehash.clone(), salt).unwrap();
+        let hash1 = file_key_calculation(password, &filehash, salt, &KdfParams::default()).unwrap();
+        let hash2 = file_key_calculation(password, &filehash, salt, &KdfParams::default()).unwrap();
         assert_eq!(hash1, hash2, "File key calculation should be deterministic");
     }
 
@@ -200,8 +334,10 @@ mod tests {
     fn test_file_key_calculation_different_filehash() {
         let password = b"my_password";
         let salt = b"somesalt123";
-        let hash1 = file_key_calculation(password, vec![1, 2, 3], salt).unwrap();
-        let hash2 = file_key_calculation(password, vec![1, 2, 4], salt).unwrap();
+        let hash1 =
+            file_key_calculation(password, &[1, 2, 3], salt, &KdfParams::default()).unwrap();
+        let hash2 =
+            file_key_calculation(password, &[1, 2, 4], salt, &KdfParams::default()).unwrap();
         assert_ne!(
             hash1, hash2,
             "Different file hashes should produce different keys"
@@ -214,4 +350,25 @@ mod tests {
         let salt = get_salt(None).unwrap();
         assert_eq!(salt, crate::STATIC_SALT.as_bytes().to_vec());
     }
+
+    #[test]
+    fn test_encode_key() {
+        let derived = BASE64_ENGINE.encode((0u8..64).collect::<Vec<u8>>());
+
+        let passphrase = encode_key(&derived, KeyFormat::Passphrase).unwrap();
+        assert_eq!(*passphrase, derived.as_bytes());
+
+        let raw = encode_key(&derived, KeyFormat::Raw).unwrap();
+        assert_eq!(*raw, (0u8..32).collect::<Vec<u8>>());
+
+        let hex = encode_key(&derived, KeyFormat::Hex).unwrap();
+        assert_eq!(hex.len(), 2 * crate::ZFS_RAW_KEY_LEN);
+        assert!(hex.starts_with(b"000102"));
+
+        let short = BASE64_ENGINE.encode([1u8; 8]);
+        assert!(matches!(
+            encode_key(&short, KeyFormat::Raw),
+            Err(Error::Crypto(_))
+        ));
+    }
 }
@@ -1,17 +1,63 @@
 //! Password hashing and key derivation utilities using Argon2.
 
+use crate::secret::{SecretBytes, Zeroizing};
+use crate::trace::Secret;
 use crate::{Error, Result};
-use argon2::{
-    Params, Version,
-    password_hash::{PasswordHasher, SaltString},
-};
+use argon2::{Params, Version, password_hash::SaltString};
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
 
-/// Argon2id parameters used for hashing.
+/// Default Argon2id parameters, used for datasets without stored KDF properties.
 const ARGON2_MEMORY: u32 = 524288; // 512 MB
 const ARGON2_LANES: u32 = 4;
 const ARGON2_ITERATIONS: u32 = 4;
 const ARGON2_OUTPUT_LEN: usize = 64;
 
+/// Argon2id cost parameters used for hashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KdfParams {
+    /// Memory size in KiB.
+    pub memory: u32,
+    /// Number of passes over the memory.
+    pub iterations: u32,
+    /// Degree of parallelism.
+    pub lanes: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            memory: ARGON2_MEMORY,
+            iterations: ARGON2_ITERATIONS,
+            lanes: ARGON2_LANES,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Creates parameters, checking that Argon2 accepts them.
+    pub fn new(memory: u32, iterations: u32, lanes: u32) -> Result<Self> {
+        let params = Self {
+            memory,
+            iterations,
+            lanes,
+        };
+        params.argon2_params()?;
+        Ok(params)
+    }
+
+    fn argon2_params(&self) -> Result<Params> {
+        Params::new(
+            self.memory,
+            self.iterations,
+            self.lanes,
+            Some(ARGON2_OUTPUT_LEN),
+        )
+        .map_err(|e| Error::Crypto(format!("Invalid Argon2 parameters: {}", e)))
+    }
+}
+
 /// Hashes a password with a given salt using Argon2id.
 ///
 /// This function uses Argon2id with a hardcoded static secret (from `crate::STATIC_SALT`)
@@ -20,20 +66,18 @@ const ARGON2_OUTPUT_LEN: usize = 64;
 /// # Arguments
 /// * `password` - The user-provided password bytes.
 /// * `salt` - The salt bytes (usually from ZFS property or env).
+/// * `kdf` - The Argon2 cost parameters (usually from ZFS properties).
 ///
 /// # Returns
-/// A `Result` containing the hashed bytes as a `Vec<u8>`.
-pub fn hash_argon2(password: &[u8], salt: &[u8]) -> Result<Vec<u8>> {
-    crate::trace(&format!("Hashing password with salt: {:?}", salt));
+/// A `Result` containing the hashed bytes, zeroized when dropped.
+pub fn hash_argon2(password: &[u8], salt: &[u8], kdf: &KdfParams) -> Result<Zeroizing<Vec<u8>>> {
+    crate::trace(&format!(
+        "Hashing password with salt: {:?}",
+        Secret::new(salt)
+    ));
 
     // Initialize Argon2 parameters (Memory, Iterations, Lanes, Output Length)
-    let params = Params::new(
-        ARGON2_MEMORY,
-        ARGON2_ITERATIONS,
-        ARGON2_LANES,
-        Some(ARGON2_OUTPUT_LEN),
-    )
-    .map_err(|e| Error::Crypto(e.to_string()))?;
+    let params = kdf.argon2_params()?;
 
     // Create Argon2 instance with the static secret
     let argon2 = argon2::Argon2::new_with_secret(
@@ -44,18 +88,215 @@ pub fn hash_argon2(password: &[u8], salt: &[u8]) -> Result<Vec<u8>> {
     )
     .map_err(|e| Error::Crypto(e.to_string()))?;
 
-    // Encode salt as SaltString
-    let salt_string = SaltString::encode_b64(salt).map_err(|e| Error::Crypto(e.to_string()))?;
+    // Salts are limited to what fits in a SaltString, as when hashes were PHC strings
+    SaltString::encode_b64(salt).map_err(|e| Error::Crypto(e.to_string()))?;
+
+    // Hash straight into a zeroizing buffer rather than a copyable PHC `Output`
+    let mut hash = Zeroizing::new(vec![0u8; ARGON2_OUTPUT_LEN]);
+    argon2
+        .hash_password_into(password, salt, &mut hash)
+        .map_err(|e| Error::Crypto(e.to_string()))?;
+    Ok(hash)
+}
+
+/// Upper bound of `default_kdf_workers`.
+const MAX_DEFAULT_WORKERS: usize = 4;
+
+/// Default number of concurrent hashes of a `KdfSession`: one per CPU, but at most 4 so
+/// unlocking many datasets with the default parameters stays within 2 GiB.
+pub fn default_kdf_workers() -> usize {
+    std::thread::available_parallelism().map_or(1, |cpus| cpus.get().min(MAX_DEFAULT_WORKERS))
+}
+
+/// Password stage hashes of a `KdfSession`, computed once per salt and parameters.
+type PasswordHashes = HashMap<
+    (Vec<u8>, KdfParams),
+    Arc<OnceLock<std::result::Result<Arc<Zeroizing<Vec<u8>>>, String>>>,
+>;
+
+/// Argon2 hashing of one password for many datasets.
+///
+/// The password stage, `hash_argon2(password, salt, kdf)`, is computed once per salt and
+/// parameters and shared by all datasets using them. At most `workers` hashes run at once
+/// across all threads using the session, so a recursive unlock uses at most `workers` times
+/// the Argon2 memory size.
+pub struct KdfSession {
+    password: SecretBytes,
+    workers: Workers,
+    password_hashes: Mutex<PasswordHashes>,
+}
+
+impl KdfSession {
+    /// Creates a session for `password` running at most `workers` (at least 1) hashes at once.
+    pub fn new(password: &[u8], workers: usize) -> Self {
+        Self {
+            password: SecretBytes::from_slice(password),
+            workers: Workers::new(workers.max(1)),
+            password_hashes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns `hash_argon2(password, salt, kdf)`, hashing only on the first call for `salt`
+    /// and `kdf`. Concurrent calls for the same salt wait for that hash.
+    pub fn password_hash(&self, salt: &[u8], kdf: &KdfParams) -> Result<Arc<Zeroizing<Vec<u8>>>> {
+        let cell = lock(&self.password_hashes)
+            .entry((salt.to_vec(), *kdf))
+            .or_default()
+            .clone();
+        cell.get_or_init(|| {
+            self.hash(&self.password, salt, kdf)
+                .map(Arc::new)
+                .map_err(|e| match e {
+                    Error::Crypto(message) => message,
+                    e => e.to_string(),
+                })
+        })
+        .clone()
+        .map_err(Error::Crypto)
+    }
+
+    /// Returns `hash_argon2(input, salt, kdf)` once one of the session's workers is free.
+    pub fn hash(&self, input: &[u8], salt: &[u8], kdf: &KdfParams) -> Result<Zeroizing<Vec<u8>>> {
+        let _worker = self.workers.acquire();
+        hash_argon2(input, salt, kdf)
+    }
+}
+
+/// Counting semaphore bounding the concurrent hashes of a `KdfSession`.
+struct Workers {
+    free: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Workers {
+    fn new(count: usize) -> Self {
+        Self {
+            free: Mutex::new(count),
+            released: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) -> Worker<'_> {
+        let mut free = lock(&self.free);
+        while *free == 0 {
+            free = self
+                .released
+                .wait(free)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        *free -= 1;
+        Worker(self)
+    }
+}
+
+/// A worker of `Workers`, released when dropped.
+struct Worker<'a>(&'a Workers);
+
+impl Drop for Worker<'_> {
+    fn drop(&mut self) {
+        *lock(&self.0.free) += 1;
+        self.0.released.notify_one();
+    }
+}
+
+/// Locks a mutex whose data stays consistent even if a holder panicked.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Smallest memory size in KiB tried by `tune_argon2`.
+const TUNE_MIN_MEMORY: u32 = 65536; // 64 MB
+
+/// Time and memory used by a single `hash_argon2` call.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KdfBenchmark {
+    /// The parameters that were measured.
+    pub params: KdfParams,
+    /// Wall clock time of the hash.
+    pub duration: Duration,
+    /// Peak resident memory of the whole process in KiB while hashing, if the platform
+    /// reports it. It includes the memory in use before the hash, not only Argon2's.
+    pub peak_memory: Option<u64>,
+}
+
+/// Candidates measured by `tune_argon2` and the parameters it recommends.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KdfTuning {
+    /// One benchmark per candidate, the last one for the recommended parameters.
+    pub candidates: Vec<KdfBenchmark>,
+    /// The parameters closest to the target time.
+    pub recommended: KdfParams,
+}
 
-    // Perform the hashing operation
-    let hash = argon2
-        .hash_password(password, &salt_string)
-        .map_err(|e: argon2::password_hash::Error| Error::Crypto(e.to_string()))?;
+/// Measures a single `hash_argon2` call with `kdf` on this machine.
+pub fn bench_argon2(kdf: &KdfParams) -> Result<KdfBenchmark> {
+    reset_peak_memory();
+    let start = Instant::now();
+    hash_argon2(b"shavee benchmark", b"shavee benchmark salt", kdf)?;
+    Ok(KdfBenchmark {
+        params: *kdf,
+        duration: start.elapsed(),
+        peak_memory: peak_memory(),
+    })
+}
+
+/// Finds Argon2 parameters for which a hash takes about `target` on this machine.
+///
+/// Memory sizes from 64 MiB up to `max_memory` KiB are measured with a single iteration,
+/// doubling each time. The largest one within `target` is recommended, with as many
+/// iterations as fit in `target`. Memory is preferred over iterations because it is what
+/// makes Argon2 expensive to attack with dedicated hardware.
+pub fn tune_argon2(target: Duration, max_memory: u32, lanes: u32) -> Result<KdfTuning> {
+    let mut candidates = Vec::new();
+    let mut best: Option<KdfBenchmark> = None;
+    let mut memory = TUNE_MIN_MEMORY.min(max_memory);
+    loop {
+        let benchmark = bench_argon2(&KdfParams::new(memory, 1, lanes)?)?;
+        candidates.push(benchmark);
+        if benchmark.duration > target {
+            break;
+        }
+        best = Some(benchmark);
+        if memory >= max_memory {
+            break;
+        }
+        memory = memory.saturating_mul(2).min(max_memory);
+    }
+
+    // Even the smallest memory size is too slow, so use it with a single iteration
+    let best = best.unwrap_or(candidates[0]);
+    let iterations = (target.as_secs_f64() / best.duration.as_secs_f64().max(f64::EPSILON))
+        .floor()
+        .clamp(1.0, u32::MAX as f64) as u32;
+    let recommended = KdfParams::new(best.params.memory, iterations, lanes)?;
+    if candidates.last().map(|candidate| candidate.params) != Some(recommended) {
+        candidates.push(bench_argon2(&recommended)?);
+    }
 
-    // Extract the hash bytes
-    hash.hash
-        .map(|h: argon2::password_hash::Output| h.as_bytes().to_vec())
-        .ok_or_else(|| Error::Crypto("Argon2 produced an empty hash".to_string()))
+    Ok(KdfTuning {
+        candidates,
+        recommended,
+    })
+}
+
+/// Resets the peak resident memory (`VmHWM`) of the process to its current resident memory,
+/// on Linux 4.0 and later, so `peak_memory` reports the peak of the next hash rather than
+/// the largest one measured so far.
+fn reset_peak_memory() {
+    // Failing leaves the peak of the process so far, which is still an upper bound
+    let _ = std::fs::write("/proc/self/clear_refs", "5");
+}
+
+/// Returns the peak resident memory (`VmHWM`) of the process in KiB since the last
+/// `reset_peak_memory`, on Linux.
+fn peak_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
 }
 
 #[cfg(test)]
@@ -66,8 +307,8 @@ mod tests {
     fn test_hash_argon2_deterministic() {
         let password = b"my_secure_password";
         let salt = b"somesalt";
-        let hash1 = hash_argon2(password, salt).unwrap();
-        let hash2 = hash_argon2(password, salt).unwrap();
+        let hash1 = hash_argon2(password, salt, &KdfParams::default()).unwrap();
+        let hash2 = hash_argon2(password, salt, &KdfParams::default()).unwrap();
 
         assert_eq!(
             hash1, hash2,
@@ -75,11 +316,71 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_hash_argon2_known_vector() {
+        // Keys of existing datasets depend on this exact output
+        let kdf = KdfParams::new(1024, 1, 1).unwrap();
+        let hash = hash_argon2(b"hunter2", b"somesalt", &kdf).unwrap();
+        assert_eq!(
+            base64::Engine::encode(&crate::logic::BASE64_ENGINE, hash),
+            "jcxyNPZZDOtFQ6PkxdDpzYX+TVbzybzz1yRina1PONBlUdW6omq+Zox9FjxGoy8dRCG4SSSeOqpSFEBqCK2h5w"
+        );
+    }
+
+    #[test]
+    fn test_kdf_session_password_hash() {
+        let kdf = KdfParams::new(1024, 1, 1).unwrap();
+        let session = KdfSession::new(b"hunter2", 2);
+
+        let first = session.password_hash(b"somesalt", &kdf).unwrap();
+        assert_eq!(
+            **first,
+            *hash_argon2(b"hunter2", b"somesalt", &kdf).unwrap()
+        );
+        // Same salt and parameters share the hash, anything else is hashed again
+        assert!(Arc::ptr_eq(
+            &first,
+            &session.password_hash(b"somesalt", &kdf).unwrap()
+        ));
+        assert_ne!(first, session.password_hash(b"othersalt", &kdf).unwrap());
+        let other = KdfParams::new(2048, 1, 1).unwrap();
+        assert_ne!(first, session.password_hash(b"somesalt", &other).unwrap());
+
+        // Errors are reported to every caller
+        for _ in 0..2 {
+            assert!(matches!(
+                session.password_hash(b"salt", &kdf),
+                Err(Error::Crypto(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_workers_bound_concurrency() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let workers = Workers::new(2);
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let _worker = workers.acquire();
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(10));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
     #[test]
     fn test_hash_argon2_different_passwords() {
         let salt = b"somesalt";
-        let hash1 = hash_argon2(b"pass1", salt).unwrap();
-        let hash2 = hash_argon2(b"pass2", salt).unwrap();
+        let hash1 = hash_argon2(b"pass1", salt, &KdfParams::default()).unwrap();
+        let hash2 = hash_argon2(b"pass2", salt, &KdfParams::default()).unwrap();
 
         assert_ne!(
             hash1, hash2,
@@ -90,8 +391,8 @@ mod tests {
     #[test]
     fn test_hash_argon2_different_salts() {
         let password = b"my_secure_password";
-        let hash1 = hash_argon2(password, b"somesalt1").unwrap();
-        let hash2 = hash_argon2(password, b"somesalt2").unwrap();
+        let hash1 = hash_argon2(password, b"somesalt1", &KdfParams::default()).unwrap();
+        let hash2 = hash_argon2(password, b"somesalt2", &KdfParams::default()).unwrap();
 
         assert_ne!(
             hash1, hash2,
@@ -99,10 +400,35 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_hash_argon2_params() {
+        let small = KdfParams::new(1024, 1, 1).unwrap();
+        let hash1 = hash_argon2(b"pass", b"somesalt", &small).unwrap();
+        let hash2 =
+            hash_argon2(b"pass", b"somesalt", &KdfParams::new(2048, 1, 1).unwrap()).unwrap();
+        assert_ne!(
+            hash1, hash2,
+            "Different parameters should result in different hashes"
+        );
+
+        assert!(KdfParams::new(1024, 0, 1).is_err());
+        assert!(KdfParams::new(8, 1, 4).is_err());
+    }
+
+    #[test]
+    fn test_tune_argon2() {
+        let tuning = tune_argon2(Duration::from_millis(200), 2048, 1).unwrap();
+        assert!(tuning.recommended.memory <= 2048);
+        assert_eq!(tuning.recommended.lanes, 1);
+        assert!(tuning.recommended.iterations >= 1);
+        assert_eq!(tuning.candidates[0].params.memory, 2048);
+        assert_eq!(tuning.candidates.last().unwrap().params, tuning.recommended);
+    }
+
     #[test]
     fn test_hash_argon2_empty_password() {
         let salt = b"somesalt";
-        let hash1 = hash_argon2(b"", salt);
+        let hash1 = hash_argon2(b"", salt, &KdfParams::default());
         assert!(hash1.is_ok(), "Should be able to hash an empty password");
     }
 }
@@ -0,0 +1,134 @@
+//! Memory handling for passwords and derived keys.
+//!
+//! Secrets are wrapped in `Zeroizing` so they are wiped when dropped instead of lingering in
+//! freed memory. Passwords, which live for the whole operation, are kept in `SecretBytes`,
+//! which additionally locks its pages in RAM so they are never written to swap.
+
+use std::fmt;
+use std::ops::Deref;
+use zeroize::Zeroize;
+
+pub use zeroize::Zeroizing;
+
+/// Secret bytes, such as a password, zeroized on drop and locked in RAM where permitted.
+///
+/// Locking uses `mlock`, which fails without `CAP_IPC_LOCK` once `RLIMIT_MEMLOCK` is reached;
+/// the bytes are then only zeroized. Pages are unlocked when the buffer is dropped, even if
+/// another buffer shares them, so locking is best effort.
+pub struct SecretBytes {
+    bytes: Zeroizing<Vec<u8>>,
+    locked: bool,
+}
+
+impl SecretBytes {
+    /// Takes ownership of `bytes` without copying them and locks them in RAM.
+    pub fn new(bytes: Vec<u8>) -> Self {
+        let bytes = Zeroizing::new(bytes);
+        let locked = lock_memory(&bytes);
+        Self { bytes, locked }
+    }
+
+    /// Copies `bytes` into a new locked buffer.
+    pub fn from_slice(bytes: &[u8]) -> Self {
+        Self::new(bytes.to_vec())
+    }
+
+    /// Whether the pages holding the bytes are locked in RAM.
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+}
+
+impl From<String> for SecretBytes {
+    fn from(value: String) -> Self {
+        Self::new(value.into_bytes())
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl AsRef<[u8]> for SecretBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl Clone for SecretBytes {
+    fn clone(&self) -> Self {
+        Self::from_slice(&self.bytes)
+    }
+}
+
+impl PartialEq for SecretBytes {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl Eq for SecretBytes {}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretBytes(..)")
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        // Wipe the bytes before their pages can be swapped out again
+        self.bytes.zeroize();
+        if self.locked {
+            unlock_memory(&self.bytes);
+        }
+    }
+}
+
+/// Locks the allocation of `bytes` (including spare capacity) in RAM.
+#[cfg(unix)]
+fn lock_memory(bytes: &Vec<u8>) -> bool {
+    if bytes.capacity() == 0 {
+        return false;
+    }
+    // SAFETY: the range is the vector's own allocation, which outlives the lock
+    unsafe { libc::mlock(bytes.as_ptr().cast(), bytes.capacity()) == 0 }
+}
+
+#[cfg(unix)]
+fn unlock_memory(bytes: &Vec<u8>) {
+    // SAFETY: the range is the vector's own allocation, locked by `lock_memory`
+    unsafe {
+        libc::munlock(bytes.as_ptr().cast(), bytes.capacity());
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_memory(_bytes: &Vec<u8>) -> bool {
+    false
+}
+
+#[cfg(not(unix))]
+fn unlock_memory(_bytes: &Vec<u8>) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_bytes() {
+        let secret = SecretBytes::from("hunter2".to_string());
+        assert_eq!(&*secret, b"hunter2");
+        assert_eq!(secret.clone(), SecretBytes::from_slice(b"hunter2"));
+        assert_eq!(format!("{:?}", secret), "SecretBytes(..)");
+
+        // Nothing to lock without an allocation
+        let empty = SecretBytes::new(Vec::new());
+        assert!(empty.is_empty());
+        assert!(!empty.is_locked());
+    }
+}
@@ -0,0 +1,356 @@
+//! Inspection of the Shavee configuration stored on datasets.
+//!
+//! `status` reads the Shavee properties of datasets along with their native encryption, and
+//! flags configurations worth a look, like datasets relying on the static fallback salt or
+//! missing properties written by current versions. Nothing is derived or changed.
+
+use crate::structs::KeyFormat;
+use crate::zfs::{self, Dataset, Encryption, ShaveeProperties, ZfsShaveeProperties};
+use crate::{Result, logic};
+use std::collections::BTreeMap;
+use std::fmt;
+use strum::IntoEnumIterator;
+use strum_macros::Display;
+
+/// Native properties read by `status` besides `Encryption::PROPERTIES`.
+const STATUS_PROPERTIES: [&str; 2] = ["keyformat", "mounted"];
+
+/// Properties written by current versions on every dataset they create or rekey, whatever
+/// the second factor. The key check value is only written on request.
+const EXPECTED_PROPERTIES: [ZfsShaveeProperties; 7] = [
+    ZfsShaveeProperties::SecondFactor,
+    ZfsShaveeProperties::Version,
+    ZfsShaveeProperties::KeyFormat,
+    ZfsShaveeProperties::KdfMemory,
+    ZfsShaveeProperties::KdfIterations,
+    ZfsShaveeProperties::KdfLanes,
+    ZfsShaveeProperties::Kdf,
+];
+
+/// Where the salt of a dataset comes from, following the precedence of `logic::get_salt`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum SaltSource {
+    /// The `com.github.shavee:salt` property of the dataset.
+    Dataset,
+    /// The `SHAVEE_SALT` environment variable of the current process.
+    Environment,
+    /// `STATIC_SALT`, shared by every installation.
+    Static,
+}
+
+/// A configuration issue found by `status`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// Shavee properties are set on a dataset without native encryption.
+    NotEncrypted,
+    /// No salt is stored, keys are derived with the static fallback salt.
+    StaticSalt,
+    /// No salt is stored, keys are derived with `SHAVEE_SALT`, which every process unlocking
+    /// the dataset must set.
+    EnvironmentSalt,
+    /// A property current versions write is unset, its default is assumed.
+    MissingProperty(ZfsShaveeProperties),
+    /// A property is set to a value that can't be used.
+    InvalidProperty(ZfsShaveeProperties, String),
+    /// The key format stored by shavee is not the `keyformat` of the dataset.
+    KeyFormatMismatch { stored: KeyFormat, zfs: KeyFormat },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::NotEncrypted => write!(f, "Shavee properties on an unencrypted dataset"),
+            Warning::StaticSalt => write!(f, "no salt stored, the static fallback salt is used"),
+            Warning::EnvironmentSalt => {
+                write!(
+                    f,
+                    "no salt stored, the {} variable is used",
+                    crate::ENV_SALT_VARIABLE
+                )
+            }
+            Warning::MissingProperty(property) => write!(f, "{} is not set", property),
+            Warning::InvalidProperty(property, value) => {
+                write!(f, "{} has an invalid value \"{}\"", property, value)
+            }
+            Warning::KeyFormatMismatch { stored, zfs } => write!(
+                f,
+                "stored keyformat {} does not match the ZFS keyformat {}",
+                stored, zfs
+            ),
+        }
+    }
+}
+
+/// The Shavee configuration and native encryption of a dataset, read by `status`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatasetStatus {
+    pub dataset: Dataset,
+    pub properties: ShaveeProperties,
+    pub encryption: Encryption,
+    /// The ZFS `keyformat`, `None` if the dataset isn't encrypted.
+    pub key_format: Option<KeyFormat>,
+    /// Whether the dataset is mounted.
+    pub mounted: bool,
+    pub warnings: Vec<Warning>,
+}
+
+impl DatasetStatus {
+    /// Returns true if any Shavee property is set on the dataset itself. Values inherited from
+    /// a parent describe the key of that parent, not of this dataset.
+    pub fn is_managed(&self) -> bool {
+        self.properties != ShaveeProperties::default()
+    }
+
+    /// Returns where the salt of a managed dataset comes from, `None` if it isn't managed.
+    pub fn salt_source(&self) -> Option<SaltSource> {
+        if !self.is_managed() {
+            None
+        } else if self.properties.salt.is_some() {
+            Some(SaltSource::Dataset)
+        } else if std::env::var_os(crate::ENV_SALT_VARIABLE).is_some() {
+            Some(SaltSource::Environment)
+        } else {
+            Some(SaltSource::Static)
+        }
+    }
+}
+
+/// Reads the Shavee configuration of a set of datasets, and of all their children if
+/// `recursive`, parents first.
+///
+/// Datasets inheriting their encryption are reported without warnings about their Shavee
+/// properties, as their key is the one of their encryption root.
+pub fn status(datasets: &[Dataset], recursive: bool) -> Result<Vec<DatasetStatus>> {
+    let mut names = ZfsShaveeProperties::names();
+    names.extend(Encryption::PROPERTIES.map(String::from));
+    names.extend(STATUS_PROPERTIES.map(String::from));
+
+    Ok(zfs::get_properties(datasets, &names, recursive)?
+        .into_iter()
+        .map(|(dataset, values)| {
+            let mut status = DatasetStatus {
+                encryption: Encryption::from_values(dataset.name(), &values),
+                properties: ShaveeProperties::from_values(&values),
+                key_format: values.get("keyformat").and_then(|value| value.parse().ok()),
+                mounted: values.get("mounted").is_some_and(|value| value == "yes"),
+                warnings: Vec::new(),
+                dataset,
+            };
+            status.warnings = warnings(&status, &values);
+            status
+        })
+        .collect())
+}
+
+/// Checks the configuration of a dataset, from its parsed `status` and raw `values`.
+fn warnings(status: &DatasetStatus, values: &BTreeMap<String, String>) -> Vec<Warning> {
+    if !status.is_managed() {
+        return Vec::new();
+    }
+    match status.encryption {
+        Encryption::Off => return vec![Warning::NotEncrypted],
+        Encryption::Inherited { .. } => return Vec::new(),
+        Encryption::Root { .. } => (),
+    }
+
+    let mut warnings = Vec::new();
+    match status.salt_source() {
+        Some(SaltSource::Static) => warnings.push(Warning::StaticSalt),
+        Some(SaltSource::Environment) => warnings.push(Warning::EnvironmentSalt),
+        _ => (),
+    }
+
+    for property in ZfsShaveeProperties::iter() {
+        match values.get(&property.to_string()) {
+            Some(value) if !is_valid(&status.properties, property, value) => {
+                warnings.push(Warning::InvalidProperty(property, value.clone()))
+            }
+            Some(_) => (),
+            None if EXPECTED_PROPERTIES.contains(&property) => {
+                warnings.push(Warning::MissingProperty(property))
+            }
+            None => (),
+        }
+    }
+    #[cfg(feature = "file")]
+    if status.properties.second_factor.as_deref() == Some("File")
+        && status.properties.file_path.is_none()
+    {
+        warnings.push(Warning::MissingProperty(ZfsShaveeProperties::FilePath));
+    }
+
+    // Datasets without a stored keyformat are unlocked with passphrase keys
+    let stored = status.properties.key_format.unwrap_or_default();
+    if let Some(zfs) = status.key_format
+        && stored != zfs
+    {
+        warnings.push(Warning::KeyFormatMismatch { stored, zfs });
+    }
+    warnings
+}
+
+/// Returns true if `value`, the value of `property`, can be used to unlock the dataset.
+fn is_valid(properties: &ShaveeProperties, property: ZfsShaveeProperties, value: &str) -> bool {
+    match property {
+        ZfsShaveeProperties::Salt => logic::salt_from_property(Some(value)).is_ok(),
+        ZfsShaveeProperties::SecondFactor => {
+            value == "Password"
+                || (cfg!(feature = "yubikey") && value == "Yubikey")
+                || (cfg!(feature = "file") && value == "File")
+        }
+        #[cfg(feature = "yubikey")]
+        ZfsShaveeProperties::YubikeySlot => properties.yubikey_slot.is_some(),
+        ZfsShaveeProperties::YubikeySerial => properties.yubikey_serial.is_some(),
+        #[cfg(feature = "file")]
+        ZfsShaveeProperties::FilePort => properties.file_port.is_some(),
+        #[cfg(feature = "file")]
+        ZfsShaveeProperties::FileSize => properties.file_size.is_some(),
+        ZfsShaveeProperties::KeyFormat => properties.key_format.is_some(),
+        ZfsShaveeProperties::KdfMemory => properties.kdf_memory.is_some(),
+        ZfsShaveeProperties::KdfIterations => properties.kdf_iterations.is_some(),
+        ZfsShaveeProperties::KdfLanes => properties.kdf_lanes.is_some(),
+        ZfsShaveeProperties::Kdf => properties.kdf_scheme().is_ok(),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::password::KdfParams;
+    use crate::structs::TwoFactorMode;
+    use crate::zfs::{InMemoryBackend, ZfsBackend};
+    use base64::Engine;
+    use std::sync::Arc;
+
+    fn memory_dataset(backend: &Arc<InMemoryBackend>, name: &str) -> Dataset {
+        Dataset::with_backend(name.to_string(), backend.clone()).unwrap()
+    }
+
+    #[test]
+    fn test_status() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let salt = logic::BASE64_ENGINE.encode(logic::generate_salt());
+        memory_dataset(&backend, "pool/home")
+            .create_2fa(
+                "derived",
+                TwoFactorMode::Password,
+                &salt,
+                KeyFormat::Passphrase,
+                &KdfParams::default(),
+                false,
+            )
+            .unwrap();
+        backend.add_inheriting_dataset("pool/home/a");
+        // An encryption root of its own under a managed parent
+        backend.add_encrypted_dataset("pool/home/b", "secret");
+        // Written by an early version, which only stored the second factor
+        backend.add_encrypted_dataset("pool/old", "secret");
+        let second_factor = ZfsShaveeProperties::SecondFactor.to_string();
+        backend
+            .set_property("pool/old", &second_factor, "Password")
+            .unwrap();
+        backend.add_dataset("pool/plain");
+        backend
+            .set_property("pool/plain", &second_factor, "Password")
+            .unwrap();
+
+        let datasets = [
+            memory_dataset(&backend, "pool"),
+            memory_dataset(&backend, "pool/old"),
+            memory_dataset(&backend, "pool/plain"),
+        ];
+        let mut statuses = status(&datasets, false).unwrap();
+        statuses.extend(status(&[memory_dataset(&backend, "pool/home")], true).unwrap());
+        let names: Vec<&str> = statuses.iter().map(|s| s.dataset.name()).collect();
+        assert_eq!(
+            names,
+            [
+                "pool",
+                "pool/old",
+                "pool/plain",
+                "pool/home",
+                "pool/home/a",
+                "pool/home/b"
+            ]
+        );
+
+        assert!(!statuses[0].is_managed());
+        assert!(statuses[0].warnings.is_empty());
+        assert_eq!(statuses[0].encryption, Encryption::Off);
+
+        let old = &statuses[1];
+        assert_eq!(old.salt_source(), Some(SaltSource::Static));
+        assert_eq!(old.warnings[0], Warning::StaticSalt);
+        assert!(
+            old.warnings
+                .contains(&Warning::MissingProperty(ZfsShaveeProperties::Version))
+        );
+        assert!(
+            !old.warnings
+                .contains(&Warning::MissingProperty(ZfsShaveeProperties::SecondFactor))
+        );
+
+        assert_eq!(statuses[2].warnings, [Warning::NotEncrypted]);
+
+        let home = &statuses[3];
+        assert!(home.is_managed() && home.mounted);
+        assert_eq!(home.encryption, Encryption::Root { key_loaded: true });
+        assert_eq!(home.key_format, Some(KeyFormat::Passphrase));
+        assert_eq!(home.salt_source(), Some(SaltSource::Dataset));
+        assert!(home.warnings.is_empty(), "{:?}", home.warnings);
+        assert!(statuses[4].warnings.is_empty());
+
+        let separate = &statuses[5];
+        assert!(!separate.is_managed());
+        assert_eq!(separate.properties, ShaveeProperties::default());
+        assert!(separate.warnings.is_empty(), "{:?}", separate.warnings);
+    }
+
+    #[test]
+    fn test_status_invalid_properties() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let home = memory_dataset(&backend, "pool/home");
+        home.create_2fa(
+            "derived",
+            TwoFactorMode::Password,
+            &logic::BASE64_ENGINE.encode(logic::generate_salt()),
+            KeyFormat::Passphrase,
+            &KdfParams::default(),
+            false,
+        )
+        .unwrap();
+        let memory = ZfsShaveeProperties::KdfMemory.to_string();
+        backend
+            .set_properties(
+                "pool/home",
+                &[
+                    (memory, "lots".to_string()),
+                    (
+                        ZfsShaveeProperties::KeyFormat.to_string(),
+                        "hex".to_string(),
+                    ),
+                ],
+            )
+            .unwrap();
+
+        let warnings = status(&[home], false).unwrap().remove(0).warnings;
+        assert_eq!(
+            warnings,
+            [
+                Warning::InvalidProperty(ZfsShaveeProperties::KdfMemory, "lots".to_string()),
+                Warning::KeyFormatMismatch {
+                    stored: KeyFormat::Hex,
+                    zfs: KeyFormat::Passphrase
+                },
+            ]
+        );
+        assert_eq!(
+            warnings[1].to_string(),
+            "stored keyformat hex does not match the ZFS keyformat passphrase"
+        );
+    }
+}
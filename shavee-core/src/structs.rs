@@ -1,7 +1,13 @@
 //! Data structures for Shavee configuration.
 
+use crate::trace::Secret;
+use std::fmt;
+use strum_macros::{Display, EnumString};
+
 /// Supported second-factor authentication modes.
-#[derive(Debug, Clone, PartialEq)]
+///
+/// The file location is redacted from the `Debug` output, which ends up in trace logs.
+#[derive(Clone, PartialEq)]
 pub enum TwoFactorMode {
     /// Use a Yubikey HMAC-SHA1 challenge-response.
     #[cfg(feature = "yubikey")]
@@ -24,3 +30,39 @@ pub enum TwoFactorMode {
     /// No second factor, only password.
     Password,
 }
+
+impl fmt::Debug for TwoFactorMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "yubikey")]
+            TwoFactorMode::Yubikey { yslot, serial } => f
+                .debug_struct("Yubikey")
+                .field("yslot", yslot)
+                .field("serial", serial)
+                .finish(),
+            #[cfg(feature = "file")]
+            TwoFactorMode::File { file, port, size } => f
+                .debug_struct("File")
+                .field("file", &Secret::new(file))
+                .field("port", port)
+                .field("size", size)
+                .finish(),
+            TwoFactorMode::Password => f.write_str("Password"),
+        }
+    }
+}
+
+/// Format in which the derived key is handed to ZFS, i.e. the dataset's `keyformat`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display, EnumString)]
+pub enum KeyFormat {
+    /// The base64-encoded derived key is used as a passphrase.
+    #[default]
+    #[strum(serialize = "passphrase")]
+    Passphrase,
+    /// The first 32 bytes of the derived key are used as a raw key.
+    #[strum(serialize = "raw")]
+    Raw,
+    /// The first 32 bytes of the derived key are used as a hex-encoded key.
+    #[strum(serialize = "hex")]
+    Hex,
+}
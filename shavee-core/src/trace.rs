@@ -0,0 +1,326 @@
+//! Trace logging, enabled with the "trace" feature, and event reporting through `Logger`.
+//!
+//! Messages must never contain passwords, keys or other secrets. Values that identify key
+//! material, like salts and second factor file locations, are wrapped in `Secret` when they
+//! are formatted into a message, so they show up as `[REDACTED]`.
+
+use std::borrow::Cow;
+use std::fmt;
+use strum_macros::{Display, EnumString};
+
+/// Text written in place of a secret value.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// A value that must not end up in logs.
+///
+/// `Debug` and `Display` print `[REDACTED]` whatever `T` is, so a `Secret` can be formatted
+/// into trace messages and error contexts without leaking the value.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    /// Returns the wrapped value, which must not be logged.
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// Syslog facility for security and authorization messages (`LOG_AUTHPRIV`).
+const LOG_AUTHPRIV: u8 = 10;
+
+/// Socket of the local syslog daemon.
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// Socket of the systemd journal's native protocol.
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Destination of the events reported through a `Logger`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum LogSink {
+    /// Standard error, with the fields appended to the message.
+    #[default]
+    Stderr,
+    /// The local syslog daemon, with the fields appended to the message.
+    Syslog,
+    /// The systemd journal, with each field as a `SHAVEE_*` journal field.
+    Journald,
+}
+
+/// Severity of an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+    Info,
+}
+
+impl Level {
+    /// The matching syslog severity.
+    fn priority(self) -> u8 {
+        match self {
+            Level::Error => 3,
+            Level::Warning => 4,
+            Level::Info => 6,
+        }
+    }
+}
+
+/// Reports events with structured fields to a `LogSink`.
+///
+/// Unlike `trace` it doesn't need the "trace" feature: it is how the PAM module reports
+/// logins, whose standard error is invisible to most display managers. Events that can't be
+/// delivered to syslog or the journal are written to standard error instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Logger {
+    ident: String,
+    sink: LogSink,
+}
+
+impl Logger {
+    /// Creates a logger tagging its events with `ident`, e.g. "shavee-pam".
+    pub fn new(ident: &str, sink: LogSink) -> Self {
+        Self {
+            ident: ident.to_string(),
+            sink,
+        }
+    }
+
+    pub fn sink(&self) -> LogSink {
+        self.sink
+    }
+
+    /// Starts an event; add its fields with `Event::field` and report it with `Event::send`.
+    pub fn event(&self, level: Level, message: impl Into<String>) -> Event<'_> {
+        Event {
+            logger: self,
+            level,
+            message: message.into(),
+            fields: Vec::new(),
+        }
+    }
+}
+
+/// An event being built by `Logger::event`.
+#[must_use = "events are only reported by `send`"]
+pub struct Event<'a> {
+    logger: &'a Logger,
+    level: Level,
+    message: String,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Event<'_> {
+    /// Adds a field, `name` being a lowercase identifier like "user" or "dataset".
+    pub fn field(mut self, name: &'static str, value: impl fmt::Display) -> Self {
+        self.fields.push((name, value.to_string()));
+        self
+    }
+
+    /// Reports the event to the sink of the logger.
+    pub fn send(self) {
+        let delivered = match self.logger.sink {
+            LogSink::Stderr => false,
+            LogSink::Syslog => send_datagram(SYSLOG_SOCKET, self.syslog_datagram().as_bytes()),
+            LogSink::Journald => send_datagram(JOURNALD_SOCKET, &self.journal_datagram()),
+        };
+        if !delivered {
+            eprintln!("{}: {}", self.logger.ident, self.text());
+        }
+    }
+
+    /// The message followed by the fields as `name=value`, quoting values with spaces.
+    fn text(&self) -> String {
+        let mut text = self.message.clone();
+        for (name, value) in &self.fields {
+            let value: Cow<str> = if value.is_empty()
+                || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=')
+            {
+                Cow::Owned(format!("{:?}", value))
+            } else {
+                Cow::Borrowed(value)
+            };
+            text.push_str(&format!(" {}={}", name, value));
+        }
+        text
+    }
+
+    /// An RFC 3164 message, without timestamp and hostname which the daemon fills in.
+    fn syslog_datagram(&self) -> String {
+        format!(
+            "<{}>{}[{}]: {}",
+            LOG_AUTHPRIV * 8 + self.level.priority(),
+            self.logger.ident,
+            std::process::id(),
+            self.text()
+        )
+    }
+
+    /// A journal entry in the native protocol.
+    fn journal_datagram(&self) -> Vec<u8> {
+        let mut datagram = Vec::new();
+        append_journal_field(&mut datagram, "MESSAGE", &self.message);
+        append_journal_field(
+            &mut datagram,
+            "PRIORITY",
+            &self.level.priority().to_string(),
+        );
+        append_journal_field(&mut datagram, "SYSLOG_FACILITY", &LOG_AUTHPRIV.to_string());
+        append_journal_field(&mut datagram, "SYSLOG_IDENTIFIER", &self.logger.ident);
+        for (name, value) in &self.fields {
+            let name = format!("SHAVEE_{}", name.to_uppercase());
+            append_journal_field(&mut datagram, &name, value);
+        }
+        datagram
+    }
+}
+
+fn append_journal_field(datagram: &mut Vec<u8>, name: &str, value: &str) {
+    datagram.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        // Values spanning lines are sent as the name, a newline and their little endian length
+        datagram.push(b'\n');
+        datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+    datagram.extend_from_slice(value.as_bytes());
+    datagram.push(b'\n');
+}
+
+#[cfg(unix)]
+fn send_datagram(path: &str, datagram: &[u8]) -> bool {
+    std::os::unix::net::UnixDatagram::unbound()
+        .and_then(|socket| socket.send_to(datagram, path))
+        .is_ok()
+}
+
+#[cfg(not(unix))]
+fn send_datagram(_path: &str, _datagram: &[u8]) -> bool {
+    false
+}
+
+/// Initializes the logging system if the "trace" feature is enabled.
+///
+/// # Arguments
+/// * `_is_test` - Whether the logger is being initialized for a test environment.
+pub fn trace_init(_is_test: bool) {
+    #[cfg(feature = "trace")]
+    {
+        if _is_test {
+            let _ = env_logger::builder().is_test(true).try_init();
+        } else {
+            env_logger::init();
+        }
+    }
+}
+
+/// Logs a trace message if the "trace" feature is enabled.
+pub fn trace(_message: &str) {
+    #[cfg(feature = "trace")]
+    log::trace!("{}", _message);
+}
+
+/// Logs an error message if the "trace" feature is enabled.
+pub fn error(_message: &str) {
+    #[cfg(feature = "trace")]
+    log::error!("{}", _message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_is_redacted() {
+        let salt = Secret::new(b"somesalt".to_vec());
+        assert_eq!(format!("salt: {:?}", salt), "salt: [REDACTED]");
+        assert_eq!(format!("{}", Secret::new("/mnt/usb/keyfile")), REDACTED);
+        assert_eq!(salt.expose(), b"somesalt");
+        assert_eq!(salt.into_inner(), b"somesalt".to_vec());
+    }
+
+    #[test]
+    fn test_log_sink_from_str() {
+        assert_eq!("syslog".parse::<LogSink>().unwrap(), LogSink::Syslog);
+        assert_eq!("journald".parse::<LogSink>().unwrap(), LogSink::Journald);
+        assert_eq!(LogSink::default().to_string(), "stderr");
+        assert!("eventlog".parse::<LogSink>().is_err());
+    }
+
+    #[test]
+    fn test_event_formats() {
+        let logger = Logger::new("shavee-pam", LogSink::Syslog);
+        let event = logger
+            .event(Level::Error, "authentication failed")
+            .field("user", "alice")
+            .field("outcome", "wrong password")
+            .field("file", Secret::new("/mnt/usb/keyfile"));
+
+        assert_eq!(
+            event.text(),
+            "authentication failed user=alice outcome=\"wrong password\" file=[REDACTED]"
+        );
+        // authpriv.err
+        assert!(event.syslog_datagram().starts_with("<83>shavee-pam["));
+        assert!(event.syslog_datagram().ends_with(
+            "]: authentication failed user=alice outcome=\"wrong password\" file=[REDACTED]"
+        ));
+
+        let journal = event.journal_datagram();
+        let journal = String::from_utf8_lossy(&journal);
+        assert!(journal.starts_with("MESSAGE=authentication failed\nPRIORITY=3\n"));
+        assert!(journal.contains("SYSLOG_IDENTIFIER=shavee-pam\n"));
+        assert!(journal.contains("SHAVEE_USER=alice\nSHAVEE_OUTCOME=wrong password\n"));
+    }
+
+    #[test]
+    fn test_journal_multiline_field() {
+        let mut datagram = Vec::new();
+        append_journal_field(&mut datagram, "MESSAGE", "a\nb");
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+        assert_eq!(datagram, expected);
+    }
+
+    #[cfg(feature = "file")]
+    #[test]
+    fn test_second_factor_file_is_redacted() {
+        let mode = crate::structs::TwoFactorMode::File {
+            file: "https://example.com/keyfile".to_string(),
+            port: Some(443),
+            size: None,
+        };
+        assert_eq!(
+            format!("{:?}", mode),
+            "File { file: [REDACTED], port: Some(443), size: None }"
+        );
+    }
+}
@@ -0,0 +1,1459 @@
+//! Key derivation from stored Shavee properties and unlocking of datasets.
+//!
+//! These are the operations shared by the `shavee` binary and the PAM module, so both derive
+//! keys the same way and report the same typed errors. `Unlocker` drives the print, load and
+//! mount workflows over a set of datasets, and `lock` reverses them.
+
+use crate::kdf::KdfScheme;
+use crate::password::{self, KdfParams, KdfSession};
+use crate::secret::{SecretBytes, Zeroizing};
+use crate::structs::{KeyFormat, TwoFactorMode};
+use crate::zfs::{
+    self, CanMount, Dataset, Encryption, Mountpoint, Operation, ResolvedDataset, ShaveeProperties,
+};
+use crate::{Error, Result, logic};
+use base64::Engine;
+use challenge_response::Device;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use strum_macros::Display;
+
+/// What `Unlocker::run` does with the derived keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnlockAction {
+    /// Only derive the keys and report them in printable form.
+    Print,
+    /// Load the keys into ZFS.
+    Load,
+    /// Load the keys and mount the datasets.
+    #[default]
+    Mount,
+}
+
+/// Whether `Unlocker::run` loaded the key of a dataset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyStatus {
+    Loaded,
+    AlreadyLoaded,
+    /// The dataset shares the key of this encryption root, which is unlocked.
+    Inherited(String),
+}
+
+/// Whether `Unlocker::run` mounted a dataset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountStatus {
+    Mounted,
+    AlreadyMounted,
+    /// The dataset is unlocked but was not mounted.
+    Skipped(SkipReason),
+}
+
+/// Why `Unlocker::run` didn't mount a dataset, named after the property preventing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum SkipReason {
+    #[strum(serialize = "canmount=off")]
+    CanMountOff,
+    /// Children with `canmount=noauto` are only mounted when given explicitly.
+    #[strum(serialize = "canmount=noauto")]
+    CanMountNoAuto,
+    /// Legacy mountpoints are only mounted with `Unlocker::mount_legacy`.
+    #[strum(serialize = "mountpoint=legacy")]
+    Legacy,
+    #[strum(serialize = "mountpoint=none")]
+    NoMountpoint,
+}
+
+/// What `Unlocker::run` did for a dataset that succeeded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnlockStatus {
+    /// The derived key in printable form, for `UnlockAction::Print`.
+    Printed(Zeroizing<String>),
+    /// The key is loaded, and for `UnlockAction::Mount` the dataset is mounted.
+    Unlocked {
+        key: KeyStatus,
+        mount: Option<MountStatus>,
+    },
+}
+
+impl fmt::Display for UnlockStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnlockStatus::Printed(_) => write!(f, "key printed"),
+            UnlockStatus::Unlocked { key, mount } => {
+                match key {
+                    KeyStatus::Loaded => write!(f, "key loaded")?,
+                    KeyStatus::AlreadyLoaded => write!(f, "key already loaded")?,
+                    KeyStatus::Inherited(root) => write!(f, "key inherited from {}", root)?,
+                }
+                match mount {
+                    Some(MountStatus::Mounted) => write!(f, ", mounted"),
+                    Some(MountStatus::AlreadyMounted) => write!(f, ", already mounted"),
+                    Some(MountStatus::Skipped(reason)) => write!(f, ", not mounted ({})", reason),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// What `lock` did for a dataset that succeeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LockStatus {
+    /// The dataset was mounted and has been unmounted.
+    pub unmounted: bool,
+    /// The key of the dataset, or of its encryption root, has been unloaded.
+    pub key_unloaded: bool,
+}
+
+impl fmt::Display for LockStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.unmounted, self.key_unloaded) {
+            (true, true) => write!(f, "unmounted, key unloaded"),
+            (true, false) => write!(f, "unmounted"),
+            (false, true) => write!(f, "key unloaded"),
+            (false, false) => write!(f, "already locked"),
+        }
+    }
+}
+
+/// Outcome of `Unlocker::run` or `lock` for a single dataset.
+#[derive(Debug)]
+pub struct DatasetOutcome<T = UnlockStatus> {
+    /// The dataset the outcome is for.
+    pub dataset: Dataset,
+    /// What was done, or why the dataset failed.
+    pub result: Result<T>,
+    /// The second factor stored on the dataset, `None` if it has none or it wasn't read.
+    pub second_factor: Option<String>,
+}
+
+/// Per-dataset outcomes of `Unlocker::run` or `lock`, parents before their children.
+#[derive(Debug)]
+pub struct UnlockReport<T = UnlockStatus> {
+    /// One outcome per dataset.
+    pub outcomes: Vec<DatasetOutcome<T>>,
+}
+
+/// Per-dataset outcomes of `lock`.
+pub type LockReport = UnlockReport<LockStatus>;
+
+/// What `Unlocker::dry_run`, `dry_run_create` or `dry_run_rekey` found would be done.
+#[derive(Debug)]
+pub struct DryRun<T = UnlockStatus> {
+    /// The operations, in the order they would run.
+    pub operations: Vec<Operation>,
+    /// What would happen to each dataset, or why it would fail.
+    pub report: UnlockReport<T>,
+}
+
+impl<T> UnlockReport<T> {
+    /// Returns true if every dataset succeeded.
+    pub fn is_success(&self) -> bool {
+        self.outcomes.iter().all(|outcome| outcome.result.is_ok())
+    }
+
+    /// Returns the outcomes of the datasets that failed.
+    pub fn failures(&self) -> impl Iterator<Item = &DatasetOutcome<T>> {
+        self.outcomes
+            .iter()
+            .filter(|outcome| outcome.result.is_err())
+    }
+
+    /// Converts the report into the first error, if any dataset failed.
+    pub fn into_result(self) -> Result<()> {
+        self.outcomes
+            .into_iter()
+            .find_map(|outcome| outcome.result.err())
+            .map_or(Ok(()), Err)
+    }
+}
+
+/// Derives the keys of datasets from their stored Shavee properties and prints, loads or
+/// mounts them.
+///
+/// ```no_run
+/// use shavee_core::unlock::{UnlockAction, Unlocker};
+/// use shavee_core::zfs::Dataset;
+///
+/// let datasets = [Dataset::new("zroot/data/home".to_string())?];
+/// let report = Unlocker::new(&datasets, b"password")
+///     .recursive(true)
+///     .action(UnlockAction::Mount)
+///     .run()?;
+/// report.into_result()?;
+/// # Ok::<(), shavee_core::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct Unlocker {
+    datasets: Vec<Dataset>,
+    password: SecretBytes,
+    second_factor: Option<TwoFactorMode>,
+    recursive: bool,
+    action: UnlockAction,
+    yubikeys: Option<Arc<[Mutex<Device>]>>,
+    kdf_workers: usize,
+    mount_legacy: bool,
+}
+
+impl Unlocker {
+    /// Creates an unlocker that mounts `datasets` with keys derived from `password` and the
+    /// second factor stored on each dataset.
+    ///
+    /// The password is copied into a `SecretBytes` that lives as long as the unlocker.
+    pub fn new(datasets: &[Dataset], password: &[u8]) -> Self {
+        Self {
+            datasets: datasets.to_vec(),
+            password: SecretBytes::from_slice(password),
+            second_factor: None,
+            recursive: false,
+            action: UnlockAction::default(),
+            yubikeys: None,
+            kdf_workers: password::default_kdf_workers(),
+            mount_legacy: false,
+        }
+    }
+
+    /// Uses `second_factor` instead of the one stored on each dataset.
+    pub fn second_factor(mut self, second_factor: Option<TwoFactorMode>) -> Self {
+        self.second_factor = second_factor;
+        self
+    }
+
+    /// Also processes the children of the datasets.
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Sets what is done with the derived keys.
+    pub fn action(mut self, action: UnlockAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// Uses these Yubikeys instead of looking for connected ones when the unlocker runs.
+    pub fn yubikeys(mut self, yubikeys: Arc<[Mutex<Device>]>) -> Self {
+        self.yubikeys = Some(yubikeys);
+        self
+    }
+
+    /// Limits the number of Argon2 hashes running at once, which bounds the memory used to
+    /// derive the keys to `kdf_workers` times the Argon2 memory size of the datasets.
+    /// Defaults to `password::default_kdf_workers`.
+    pub fn kdf_workers(mut self, kdf_workers: usize) -> Self {
+        self.kdf_workers = kdf_workers;
+        self
+    }
+
+    /// Also mounts the datasets with `mountpoint=legacy`, through mount(8) which finds their
+    /// mountpoint in /etc/fstab. They are skipped by default.
+    pub fn mount_legacy(mut self, mount_legacy: bool) -> Self {
+        self.mount_legacy = mount_legacy;
+        self
+    }
+
+    /// Derives the keys of the encryption roots in parallel and loads them, then mounts the
+    /// datasets by mountpoint depth so parents are mounted before their children, and
+    /// datasets with `mountpoint=legacy` last.
+    ///
+    /// Datasets sharing the key of an encryption root are unlocked along with it without
+    /// deriving a key; for `UnlockAction::Print` they are left out of the report, like
+    /// unencrypted children, which have no key. The datasets passed to `new` are always
+    /// reported.
+    ///
+    /// Datasets sharing a salt and Argon2 parameters share the hash of the password (see
+    /// `KdfSession`). `create`, `rekey` and `passwd` store a fresh salt per dataset, so these
+    /// are the datasets without a stored salt, which all use `SHAVEE_SALT` or the static
+    /// fallback salt.
+    ///
+    /// Datasets that must not be mounted are reported with `MountStatus::Skipped`: those with
+    /// `canmount=off` or `mountpoint=none`, children with `canmount=noauto` and, unless
+    /// `mount_legacy` is set, datasets with `mountpoint=legacy`.
+    ///
+    /// Fails only if the datasets can't be resolved, per-dataset failures are in the report.
+    pub fn run(&self) -> Result<UnlockReport> {
+        let datasets = self.resolve()?;
+        let yubikeys = self.fetch_yubikeys();
+
+        let session = KdfSession::new(&self.password, self.kdf_workers);
+        let keys: Vec<Option<Result<Zeroizing<String>>>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = datasets
+                .iter()
+                .map(|resolved| {
+                    if !self.derives_key(resolved) {
+                        return None;
+                    }
+                    let yubikeys = yubikeys.as_deref();
+                    let session = &session;
+                    Some(scope.spawn(move || {
+                        stored_key(
+                            &resolved.dataset,
+                            &resolved.properties,
+                            session,
+                            self.second_factor.as_ref(),
+                            yubikeys,
+                        )
+                    }))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle.map(|handle| {
+                        handle.join().unwrap_or_else(|_| {
+                            Err(Error::Other(crate::UNREACHABLE_CODE.to_string()))
+                        })
+                    })
+                })
+                .collect()
+        });
+
+        // Encryption roots come before the datasets sharing their key
+        let mut results: Vec<Result<UnlockStatus>> = Vec::with_capacity(datasets.len());
+        for (resolved, key) in datasets.iter().zip(keys) {
+            let result = match key {
+                Some(key) => {
+                    let key_format = resolved.properties.key_format.unwrap_or_default();
+                    key.and_then(|key| self.unlock(resolved, &key, key_format))
+                }
+                None => inherited_key(&datasets, &results, resolved),
+            };
+            results.push(result);
+        }
+
+        if self.action == UnlockAction::Mount {
+            for i in mount_order(&datasets) {
+                let result = &mut results[i];
+                if let Ok(UnlockStatus::Unlocked { mount, .. }) = result {
+                    let dataset = &datasets[i].dataset;
+                    let (status, operation) = self.plan_mount(&datasets[i]);
+                    let mounted = match operation {
+                        Some(Operation::MountLegacy { .. }) => dataset.mount_legacy(),
+                        Some(_) => dataset.mount(),
+                        None => Ok(()),
+                    };
+                    match mounted {
+                        Ok(()) => *mount = Some(status),
+                        Err(error) => *result = Err(error),
+                    }
+                }
+            }
+        }
+
+        Ok(report(datasets, results))
+    }
+
+    /// Plans `run` without deriving keys or changing the pool.
+    ///
+    /// The datasets are resolved and the stored properties of the keys to derive are checked
+    /// as `run` would, and their second factor must be available (see `check_second_factor`).
+    /// Whether the password is right can't be known without deriving the keys, so they are
+    /// assumed to load. `UnlockAction::Print` changes nothing and has no dry run.
+    pub fn dry_run(&self) -> Result<DryRun> {
+        if self.action == UnlockAction::Print {
+            return Err(Error::InvalidInput(
+                "Printing keys has no dry run".to_string(),
+            ));
+        }
+        let datasets = self.resolve()?;
+        let yubikeys = self.fetch_yubikeys();
+
+        let mut operations = Vec::new();
+        let mut results: Vec<Result<UnlockStatus>> = Vec::with_capacity(datasets.len());
+        for resolved in &datasets {
+            if !self.derives_key(resolved) {
+                results.push(inherited_key(&datasets, &results, resolved));
+                continue;
+            }
+            let dataset = &resolved.dataset;
+            let result = check_stored_key(
+                dataset,
+                &resolved.properties,
+                self.second_factor.as_ref(),
+                yubikeys.as_deref(),
+            )
+            .and_then(|()| match resolved.encryption {
+                Encryption::Off => Err(Error::InvalidInput(format!(
+                    "Dataset {} is not encrypted",
+                    dataset
+                ))),
+                _ if resolved.encryption.key_loaded() => Ok(KeyStatus::AlreadyLoaded),
+                _ => {
+                    operations.push(Operation::LoadKey {
+                        dataset: dataset.name().to_string(),
+                    });
+                    Ok(KeyStatus::Loaded)
+                }
+            })
+            .map(|key| UnlockStatus::Unlocked { key, mount: None });
+            results.push(result);
+        }
+
+        if self.action == UnlockAction::Mount {
+            for i in mount_order(&datasets) {
+                if let Ok(UnlockStatus::Unlocked { mount, .. }) = &mut results[i] {
+                    let (status, operation) = self.plan_mount(&datasets[i]);
+                    operations.extend(operation);
+                    *mount = Some(status);
+                }
+            }
+        }
+
+        Ok(DryRun {
+            operations,
+            report: report(datasets, results),
+        })
+    }
+
+    /// Resolves the datasets, and their children if recursive, leaving out the ones without a
+    /// key to unlock.
+    fn resolve(&self) -> Result<Vec<ResolvedDataset>> {
+        // Resolve the children and read the properties of every dataset in one query
+        Ok(zfs::resolve_datasets(&self.datasets, self.recursive)?
+            .into_iter()
+            .filter(|resolved| {
+                self.datasets.contains(&resolved.dataset)
+                    || match resolved.encryption {
+                        Encryption::Off => false,
+                        Encryption::Root { .. } => true,
+                        Encryption::Inherited { .. } => self.action != UnlockAction::Print,
+                    }
+            })
+            .collect())
+    }
+
+    fn fetch_yubikeys(&self) -> Option<Arc<[Mutex<Device>]>> {
+        match &self.yubikeys {
+            Some(yubikeys) => Some(yubikeys.clone()),
+            None => crate::yubikey::fetch_yubikeys().ok(),
+        }
+    }
+
+    /// Returns true if the key of the dataset is derived, which only datasets sharing the key
+    /// of an encryption root skip (unless it is printed).
+    fn derives_key(&self, resolved: &ResolvedDataset) -> bool {
+        self.action == UnlockAction::Print
+            || !matches!(resolved.encryption, Encryption::Inherited { .. })
+    }
+
+    /// Prints or loads a derived key.
+    fn unlock(
+        &self,
+        resolved: &ResolvedDataset,
+        key: &str,
+        key_format: KeyFormat,
+    ) -> Result<UnlockStatus> {
+        if self.action == UnlockAction::Print {
+            return logic::printable_key(key, key_format).map(UnlockStatus::Printed);
+        }
+
+        let key_status = if resolved.encryption.key_loaded() {
+            KeyStatus::AlreadyLoaded
+        } else {
+            resolved.dataset.load_key(key, key_format)?;
+            KeyStatus::Loaded
+        };
+        Ok(UnlockStatus::Unlocked {
+            key: key_status,
+            mount: None,
+        })
+    }
+
+    /// Returns how an unlocked dataset ends up and the operation mounting it, if any.
+    fn plan_mount(&self, resolved: &ResolvedDataset) -> (MountStatus, Option<Operation>) {
+        if resolved.mounted {
+            return (MountStatus::AlreadyMounted, None);
+        }
+        let named = self.datasets.contains(&resolved.dataset);
+        let skip = match (resolved.canmount, &resolved.mountpoint) {
+            (CanMount::Off, _) => Some(SkipReason::CanMountOff),
+            (CanMount::NoAuto, _) if !named => Some(SkipReason::CanMountNoAuto),
+            (_, Mountpoint::None) => Some(SkipReason::NoMountpoint),
+            (_, Mountpoint::Legacy) if !self.mount_legacy => Some(SkipReason::Legacy),
+            _ => None,
+        };
+        if let Some(reason) = skip {
+            return (MountStatus::Skipped(reason), None);
+        }
+
+        let dataset = resolved.dataset.name().to_string();
+        let operation = if resolved.mountpoint == Mountpoint::Legacy {
+            Operation::MountLegacy { dataset }
+        } else {
+            Operation::Mount { dataset }
+        };
+        (MountStatus::Mounted, Some(operation))
+    }
+}
+
+/// Returns the status of a dataset sharing the key of its encryption root, given the results
+/// of the datasets before it.
+fn inherited_key(
+    datasets: &[ResolvedDataset],
+    results: &[Result<UnlockStatus>],
+    resolved: &ResolvedDataset,
+) -> Result<UnlockStatus> {
+    let Encryption::Inherited { root, key_loaded } = &resolved.encryption else {
+        return Err(Error::Other(crate::UNREACHABLE_CODE.to_string()));
+    };
+    let root_unlocked = match datasets.iter().position(|r| r.dataset.name() == root) {
+        Some(i) => results.get(i).is_some_and(Result::is_ok),
+        None => *key_loaded,
+    };
+    if !root_unlocked {
+        return Err(Error::Zfs(format!(
+            "{} shares the key of its encryption root {}, which is locked",
+            resolved.dataset, root
+        )));
+    }
+    Ok(UnlockStatus::Unlocked {
+        key: KeyStatus::Inherited(root.clone()),
+        mount: None,
+    })
+}
+
+/// Indices of the datasets in the order they are mounted: shallower mountpoints first, the
+/// order of the datasets breaking ties. Legacy datasets come last, as their mountpoint, set in
+/// fstab or by hand, may lie under any other.
+fn mount_order(datasets: &[ResolvedDataset]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..datasets.len()).collect();
+    order.sort_by_key(|&i| {
+        let mountpoint = &datasets[i].mountpoint;
+        (matches!(mountpoint, Mountpoint::Legacy), mountpoint.depth())
+    });
+    order
+}
+
+fn report<T>(datasets: Vec<ResolvedDataset>, results: Vec<Result<T>>) -> UnlockReport<T> {
+    let outcomes = datasets
+        .into_iter()
+        .zip(results)
+        .map(|(resolved, result)| DatasetOutcome {
+            dataset: resolved.dataset,
+            result,
+            second_factor: resolved.properties.second_factor,
+        })
+        .collect();
+    UnlockReport { outcomes }
+}
+
+/// Plans creating `datasets`, or changing the key of the existing ones, with `second_factor`
+/// like `Dataset::create_2fa`, without deriving keys or changing the pool.
+///
+/// The second factor must be available (see `check_second_factor`).
+pub fn dry_run_create(
+    datasets: &[Dataset],
+    second_factor: &TwoFactorMode,
+    key_format: KeyFormat,
+    kdf: &KdfParams,
+    key_check: bool,
+    yubikeys: Option<&[Mutex<Device>]>,
+) -> DryRun<()> {
+    let mut operations = Vec::new();
+    let outcomes = datasets
+        .iter()
+        .map(|dataset| {
+            let result = check_second_factor(second_factor, yubikeys)
+                .and_then(|mode| dataset.plan_create_2fa(mode, key_format, kdf, key_check))
+                .map(|planned| operations.extend(planned));
+            DatasetOutcome {
+                dataset: dataset.clone(),
+                result,
+                second_factor: None,
+            }
+        })
+        .collect();
+    DryRun {
+        operations,
+        report: UnlockReport { outcomes },
+    }
+}
+
+/// Plans `rekey` for `datasets` without deriving keys or changing the pool.
+///
+/// `key_check` is handled like by `rekey`. The stored properties must allow deriving the current key, and both the stored and the
+/// new second factor must be available (see `check_second_factor`).
+pub fn dry_run_rekey(
+    datasets: &[Dataset],
+    second_factor: Option<&TwoFactorMode>,
+    key_format: Option<KeyFormat>,
+    key_check: Option<bool>,
+    yubikeys: Option<&[Mutex<Device>]>,
+) -> DryRun<()> {
+    let mut operations = Vec::new();
+    let outcomes = datasets
+        .iter()
+        .map(|dataset| {
+            let result = dataset
+                .get_shavee_properties()
+                .and_then(|properties| {
+                    check_stored_key(dataset, &properties, None, yubikeys)?;
+                    let second_factor = match second_factor {
+                        Some(second_factor) => second_factor.clone(),
+                        None => stored_second_factor(dataset, &properties, yubikeys)?,
+                    };
+                    dataset.plan_rekey(
+                        check_second_factor(&second_factor, yubikeys)?,
+                        key_format.unwrap_or(properties.key_format.unwrap_or_default()),
+                        &properties.kdf_params(),
+                        key_check.unwrap_or(properties.key_check.is_some()),
+                    )
+                })
+                .map(|planned| operations.extend(planned));
+            DatasetOutcome {
+                dataset: dataset.clone(),
+                result,
+                second_factor: None,
+            }
+        })
+        .collect();
+    DryRun {
+        operations,
+        report: UnlockReport { outcomes },
+    }
+}
+
+/// Unmounts datasets and unloads their keys, with `recursive` including all their children.
+///
+/// Datasets are unmounted in the reverse of the order they are mounted in, deepest mountpoint
+/// first, with `force` even if their mountpoint is busy. Keys are then unloaded from the
+/// encryption roots among the datasets; a dataset sharing the key of an encryption root
+/// outside of them stays unlocked. A dataset that fails to unmount keeps its key loaded.
+pub fn lock(datasets: &[Dataset], recursive: bool, force: bool) -> Result<LockReport> {
+    let mut outcomes = Vec::new();
+    for dataset in datasets {
+        outcomes.extend(lock_dataset(dataset, recursive, force)?);
+    }
+    Ok(UnlockReport { outcomes })
+}
+
+fn lock_dataset(
+    root: &Dataset,
+    recursive: bool,
+    force: bool,
+) -> Result<Vec<DatasetOutcome<LockStatus>>> {
+    let entries = root.backend().get_properties(
+        &[root.name()],
+        &["mounted", "mountpoint", "keystatus", "encryptionroot"],
+        recursive,
+    )?;
+    let datasets = entries
+        .iter()
+        .map(|(name, _)| Dataset::with_backend(name.clone(), root.backend().clone()))
+        .collect::<Result<Vec<_>>>()?;
+    let mut results: Vec<Result<LockStatus>> =
+        entries.iter().map(|_| Ok(LockStatus::default())).collect();
+
+    // The reverse of `mount_order`, so no mountpoint is busy because of one mounted below it
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    let mountpoints: Vec<Mountpoint> = entries
+        .iter()
+        .map(|(_, values)| Mountpoint::from_value(values.get("mountpoint").map(String::as_str)))
+        .collect();
+    order.sort_by_key(|&i| {
+        let mountpoint = &mountpoints[i];
+        std::cmp::Reverse((matches!(mountpoint, Mountpoint::Legacy), mountpoint.depth()))
+    });
+
+    for &i in &order {
+        if entries[i].1.get("mounted").map(String::as_str) != Some("yes") {
+            continue;
+        }
+        // `zfs unmount` refuses legacy datasets, they were mounted through mount(8)
+        let unmounted = match (&mountpoints[i], force) {
+            (Mountpoint::Legacy, force) => datasets[i].unmount_legacy(force),
+            (_, true) => datasets[i].force_unmount(),
+            (_, false) => datasets[i].unmount(),
+        };
+        match unmounted {
+            Ok(()) => {
+                if let Ok(status) = &mut results[i] {
+                    status.unmounted = true;
+                }
+            }
+            Err(error) => results[i] = Err(error),
+        }
+    }
+
+    for &i in &order {
+        let (name, values) = &entries[i];
+        let encryption_root = values.get("encryptionroot").unwrap_or(name);
+        if results[i].is_err()
+            || encryption_root != name
+            || values.get("keystatus").map(String::as_str) != Some("available")
+        {
+            continue;
+        }
+        if let Err(error) = datasets[i].unload_key(false) {
+            results[i] = Err(error);
+            continue;
+        }
+        // The datasets inheriting the key are locked along with their encryption root
+        for (j, (_, values)) in entries.iter().enumerate() {
+            if (j == i || values.get("encryptionroot") == Some(name))
+                && let Ok(status) = &mut results[j]
+            {
+                status.key_unloaded = true;
+            }
+        }
+    }
+
+    Ok(datasets
+        .into_iter()
+        .zip(results)
+        .map(|(dataset, result)| DatasetOutcome {
+            dataset,
+            result,
+            second_factor: None,
+        })
+        .collect())
+}
+
+/// Derives the key for `second_factor` from the password of `session` and `salt` with the
+/// derivation `scheme` and the Argon2 parameters `kdf`.
+///
+/// A Yubikey is picked from `yubikeys` by its serial, or the first one is used if no serial is
+/// given. Returns the key and the second factor to store, which records the serial of the
+/// Yubikey that was used.
+pub fn derive_key(
+    session: &KdfSession,
+    second_factor: &TwoFactorMode,
+    salt: &[u8],
+    scheme: KdfScheme,
+    kdf: &KdfParams,
+    yubikeys: Option<&[Mutex<Device>]>,
+) -> Result<(Zeroizing<String>, TwoFactorMode)> {
+    match second_factor {
+        #[cfg(feature = "yubikey")]
+        TwoFactorMode::Yubikey { yslot, serial } => {
+            let (yubikey, serial) = select_yubikey(yubikeys, *serial)?;
+            let key = scheme.derive(session, second_factor, Some(yubikey), salt, kdf)?;
+            Ok((
+                key,
+                TwoFactorMode::Yubikey {
+                    yslot: *yslot,
+                    serial,
+                },
+            ))
+        }
+        _ => Ok((
+            scheme.derive(session, second_factor, None, salt, kdf)?,
+            second_factor.clone(),
+        )),
+    }
+}
+
+/// Checks that `second_factor` can be used, without deriving a key: its Yubikey is connected
+/// or its local key file can be opened. Remote key files are not fetched.
+///
+/// Returns the second factor to store, like `derive_key`.
+pub fn check_second_factor(
+    second_factor: &TwoFactorMode,
+    #[allow(unused_variables)] yubikeys: Option<&[Mutex<Device>]>,
+) -> Result<TwoFactorMode> {
+    match second_factor {
+        #[cfg(feature = "yubikey")]
+        TwoFactorMode::Yubikey { yslot, serial } => {
+            let (_, serial) = select_yubikey(yubikeys, *serial)?;
+            Ok(TwoFactorMode::Yubikey {
+                yslot: *yslot,
+                serial,
+            })
+        }
+        #[cfg(feature = "file")]
+        TwoFactorMode::File { file, .. } => {
+            crate::filehash::check_file(file)?;
+            Ok(second_factor.clone())
+        }
+        _ => Ok(second_factor.clone()),
+    }
+}
+
+/// Picks a Yubikey by its serial, or the first one if no serial is given, and returns it
+/// with its serial.
+#[cfg(feature = "yubikey")]
+fn select_yubikey(
+    yubikeys: Option<&[Mutex<Device>]>,
+    serial: Option<u32>,
+) -> Result<(&Mutex<Device>, Option<u32>)> {
+    let yubikeys = yubikeys.unwrap_or_default();
+    let yubikey = match serial {
+        Some(serial) => crate::yubikey::yubikey_get_from_serial(yubikeys, serial)?,
+        None => yubikeys
+            .first()
+            .ok_or_else(|| Error::Yubikey("Device Not Found".to_string()))?,
+    };
+    let serial = yubikey
+        .lock()
+        .map_err(|_| Error::Yubikey("Failed to lock Yubikey device".to_string()))?
+        .serial;
+    Ok((yubikey, serial))
+}
+
+/// Derives the current key of `dataset` from its stored Shavee properties and the password of
+/// `session`.
+///
+/// `second_factor` overrides the stored second factor. If the dataset has a key check value
+/// the key is verified against it, so a wrong password or second factor is reported as
+/// `Error::IncorrectKey` without touching the pool.
+pub fn stored_key(
+    dataset: &Dataset,
+    properties: &ShaveeProperties,
+    session: &KdfSession,
+    second_factor: Option<&TwoFactorMode>,
+    yubikeys: Option<&[Mutex<Device>]>,
+) -> Result<Zeroizing<String>> {
+    let salt = logic::salt_from_property(properties.salt.as_deref())?;
+    let second_factor = match second_factor {
+        Some(second_factor) => second_factor.clone(),
+        None => stored_second_factor(dataset, properties, yubikeys)?,
+    };
+    let (key, _) = derive_key(
+        session,
+        &second_factor,
+        &salt,
+        properties.kdf_scheme()?,
+        &properties.kdf_params(),
+        yubikeys,
+    )?;
+    if let (Some(salt), Some(key_check)) = (&properties.salt, &properties.key_check) {
+        logic::verify_key_check(&key, salt, key_check)?;
+    }
+    Ok(key)
+}
+
+/// Checks that the key of `dataset` could be derived by `stored_key`: its stored properties
+/// are valid and its second factor is available (see `check_second_factor`).
+fn check_stored_key(
+    dataset: &Dataset,
+    properties: &ShaveeProperties,
+    second_factor: Option<&TwoFactorMode>,
+    yubikeys: Option<&[Mutex<Device>]>,
+) -> Result<()> {
+    logic::salt_from_property(properties.salt.as_deref())?;
+    properties.kdf_scheme()?;
+    let second_factor = match second_factor {
+        Some(second_factor) => second_factor.clone(),
+        None => stored_second_factor(dataset, properties, yubikeys)?,
+    };
+    check_second_factor(&second_factor, yubikeys)?;
+    Ok(())
+}
+
+/// Re-wraps `dataset` with a key derived from `new_password` and a fresh salt, after deriving
+/// its current key from the stored properties and `old_password`.
+///
+/// `second_factor` and `key_format` default to the ones stored on the dataset, and the stored
+/// Argon2 parameters are kept. A key check value is stored with `key_check`, by default only if
+/// the dataset already has one. The new key is derived with `KdfScheme::CURRENT`, which moves
+/// the dataset to the current scheme. The current key is verified before anything is changed
+/// (see `Dataset::rekey`).
+pub fn rekey(
+    dataset: &Dataset,
+    old_password: &[u8],
+    new_password: &[u8],
+    second_factor: Option<&TwoFactorMode>,
+    key_format: Option<KeyFormat>,
+    key_check: Option<bool>,
+    yubikeys: Option<&[Mutex<Device>]>,
+) -> Result<()> {
+    let properties = dataset.get_shavee_properties()?;
+    let old_format = properties.key_format.unwrap_or_default();
+    let second_factor = match second_factor {
+        Some(second_factor) => second_factor.clone(),
+        None => stored_second_factor(dataset, &properties, yubikeys)?,
+    };
+    let old_session = KdfSession::new(old_password, 1);
+    let old_key = stored_key(dataset, &properties, &old_session, None, yubikeys)?;
+
+    let salt = logic::generate_salt();
+    let kdf = properties.kdf_params();
+    let (new_key, second_factor) = derive_key(
+        &KdfSession::new(new_password, 1),
+        &second_factor,
+        &salt,
+        KdfScheme::CURRENT,
+        &kdf,
+        yubikeys,
+    )?;
+
+    dataset.rekey(
+        &old_key,
+        &new_key,
+        second_factor,
+        &logic::BASE64_ENGINE.encode(salt),
+        key_format.unwrap_or(old_format),
+        &kdf,
+        key_check.unwrap_or(properties.key_check.is_some()),
+    )
+}
+
+/// Returns the second factor stored on `dataset`.
+///
+/// A stored Yubikey serial that is not connected falls back to the first Yubikey, so backup
+/// keys programmed with the same secret keep working.
+fn stored_second_factor(
+    dataset: &Dataset,
+    properties: &ShaveeProperties,
+    #[allow(unused_variables)] yubikeys: Option<&[Mutex<Device>]>,
+) -> Result<TwoFactorMode> {
+    #[allow(unused_mut)]
+    let mut second_factor = properties.two_factor_mode(dataset.name())?;
+    #[cfg(feature = "yubikey")]
+    if let TwoFactorMode::Yubikey { serial, .. } = &mut second_factor
+        && serial.is_some_and(|serial| {
+            crate::yubikey::yubikey_get_from_serial(yubikeys.unwrap_or_default(), serial).is_err()
+        })
+    {
+        *serial = None;
+    }
+    Ok(second_factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zfs::{InMemoryBackend, ZfsBackend, ZfsShaveeProperties};
+    use std::sync::Arc;
+
+    fn memory_dataset(backend: &Arc<InMemoryBackend>, name: &str) -> Dataset {
+        Dataset::with_backend(name.to_string(), backend.clone()).unwrap()
+    }
+
+    /// Creates a Shavee password dataset `name` protected by `password`, locked and unmounted.
+    fn create_locked(
+        backend: &Arc<InMemoryBackend>,
+        name: &str,
+        password: &[u8],
+        format: KeyFormat,
+    ) -> Dataset {
+        let ds = memory_dataset(backend, name);
+        let salt = logic::generate_salt();
+        // Small Argon2 parameters keep the tests fast, unlocking has to read them back
+        let kdf = KdfParams::new(1024, 1, 1).unwrap();
+        let (key, mode) = derive_key(
+            &KdfSession::new(password, 1),
+            &TwoFactorMode::Password,
+            &salt,
+            KdfScheme::CURRENT,
+            &kdf,
+            None,
+        )
+        .unwrap();
+        ds.create_2fa(
+            &key,
+            mode,
+            &logic::BASE64_ENGINE.encode(&salt),
+            format,
+            &kdf,
+            false,
+        )
+        .unwrap();
+        ds.unmount().unwrap();
+        ds.unload_key(false).unwrap();
+        ds
+    }
+
+    #[test]
+    fn test_unlocker_mount() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let ds = create_locked(&backend, "pool/home", b"hunter2", KeyFormat::Passphrase);
+        let datasets = std::slice::from_ref(&ds);
+
+        let report = Unlocker::new(datasets, b"wrong").run().unwrap();
+        assert!(!report.is_success());
+        assert!(matches!(report.into_result(), Err(Error::IncorrectKey(_))));
+        assert!(!backend.dataset("pool/home").unwrap().key_loaded);
+
+        let report = Unlocker::new(datasets, b"hunter2").run().unwrap();
+        assert_eq!(
+            report.outcomes[0].result.as_ref().unwrap(),
+            &UnlockStatus::Unlocked {
+                key: KeyStatus::Loaded,
+                mount: Some(MountStatus::Mounted),
+            }
+        );
+        let unlocked = backend.dataset("pool/home").unwrap();
+        assert!(unlocked.key_loaded);
+        assert!(unlocked.mounted);
+
+        let report = Unlocker::new(datasets, b"hunter2").run().unwrap();
+        assert_eq!(
+            report.outcomes[0].result.as_ref().unwrap().to_string(),
+            "key already loaded, already mounted"
+        );
+    }
+
+    #[test]
+    fn test_lock() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let home = create_locked(&backend, "pool/home", b"hunter2", KeyFormat::Passphrase);
+        create_locked(&backend, "pool/home/user", b"hunter2", KeyFormat::Hex);
+        // Outside of its parent, so a child failing to unmount doesn't keep the parent busy
+        backend
+            .set_property("pool/home/user", "mountpoint", "/srv/user")
+            .unwrap();
+        let datasets = std::slice::from_ref(&home);
+        Unlocker::new(datasets, b"hunter2")
+            .recursive(true)
+            .run()
+            .and_then(UnlockReport::into_result)
+            .unwrap();
+
+        // A busy child keeps its key without force
+        backend.set_busy("pool/home/user", true);
+        let report = lock(datasets, true, false).unwrap();
+        let names: Vec<&str> = report.outcomes.iter().map(|o| o.dataset.name()).collect();
+        assert_eq!(names, ["pool/home", "pool/home/user"]);
+        assert_eq!(
+            report.outcomes[0].result.as_ref().unwrap().to_string(),
+            "unmounted, key unloaded"
+        );
+        assert!(matches!(report.outcomes[1].result, Err(Error::Zfs(_))));
+        let user = backend.dataset("pool/home/user").unwrap();
+        assert!(user.mounted && user.key_loaded);
+
+        let report = lock(datasets, true, true).unwrap();
+        assert!(report.is_success());
+        assert_eq!(
+            report.outcomes[0].result.as_ref().unwrap(),
+            &LockStatus::default()
+        );
+        assert_eq!(
+            report.outcomes[1].result.as_ref().unwrap(),
+            &LockStatus {
+                unmounted: true,
+                key_unloaded: true,
+            }
+        );
+        let user = backend.dataset("pool/home/user").unwrap();
+        assert!(!user.mounted && !user.key_loaded);
+        assert!(backend.dataset("pool").unwrap().mounted);
+    }
+
+    #[test]
+    fn test_lock_mountpoint_order() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let home = create_locked(&backend, "pool/home", b"hunter2", KeyFormat::Passphrase);
+        backend.add_inheriting_dataset("pool/home/a");
+        // Mountpoints needn't follow the dataset hierarchy
+        backend
+            .set_property("pool/home", "mountpoint", "/home/alice")
+            .unwrap();
+        backend
+            .set_property("pool/home/a", "mountpoint", "/home")
+            .unwrap();
+        let datasets = std::slice::from_ref(&home);
+        Unlocker::new(datasets, b"hunter2")
+            .recursive(true)
+            .run()
+            .and_then(UnlockReport::into_result)
+            .unwrap();
+
+        let report = lock(datasets, true, false).unwrap();
+        assert!(report.is_success());
+        for name in ["pool/home", "pool/home/a"] {
+            let state = backend.dataset(name).unwrap();
+            assert!(!state.mounted && !state.key_loaded, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_lock_legacy_mountpoint() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let home = create_locked(&backend, "pool/home", b"hunter2", KeyFormat::Passphrase);
+        backend
+            .set_property("pool/home", "mountpoint", "legacy")
+            .unwrap();
+        let datasets = std::slice::from_ref(&home);
+        Unlocker::new(datasets, b"hunter2")
+            .mount_legacy(true)
+            .run()
+            .and_then(UnlockReport::into_result)
+            .unwrap();
+        assert!(backend.dataset("pool/home").unwrap().mounted);
+        // Like ZFS, `zfs unmount` refuses a legacy dataset
+        assert!(home.unmount().is_err());
+
+        let report = lock(datasets, false, false).unwrap();
+        assert!(report.is_success());
+        assert!(report.outcomes[0].result.as_ref().unwrap().unmounted);
+        let state = backend.dataset("pool/home").unwrap();
+        assert!(!state.mounted && !state.key_loaded);
+    }
+
+    #[test]
+    fn test_unlocker_reports_each_dataset() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        create_locked(&backend, "pool/a", b"hunter2", KeyFormat::Hex);
+        create_locked(&backend, "pool/b", b"other", KeyFormat::Passphrase);
+        let pool = memory_dataset(&backend, "pool");
+
+        let report = Unlocker::new(&[pool], b"hunter2")
+            .recursive(true)
+            .action(UnlockAction::Load)
+            .run()
+            .unwrap();
+        let names: Vec<&str> = report.outcomes.iter().map(|o| o.dataset.name()).collect();
+        assert_eq!(names, ["pool", "pool/a", "pool/b"]);
+        let failed: Vec<&str> = report.failures().map(|o| o.dataset.name()).collect();
+        // "pool" has no Shavee properties and "pool/b" uses another password
+        assert_eq!(failed, ["pool", "pool/b"]);
+        assert!(backend.dataset("pool/a").unwrap().key_loaded);
+        assert!(!backend.dataset("pool/a").unwrap().mounted);
+    }
+
+    #[test]
+    fn test_unlocker_encryption_roots() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        backend.add_dataset("pool/data");
+        create_locked(&backend, "pool/home", b"hunter2", KeyFormat::Passphrase);
+        backend.add_inheriting_dataset("pool/home/a");
+        backend.add_inheriting_dataset("pool/home/a/b");
+        let pool = [memory_dataset(&backend, "pool")];
+        let names = |report: &UnlockReport| -> Vec<String> {
+            report
+                .outcomes
+                .iter()
+                .map(|o| o.dataset.name().to_string())
+                .collect()
+        };
+
+        // Unencrypted children are skipped, the children of a locked root can't be mounted
+        let report = Unlocker::new(&pool, b"wrong")
+            .recursive(true)
+            .run()
+            .unwrap();
+        assert_eq!(
+            names(&report),
+            ["pool", "pool/home", "pool/home/a", "pool/home/a/b"]
+        );
+        assert!(matches!(
+            report.outcomes[1].result,
+            Err(Error::IncorrectKey(_))
+        ));
+        assert!(matches!(report.outcomes[2].result, Err(Error::Zfs(_))));
+
+        // Only the key of the encryption root is derived and loaded
+        let report = Unlocker::new(&pool, b"hunter2")
+            .recursive(true)
+            .run()
+            .unwrap();
+        let statuses: Vec<String> = report.outcomes[1..]
+            .iter()
+            .map(|o| o.result.as_ref().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                "key loaded, mounted",
+                "key inherited from pool/home, mounted",
+                "key inherited from pool/home, mounted",
+            ]
+        );
+        assert!(backend.dataset("pool/home/a/b").unwrap().mounted);
+
+        // Datasets sharing a key have none of their own to print
+        let report = Unlocker::new(&pool, b"hunter2")
+            .recursive(true)
+            .action(UnlockAction::Print)
+            .run()
+            .unwrap();
+        assert_eq!(names(&report), ["pool", "pool/home"]);
+        assert!(report.outcomes[1].result.is_ok());
+    }
+
+    #[test]
+    fn test_unlocker_mount_properties() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let home = create_locked(&backend, "pool/home", b"hunter2", KeyFormat::Passphrase);
+        for (name, property, value) in [
+            ("pool/home/off", "canmount", "off"),
+            ("pool/home/noauto", "canmount", "noauto"),
+            ("pool/home/legacy", "mountpoint", "legacy"),
+            ("pool/home/none", "mountpoint", "none"),
+        ] {
+            backend.add_inheriting_dataset(name);
+            backend.set_property(name, property, value).unwrap();
+        }
+        // Mountpoints needn't follow the dataset hierarchy
+        backend
+            .set_property("pool/home", "mountpoint", "/home/alice")
+            .unwrap();
+        backend.add_inheriting_dataset("pool/home/root");
+        backend
+            .set_property("pool/home/root", "mountpoint", "/home")
+            .unwrap();
+
+        let unlocker = Unlocker::new(std::slice::from_ref(&home), b"hunter2").recursive(true);
+        let report = unlocker.run().unwrap();
+        assert!(report.is_success());
+        let statuses: Vec<String> = report
+            .outcomes
+            .iter()
+            .map(|o| format!("{}: {}", o.dataset, o.result.as_ref().unwrap()))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                "pool/home: key loaded, mounted",
+                "pool/home/legacy: key inherited from pool/home, not mounted (mountpoint=legacy)",
+                "pool/home/noauto: key inherited from pool/home, not mounted (canmount=noauto)",
+                "pool/home/none: key inherited from pool/home, not mounted (mountpoint=none)",
+                "pool/home/off: key inherited from pool/home, not mounted (canmount=off)",
+                "pool/home/root: key inherited from pool/home, mounted",
+            ]
+        );
+
+        // Legacy mountpoints are mounted on request, children with canmount=noauto when named
+        let noauto = memory_dataset(&backend, "pool/home/noauto");
+        assert!(unlocker.mount_legacy(true).run().unwrap().is_success());
+        let report = Unlocker::new(&[noauto], b"hunter2").run().unwrap();
+        assert_eq!(
+            report.outcomes[0].result.as_ref().unwrap().to_string(),
+            "key inherited from pool/home, mounted"
+        );
+        assert!(backend.dataset("pool/home/legacy").unwrap().mounted);
+        assert!(!backend.dataset("pool/home/off").unwrap().mounted);
+    }
+
+    #[test]
+    fn test_unlocker_print() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let ds = create_locked(&backend, "pool/home", b"hunter2", KeyFormat::Hex);
+
+        let report = Unlocker::new(std::slice::from_ref(&ds), b"hunter2")
+            .action(UnlockAction::Print)
+            .run()
+            .unwrap();
+        let Ok(UnlockStatus::Printed(printed)) = &report.outcomes[0].result else {
+            panic!(
+                "expected a printed key, got {:?}",
+                report.outcomes[0].result
+            );
+        };
+        // Hex keys are printed as stored in ZFS, and printing must not unlock the dataset
+        assert_eq!(
+            Some(printed.as_bytes()),
+            backend.dataset("pool/home").unwrap().key.as_deref()
+        );
+        assert!(!backend.dataset("pool/home").unwrap().key_loaded);
+    }
+
+    #[test]
+    fn test_unlocker_key_check() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let ds = create_locked(&backend, "pool/home", b"hunter2", KeyFormat::Passphrase);
+        let key_check = crate::zfs::ZfsShaveeProperties::KeyCheck.to_string();
+        // Only stored on request, then kept by a password change
+        assert!(ds.get_shavee_properties().unwrap().key_check.is_none());
+        rekey(&ds, b"hunter2", b"hunter2", None, None, Some(true), None).unwrap();
+        rekey(&ds, b"hunter2", b"hunter2", None, None, None, None).unwrap();
+        assert!(ds.get_shavee_properties().unwrap().key_check.is_some());
+        ds.unload_key(false).unwrap();
+
+        // A wrong password is caught by the key check even when only printing
+        let print = Unlocker::new(std::slice::from_ref(&ds), b"wrong").action(UnlockAction::Print);
+        let report = print.run().unwrap();
+        assert!(matches!(report.into_result(), Err(Error::IncorrectKey(_))));
+
+        // Datasets without a key check can only be verified by ZFS
+        backend.inherit_property("pool/home", &key_check).unwrap();
+        assert!(print.run().unwrap().is_success());
+    }
+
+    #[test]
+    fn test_unlocker_kdf_scheme() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let ds = create_locked(&backend, "pool/home", b"hunter2", KeyFormat::Passphrase);
+        let kdf = ZfsShaveeProperties::Kdf.to_string();
+        assert_eq!(
+            backend.get_property("pool/home", &kdf).unwrap(),
+            Some(KdfScheme::CURRENT.to_string())
+        );
+        let print =
+            Unlocker::new(std::slice::from_ref(&ds), b"hunter2").action(UnlockAction::Print);
+
+        // Datasets created before the scheme was stored use the legacy one
+        backend.inherit_property("pool/home", &kdf).unwrap();
+        assert!(print.run().unwrap().is_success());
+
+        backend
+            .set_properties("pool/home", &[(kdf, "argon2id-v99".to_string())])
+            .unwrap();
+        let report = print.run().unwrap();
+        assert!(matches!(report.into_result(), Err(Error::Properties(_))));
+    }
+
+    fn operations<T>(dry_run: &DryRun<T>) -> Vec<String> {
+        dry_run.operations.iter().map(|o| o.to_string()).collect()
+    }
+
+    #[test]
+    fn test_unlocker_dry_run() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let home = create_locked(&backend, "pool/home", b"hunter2", KeyFormat::Passphrase);
+        backend.add_inheriting_dataset("pool/home/a");
+        backend
+            .set_property("pool/home/a", "mountpoint", "legacy")
+            .unwrap();
+        let unlocker = Unlocker::new(std::slice::from_ref(&home), b"")
+            .recursive(true)
+            .mount_legacy(true);
+
+        let dry_run = unlocker.dry_run().unwrap();
+        assert!(dry_run.report.is_success());
+        assert_eq!(
+            operations(&dry_run),
+            [
+                "zfs load-key pool/home",
+                "zfs mount pool/home",
+                "mount pool/home/a"
+            ]
+        );
+        assert_eq!(
+            dry_run.report.outcomes[1]
+                .result
+                .as_ref()
+                .unwrap()
+                .to_string(),
+            "key inherited from pool/home, mounted"
+        );
+        // Nothing is derived or changed
+        let state = backend.dataset("pool/home").unwrap();
+        assert!(!state.key_loaded && !state.mounted);
+
+        assert!(matches!(
+            unlocker.action(UnlockAction::Print).dry_run(),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+
+    #[cfg(feature = "file")]
+    #[test]
+    fn test_unlocker_dry_run_checks_second_factor() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let home = create_locked(&backend, "pool/home", b"hunter2", KeyFormat::Passphrase);
+        let missing = TwoFactorMode::File {
+            file: "/non/existent/keyfile".to_string(),
+            port: None,
+            size: None,
+        };
+
+        let dry_run = Unlocker::new(std::slice::from_ref(&home), b"")
+            .second_factor(Some(missing))
+            .dry_run()
+            .unwrap();
+        assert!(matches!(dry_run.report.into_result(), Err(Error::Io(_))));
+        assert!(dry_run.operations.is_empty());
+    }
+
+    #[test]
+    fn test_dry_run_create() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        memory_dataset(&backend, "pool/existing")
+            .create("secret")
+            .unwrap();
+        let locked = create_locked(&backend, "pool/locked", b"hunter2", KeyFormat::Passphrase);
+        let datasets = [
+            memory_dataset(&backend, "pool/new"),
+            memory_dataset(&backend, "pool/existing"),
+            locked,
+            memory_dataset(&backend, "missing/new"),
+        ];
+        let kdf = KdfParams::new(1024, 1, 1).unwrap();
+
+        let dry_run = dry_run_create(
+            &datasets,
+            &TwoFactorMode::Password,
+            KeyFormat::Hex,
+            &kdf,
+            false,
+            None,
+        );
+        let operations = operations(&dry_run);
+        let salt = format!("{}={}", ZfsShaveeProperties::Salt, crate::zfs::PLANNED_SALT);
+        assert_eq!(operations.len(), 3);
+        assert!(
+            operations[0].starts_with(
+                "zfs create -o encryption=on -o keyformat=hex -o keylocation=prompt -o "
+            )
+        );
+        assert!(operations[0].contains(&salt) && operations[0].ends_with(" pool/new"));
+        assert!(operations[1].starts_with("zfs set ") && operations[1].contains(&salt));
+        assert_eq!(
+            operations[2],
+            "zfs change-key -o keylocation=prompt -o keyformat=hex pool/existing"
+        );
+
+        // A locked dataset can't change its key and a dataset can't be created without parent
+        let failed: Vec<&str> = dry_run
+            .report
+            .failures()
+            .map(|o| o.dataset.name())
+            .collect();
+        assert_eq!(failed, ["pool/locked", "missing/new"]);
+        assert!(!backend.exists("pool/new").unwrap());
+    }
+
+    #[test]
+    fn test_dry_run_rekey() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        backend.add_dataset("pool/plain");
+        let home = create_locked(&backend, "pool/home", b"hunter2", KeyFormat::Passphrase);
+        let port = ZfsShaveeProperties::FilePort.to_string();
+        backend
+            .set_properties("pool/home", &[(port.clone(), "443".to_string())])
+            .unwrap();
+
+        let datasets = [home, memory_dataset(&backend, "pool/plain")];
+        let dry_run = dry_run_rekey(&datasets, None, Some(KeyFormat::Hex), None, None);
+        let operations = operations(&dry_run);
+        assert_eq!(operations[0], "zfs load-key pool/home");
+        // The stale file port of the old configuration is cleared along with the new values
+        assert!(operations[1].starts_with("zfs set "));
+        assert!(operations[1].ends_with(&format!(" {}=- pool/home", port)));
+        assert_eq!(
+            operations[2],
+            "zfs change-key -o keylocation=prompt -o keyformat=hex pool/home"
+        );
+        assert_eq!(operations[3], format!("zfs inherit {} pool/home", port));
+        assert!(matches!(
+            dry_run.report.outcomes[1].result,
+            Err(Error::Properties(_))
+        ));
+        assert!(!backend.dataset("pool/home").unwrap().key_loaded);
+    }
+
+    #[cfg(feature = "yubikey")]
+    #[test]
+    fn test_derive_key_without_yubikey() {
+        let mode = TwoFactorMode::Yubikey {
+            yslot: Some(2),
+            serial: None,
+        };
+        let result = derive_key(
+            &KdfSession::new(b"hunter2", 1),
+            &mode,
+            b"salt",
+            KdfScheme::CURRENT,
+            &KdfParams::default(),
+            None,
+        );
+        assert!(matches!(result, Err(Error::Yubikey(_))));
+    }
+}
@@ -1,7 +1,10 @@
 //! Yubikey HMAC-SHA1 challenge-response utilities.
 
+use crate::password::KdfParams;
+use crate::secret::Zeroizing;
 use crate::{Error, Result};
 use challenge_response::config::{Config, Mode, Slot};
+use challenge_response::hmacmode::Hmac;
 use challenge_response::{ChallengeResponse, Device};
 use std::sync::{Arc, Mutex};
 
@@ -11,45 +14,55 @@ use std::sync::{Arc, Mutex};
 /// * `password` - The user password to be hashed and used as a challenge.
 /// * `slot` - The Yubikey slot to use (1 or 2). Defaults to 2 if not specified.
 /// * `salt` - The salt for hashing the password before sending it as a challenge.
+/// * `kdf` - The Argon2 cost parameters.
 /// * `yubikey` - A Mutex-protected Yubikey device.
 ///
 /// # Returns
-/// A `Result` containing the final derived key as a `Vec<u8>`.
+/// A `Result` containing the final derived key, zeroized when dropped.
 pub fn yubikey_get_hash(
     password: &[u8],
     slot: Option<u8>,
     salt: &[u8],
+    kdf: &KdfParams,
     yubikey: &Mutex<Device>,
-) -> Result<Vec<u8>> {
+) -> Result<Zeroizing<Vec<u8>>> {
+    // Prepare the challenge by hashing the password
+    let challenge = crate::password::hash_argon2(password, salt, kdf)?;
+
+    let hmac_result = challenge_response(&challenge, slot, yubikey)?;
+
+    // The HMAC result is used as input for a final Argon2 hash to derive the encryption key
+    crate::password::hash_argon2(&hmac_result, salt, kdf)
+}
+
+/// Sends `challenge` to the HMAC-SHA1 slot of a Yubikey (2 if not specified).
+///
+/// The response wipes itself when dropped.
+pub fn challenge_response(
+    challenge: &[u8],
+    slot: Option<u8>,
+    yubikey: &Mutex<Device>,
+) -> Result<Hmac> {
     let mut yubi_service = ChallengeResponse::new()
         .map_err(|e| Error::Yubikey(format!("Failed to initialize Yubikey service: {}", e)))?;
 
-    // Prepare the challenge by hashing the password
-    let challenge = crate::password::hash_argon2(password, salt)?;
-
     let yslot = match slot {
         Some(1) => Slot::Slot1,
         _ => Slot::Slot2,
     };
 
-    let hmac_result = {
-        let key_handle = yubikey
-            .lock()
-            .map_err(|_| Error::Yubikey("Failed to lock Yubikey device".to_string()))?;
+    let key_handle = yubikey
+        .lock()
+        .map_err(|_| Error::Yubikey("Failed to lock Yubikey device".to_string()))?;
 
-        let config = Config::new_from(key_handle.clone())
-            .set_variable_size(false)
-            .set_mode(Mode::Sha1)
-            .set_slot(yslot);
+    let config = Config::new_from(key_handle.clone())
+        .set_variable_size(false)
+        .set_mode(Mode::Sha1)
+        .set_slot(yslot);
 
-        yubi_service
-            .challenge_response_hmac(&challenge, config)
-            .map_err(|e| Error::Yubikey(format!("HMAC challenge failed: {}", e)))?
-    };
-
-    // The HMAC result is used as input for a final Argon2 hash to derive the encryption key
-    let final_hash = crate::password::hash_argon2(&hmac_result.0, salt)?;
-    Ok(final_hash)
+    yubi_service
+        .challenge_response_hmac(challenge, config)
+        .map_err(|e| Error::Yubikey(format!("HMAC challenge failed: {}", e)))
 }
 
 /// Retrieves a Yubikey device from a list based on its serial number.
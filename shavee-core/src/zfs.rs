@@ -2,20 +2,39 @@
 //!
 //! This module provides tools to interact with ZFS datasets, including setting/getting properties,
 //! mounting/unmounting, and creating new datasets with encryption.
-//! It executes ZFS commands via `std::process::Command`.
+//! The actual ZFS operations are delegated to a `ZfsBackend`; by default `CliBackend`, which
+//! executes ZFS commands via `std::process::Command`. With the `libzfs` feature, libzfs is
+//! used directly when it can be loaded. An in-memory backend is available for tests with the
+//! `testing` feature.
 
+mod backend;
+#[cfg(feature = "libzfs")]
+mod libzfs;
+#[cfg(any(test, feature = "testing"))]
+mod memory;
+
+pub use backend::{CliBackend, ZfsBackend};
+#[cfg(feature = "libzfs")]
+pub use libzfs::LibZfsBackend;
+#[cfg(any(test, feature = "testing"))]
+pub use memory::{InMemoryBackend, MemoryDataset};
+
+use crate::kdf::KdfScheme;
+use crate::password::KdfParams;
+use crate::structs::KeyFormat;
 use crate::{Error, Result};
 use clap::crate_version;
+use std::collections::BTreeMap;
 use std::fmt;
-use std::io::Write;
-use std::process::Command;
 use std::sync::Arc;
+#[cfg(feature = "libzfs")]
+use std::sync::LazyLock;
 use strum::IntoEnumIterator;
-use strum_macros::{Display, EnumIter};
+use strum_macros::{Display, EnumIter, EnumString};
 
 /// ZFS Properties used to store Shavee configuration.
 /// These properties are stored on the dataset itself.
-#[derive(Debug, Clone, PartialEq, Copy, EnumIter, Display)]
+#[derive(Debug, Clone, PartialEq, Eq, Copy, EnumIter, Display)]
 pub enum ZfsShaveeProperties {
     /// The salt used for key derivation (stored as base64).
     #[strum(serialize = "com.github.shavee:salt")]
@@ -45,16 +64,360 @@ pub enum ZfsShaveeProperties {
     /// Shavee version used to create/update the dataset.
     #[strum(serialize = "com.github.shavee:version")]
     Version,
+    /// Format in which the derived key is given to ZFS (passphrase, raw or hex).
+    #[strum(serialize = "com.github.shavee:keyformat")]
+    KeyFormat,
+    /// Keyed MAC of the derived key, to detect a wrong password before asking ZFS.
+    #[strum(serialize = "com.github.shavee:keycheck")]
+    KeyCheck,
+    /// Argon2 memory size in KiB.
+    #[strum(serialize = "com.github.shavee:kdfmemory")]
+    KdfMemory,
+    /// Argon2 number of iterations.
+    #[strum(serialize = "com.github.shavee:kdfiterations")]
+    KdfIterations,
+    /// Argon2 degree of parallelism.
+    #[strum(serialize = "com.github.shavee:kdflanes")]
+    KdfLanes,
+    /// Identifier of the key derivation scheme (see `kdf::KdfScheme`).
+    #[strum(serialize = "com.github.shavee:kdf")]
+    Kdf,
+}
+
+impl ZfsShaveeProperties {
+    /// Returns the ZFS property names of all Shavee properties.
+    pub fn names() -> Vec<String> {
+        Self::iter().map(|property| property.to_string()).collect()
+    }
 }
 
-/// ZFS error messages that can be safely ignored under certain conditions.
-const ZFS_ERROR_ALREADY_MOUNTED: &str = "filesystem already mounted";
-const ZFS_ERROR_KEY_ALREADY_LOADED: &str = "Key already loaded";
+/// Shavee configuration read back from a dataset's properties.
+/// Every field is `None` if the corresponding property is unset or can't be parsed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShaveeProperties {
+    /// Base64-encoded salt.
+    pub salt: Option<String>,
+    /// Second factor method ("Password", "Yubikey" or "File").
+    pub second_factor: Option<String>,
+    /// Yubikey HMAC slot.
+    pub yubikey_slot: Option<u8>,
+    /// Yubikey serial number.
+    pub yubikey_serial: Option<u32>,
+    /// Key file path or URL.
+    pub file_path: Option<String>,
+    /// Port for remote key files.
+    pub file_port: Option<u16>,
+    /// Maximum bytes to read from the key file.
+    pub file_size: Option<u64>,
+    /// Shavee version used to create/update the dataset.
+    pub version: Option<String>,
+    /// Format of the key given to ZFS. Datasets without it use `KeyFormat::Passphrase`.
+    pub key_format: Option<KeyFormat>,
+    /// Key check value (see `logic::key_check`).
+    pub key_check: Option<String>,
+    /// Argon2 memory size in KiB.
+    pub kdf_memory: Option<u32>,
+    /// Argon2 number of iterations.
+    pub kdf_iterations: Option<u32>,
+    /// Argon2 degree of parallelism.
+    pub kdf_lanes: Option<u32>,
+    /// Key derivation scheme identifier.
+    pub kdf: Option<String>,
+}
+
+impl ShaveeProperties {
+    /// Builds the typed properties from raw values keyed by ZFS property name.
+    pub fn from_values(values: &BTreeMap<String, String>) -> Self {
+        let get = |property: &str| values.get(property).cloned();
+        let mut properties = Self::default();
+        for property in ZfsShaveeProperties::iter() {
+            let value = get(&property.to_string());
+            match property {
+                ZfsShaveeProperties::Salt => properties.salt = value,
+                ZfsShaveeProperties::SecondFactor => properties.second_factor = value,
+                #[cfg(feature = "yubikey")]
+                ZfsShaveeProperties::YubikeySlot => {
+                    properties.yubikey_slot = value.and_then(|s| s.parse().ok())
+                }
+                ZfsShaveeProperties::YubikeySerial => {
+                    properties.yubikey_serial = value.and_then(|s| s.parse().ok())
+                }
+                #[cfg(feature = "file")]
+                ZfsShaveeProperties::FilePath => properties.file_path = value,
+                #[cfg(feature = "file")]
+                ZfsShaveeProperties::FilePort => {
+                    properties.file_port = value.and_then(|s| s.parse().ok())
+                }
+                #[cfg(feature = "file")]
+                ZfsShaveeProperties::FileSize => {
+                    properties.file_size = value.and_then(|s| s.parse().ok())
+                }
+                ZfsShaveeProperties::Version => properties.version = value,
+                ZfsShaveeProperties::KeyFormat => {
+                    properties.key_format = value.and_then(|s| s.parse().ok())
+                }
+                ZfsShaveeProperties::KeyCheck => properties.key_check = value,
+                ZfsShaveeProperties::KdfMemory => {
+                    properties.kdf_memory = value.and_then(|s| s.parse().ok())
+                }
+                ZfsShaveeProperties::KdfIterations => {
+                    properties.kdf_iterations = value.and_then(|s| s.parse().ok())
+                }
+                ZfsShaveeProperties::KdfLanes => {
+                    properties.kdf_lanes = value.and_then(|s| s.parse().ok())
+                }
+                ZfsShaveeProperties::Kdf => properties.kdf = value,
+            }
+        }
+        properties
+    }
+
+    /// Returns the stored Argon2 parameters. Datasets created before they were stored use the
+    /// defaults for the missing ones.
+    pub fn kdf_params(&self) -> KdfParams {
+        let default = KdfParams::default();
+        KdfParams {
+            memory: self.kdf_memory.unwrap_or(default.memory),
+            iterations: self.kdf_iterations.unwrap_or(default.iterations),
+            lanes: self.kdf_lanes.unwrap_or(default.lanes),
+        }
+    }
+
+    /// Returns the stored key derivation scheme, `KdfScheme::LEGACY` if none is stored.
+    /// An unknown scheme is an `Error::Properties`.
+    pub fn kdf_scheme(&self) -> Result<KdfScheme> {
+        KdfScheme::from_property(self.kdf.as_deref())
+    }
+
+    /// Reconstructs the `TwoFactorMode` stored on the dataset named `dataset`.
+    pub fn two_factor_mode(&self, dataset: &str) -> Result<crate::structs::TwoFactorMode> {
+        let second_factor = self.second_factor.as_deref().ok_or_else(|| {
+            Error::Properties(format!("Missing second factor property on {}", dataset))
+        })?;
+
+        match second_factor {
+            #[cfg(feature = "yubikey")]
+            "Yubikey" => Ok(crate::structs::TwoFactorMode::Yubikey {
+                yslot: self.yubikey_slot,
+                serial: self.yubikey_serial,
+            }),
+            #[cfg(feature = "file")]
+            "File" => {
+                let file = self.file_path.clone().ok_or_else(|| {
+                    Error::Properties(format!("Missing file path property on {}", dataset))
+                })?;
+                Ok(crate::structs::TwoFactorMode::File {
+                    file,
+                    port: self.file_port,
+                    size: self.file_size,
+                })
+            }
+            "Password" => Ok(crate::structs::TwoFactorMode::Password),
+            _ => Err(Error::Properties(format!(
+                "Unknown second factor mode: {}",
+                second_factor
+            ))),
+        }
+    }
+}
+
+/// Native encryption of a dataset, from its `encryption`, `encryptionroot` and `keystatus`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Encryption {
+    /// `encryption=off`, the dataset has no key.
+    Off,
+    /// The dataset is an encryption root: its key is loaded into it.
+    Root { key_loaded: bool },
+    /// The dataset shares the key of its encryption root `root`, one of its ancestors, and
+    /// is unlocked along with it.
+    Inherited { root: String, key_loaded: bool },
+}
+
+impl Encryption {
+    /// Native properties describing the encryption of a dataset.
+    pub const PROPERTIES: [&str; 3] = ["encryption", "encryptionroot", "keystatus"];
+
+    /// Builds the encryption of the dataset named `dataset` from the values of `PROPERTIES`.
+    pub fn from_values(dataset: &str, values: &BTreeMap<String, String>) -> Self {
+        if values.get("encryption").is_none_or(|value| value == "off") {
+            return Encryption::Off;
+        }
+        let key_loaded = values
+            .get("keystatus")
+            .is_some_and(|value| value == "available");
+        match values.get("encryptionroot") {
+            Some(root) if root != dataset => Encryption::Inherited {
+                root: root.clone(),
+                key_loaded,
+            },
+            _ => Encryption::Root { key_loaded },
+        }
+    }
+
+    /// Returns true if the key protecting the dataset is loaded.
+    pub fn key_loaded(&self) -> bool {
+        match self {
+            Encryption::Off => false,
+            Encryption::Root { key_loaded } | Encryption::Inherited { key_loaded, .. } => {
+                *key_loaded
+            }
+        }
+    }
+}
+
+/// The `canmount` property of a dataset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum CanMount {
+    #[default]
+    On,
+    Off,
+    /// Only mounted explicitly, not along with the other datasets.
+    NoAuto,
+}
+
+/// The `mountpoint` property of a dataset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mountpoint {
+    /// Mounted by ZFS at this path.
+    Path(String),
+    /// `mountpoint=legacy`, mounted with mount(8) and /etc/fstab instead of ZFS.
+    Legacy,
+    /// `mountpoint=none`, or a dataset without one like a volume: never mounted.
+    None,
+}
+
+impl Mountpoint {
+    /// Parses the value of `mountpoint`, `None` if it is unset.
+    pub fn from_value(value: Option<&str>) -> Self {
+        match value {
+            Some("legacy") => Mountpoint::Legacy,
+            Some(path) if path.starts_with('/') => Mountpoint::Path(path.to_string()),
+            _ => Mountpoint::None,
+        }
+    }
+
+    /// Number of components of the mountpoint path, 0 if it isn't a path.
+    /// A dataset must be mounted after every dataset with a smaller depth.
+    pub fn depth(&self) -> usize {
+        match self {
+            Mountpoint::Path(path) => path.split('/').filter(|c| !c.is_empty()).count(),
+            Mountpoint::Legacy | Mountpoint::None => 0,
+        }
+    }
+}
+
+/// Native properties describing how a dataset is mounted.
+const MOUNT_PROPERTIES: [&str; 3] = ["canmount", "mountpoint", "mounted"];
+
+/// A dataset found by `resolve_datasets`, with its Shavee properties, native encryption and
+/// mount properties.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedDataset {
+    pub dataset: Dataset,
+    pub properties: ShaveeProperties,
+    pub encryption: Encryption,
+    pub canmount: CanMount,
+    pub mountpoint: Mountpoint,
+    /// Whether the dataset is mounted.
+    pub mounted: bool,
+}
+
+/// Values written in place of the salt and the key check value by planned property writes,
+/// as both are only known once the key is derived.
+pub const PLANNED_SALT: &str = "<new salt>";
+pub const PLANNED_KEY_CHECK: &str = "<key check>";
+
+/// A change to the pool planned by a dry run. `Display` shows the equivalent command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    /// Creates an encrypted dataset with its properties.
+    Create {
+        dataset: String,
+        key_format: KeyFormat,
+        properties: Vec<(String, String)>,
+    },
+    /// Loads the key of a dataset.
+    LoadKey { dataset: String },
+    /// Checks the current key of a dataset without loading it.
+    VerifyKey { dataset: String },
+    /// Sets several properties at once.
+    SetProperties {
+        dataset: String,
+        properties: Vec<(String, String)>,
+    },
+    /// Removes a property cleared by `SetProperties` once the new key is in use.
+    InheritProperty { dataset: String, property: String },
+    /// Replaces the key of a dataset.
+    ChangeKey {
+        dataset: String,
+        key_format: KeyFormat,
+    },
+    /// Mounts a dataset.
+    Mount { dataset: String },
+    /// Mounts a dataset with a legacy mountpoint through mount(8).
+    MountLegacy { dataset: String },
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Create {
+                dataset,
+                key_format,
+                properties,
+            } => {
+                write!(
+                    f,
+                    "zfs create -o encryption=on -o keyformat={} -o keylocation=prompt",
+                    key_format
+                )?;
+                for (property, value) in properties {
+                    write!(f, " -o {}={}", property, value)?;
+                }
+                write!(f, " {}", dataset)
+            }
+            Operation::LoadKey { dataset } => write!(f, "zfs load-key {}", dataset),
+            Operation::VerifyKey { dataset } => write!(f, "zfs load-key -n {}", dataset),
+            Operation::SetProperties {
+                dataset,
+                properties,
+            } => {
+                write!(f, "zfs set")?;
+                for (property, value) in properties {
+                    write!(f, " {}={}", property, value)?;
+                }
+                write!(f, " {}", dataset)
+            }
+            Operation::InheritProperty { dataset, property } => {
+                write!(f, "zfs inherit {} {}", property, dataset)
+            }
+            Operation::ChangeKey {
+                dataset,
+                key_format,
+            } => write!(
+                f,
+                "zfs change-key -o keylocation=prompt -o keyformat={} {}",
+                key_format, dataset
+            ),
+            Operation::Mount { dataset } => write!(f, "zfs mount {}", dataset),
+            Operation::MountLegacy { dataset } => write!(f, "mount {}", dataset),
+        }
+    }
+}
 
 /// A representation of a ZFS dataset.
-#[derive(Debug, PartialEq, Clone)]
+/// Two datasets are equal if they have the same name, regardless of their backend.
+#[derive(Debug, Clone)]
 pub struct Dataset {
     name: String,
+    backend: Arc<dyn ZfsBackend>,
+}
+
+impl PartialEq for Dataset {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
 }
 
 impl fmt::Display for Dataset {
@@ -65,10 +428,20 @@ impl fmt::Display for Dataset {
 
 impl Dataset {
     /// Initializes a new Dataset instance after validating its name.
+    /// The dataset is managed through the backend returned by `default_backend()`.
     ///
     /// # Arguments
     /// * `name` - The full path of the ZFS dataset (e.g., "zpool/home/user").
     pub fn new(name: String) -> Result<Self> {
+        Self::with_backend(name, default_backend())
+    }
+
+    /// Initializes a new Dataset instance managed through a custom `ZfsBackend`.
+    ///
+    /// # Arguments
+    /// * `name` - The full path of the ZFS dataset (e.g., "zpool/home/user").
+    /// * `backend` - The backend executing the ZFS operations.
+    pub fn with_backend(name: String, backend: Arc<dyn ZfsBackend>) -> Result<Self> {
         crate::trace(&format!("Validating ZFS dataset name: \"{}\"", name));
 
         // Check if characters are allowed in ZFS dataset names
@@ -77,7 +450,7 @@ impl Dataset {
 
         if name.is_empty()
             || !name.chars().all(is_valid_char)
-            || !name.chars().next().map_or(false, |c| c.is_alphanumeric())
+            || !name.chars().next().is_some_and(|c| c.is_alphanumeric())
         {
             return Err(Error::InvalidInput(format!(
                 "Invalid ZFS dataset name: {}",
@@ -85,7 +458,7 @@ impl Dataset {
             )));
         }
 
-        Ok(Self { name })
+        Ok(Self { name, backend })
     }
 
     /// Returns the name of the dataset.
@@ -93,191 +466,404 @@ impl Dataset {
         &self.name
     }
 
+    /// Returns the backend used to manage this dataset.
+    pub fn backend(&self) -> &Arc<dyn ZfsBackend> {
+        &self.backend
+    }
+
     /// Sets multiple Shavee configuration properties on the ZFS dataset at once.
     ///
     /// # Arguments
     /// * `mode` - The 2FA mode configuration to store.
     /// * `salt` - The base64-encoded salt string.
+    /// * `key_format` - The format in which the key is given to ZFS.
+    /// * `kdf` - The Argon2 parameters the key was derived with.
     pub fn set_properties_2fa(
         &self,
         mode: crate::structs::TwoFactorMode,
         salt: &str,
+        key_format: KeyFormat,
+        kdf: &KdfParams,
     ) -> Result<()> {
         crate::trace(&format!(
             "Setting Shavee properties for dataset: {}",
             self.name
         ));
 
-        // Iterate through all possible Shavee properties and set relevant ones
-        for property in ZfsShaveeProperties::iter() {
-            let value = match property {
-                ZfsShaveeProperties::Salt => Some(salt.to_string()),
-                ZfsShaveeProperties::Version => Some(crate_version!().to_string()),
-                ZfsShaveeProperties::SecondFactor => match mode {
-                    #[cfg(feature = "yubikey")]
-                    crate::structs::TwoFactorMode::Yubikey { .. } => Some("Yubikey".to_string()),
-                    #[cfg(feature = "file")]
-                    crate::structs::TwoFactorMode::File { .. } => Some("File".to_string()),
-                    crate::structs::TwoFactorMode::Password => Some("Password".to_string()),
-                },
-                ZfsShaveeProperties::YubikeySerial => {
-                    if let crate::structs::TwoFactorMode::Yubikey {
-                        serial: Some(s), ..
-                    } = mode
-                    {
-                        Some(s.to_string())
-                    } else {
-                        None
-                    }
-                }
-                ZfsShaveeProperties::YubikeySlot => {
-                    if let crate::structs::TwoFactorMode::Yubikey { yslot: Some(s), .. } = mode {
-                        Some(s.to_string())
-                    } else {
-                        None
-                    }
-                }
-                ZfsShaveeProperties::FilePath => {
-                    if let crate::structs::TwoFactorMode::File { file: ref f, .. } = mode {
-                        Some(f.clone())
-                    } else {
-                        None
-                    }
-                }
-                ZfsShaveeProperties::FilePort => {
-                    if let crate::structs::TwoFactorMode::File { port: Some(p), .. } = mode {
-                        Some(p.to_string())
-                    } else {
-                        None
-                    }
-                }
-                ZfsShaveeProperties::FileSize => {
-                    if let crate::structs::TwoFactorMode::File { size: Some(s), .. } = mode {
-                        Some(s.to_string())
-                    } else {
-                        None
-                    }
-                }
-            };
+        // All properties are set at once so they never get out of sync with each other
+        self.backend.set_properties(
+            &self.name,
+            &properties_2fa(&mode, salt, key_format, kdf, None),
+        )
+    }
+
+    /// Creates a new encrypted dataset, or changes the key of an existing one, and stores the
+    /// Shavee properties for `mode` and `salt` without leaving the dataset half updated.
+    ///
+    /// A new dataset is created with all its properties in a single step. For an existing
+    /// dataset the properties are replaced before the key is changed and restored if changing
+    /// the key fails, so the stored configuration always matches the key in use (see
+    /// `change_key_2fa`).
+    ///
+    /// # Arguments
+    /// * `derived_key` - The derived key (base64) to protect the dataset with, derived with
+    ///   `KdfScheme::CURRENT`.
+    /// * `mode` - The 2FA mode configuration to store.
+    /// * `salt` - The base64-encoded salt string.
+    /// * `key_format` - The format in which the key is given to ZFS.
+    /// * `kdf` - The Argon2 parameters the key was derived with.
+    /// * `key_check` - Whether to store a key check value, see `logic::key_check`.
+    pub fn create_2fa(
+        &self,
+        derived_key: &str,
+        mode: crate::structs::TwoFactorMode,
+        salt: &str,
+        key_format: KeyFormat,
+        kdf: &KdfParams,
+        key_check: bool,
+    ) -> Result<()> {
+        let key_check = key_check
+            .then(|| crate::logic::key_check(derived_key, salt))
+            .transpose()?;
+        let properties = properties_2fa(&mode, salt, key_format, kdf, key_check.as_deref());
+        let key = crate::logic::encode_key(derived_key, key_format)?;
+
+        if !self.exists()? {
+            crate::trace("Dataset does not exist, creating new encrypted dataset");
+            return self
+                .backend
+                .create(&self.name, &key, key_format, &properties);
+        }
+
+        crate::trace("Dataset exists, updating Shavee properties and encryption key");
+        self.change_key_2fa(&key, key_format, &properties, None)
+    }
+
+    /// Re-wraps an existing encrypted dataset with a new derived key and Shavee configuration.
+    ///
+    /// `old_key` is the key derived from the dataset's current configuration, given to ZFS in
+    /// the dataset's current keyformat. The current key is verified first, and loaded if the
+    /// dataset is locked, so a wrong old key fails before anything is changed. `new_key` must
+    /// be derived with `KdfScheme::CURRENT`. The locally set Shavee properties are read back
+    /// before the key is changed, see `change_key_2fa`. A key check value is stored only with
+    /// `key_check`, like for `create_2fa`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn rekey(
+        &self,
+        old_key: &str,
+        new_key: &str,
+        mode: crate::structs::TwoFactorMode,
+        salt: &str,
+        key_format: KeyFormat,
+        kdf: &KdfParams,
+        key_check: bool,
+    ) -> Result<()> {
+        let old_format = match self.get_property("keyformat")? {
+            Some(format) => format.parse().map_err(|_| {
+                Error::Zfs(format!("Unknown keyformat {} of {}", format, self.name))
+            })?,
+            None => KeyFormat::default(),
+        };
+        match self.get_property("keystatus")?.as_deref() {
+            Some("available") => {
+                crate::trace("Dataset key is already loaded, verifying the current key");
+                self.verify_key(old_key, old_format)?;
+            }
+            Some(_) => {
+                crate::trace("Loading the current key to verify it");
+                self.load_key(old_key, old_format)?;
+            }
+            None => {
+                return Err(Error::InvalidInput(format!(
+                    "Dataset {} is not encrypted",
+                    self.name
+                )));
+            }
+        }
+
+        let key_check = key_check
+            .then(|| crate::logic::key_check(new_key, salt))
+            .transpose()?;
+        let properties = properties_2fa(&mode, salt, key_format, kdf, key_check.as_deref());
+        let key = crate::logic::encode_key(new_key, key_format)?;
+        let previous_key = crate::logic::encode_key(old_key, old_format)?;
+        self.change_key_2fa(
+            &key,
+            key_format,
+            &properties,
+            Some((&previous_key, old_format)),
+        )
+    }
+
+    /// Returns the operations `create_2fa` would perform for `mode`, without deriving a key or
+    /// changing the pool. The salt and, with `key_check`, the key check are written as
+    /// `PLANNED_SALT` and `PLANNED_KEY_CHECK`.
+    ///
+    /// Fails where `create_2fa` is bound to: the parent of a new dataset doesn't exist, or an
+    /// existing dataset isn't encrypted or its key isn't loaded.
+    pub fn plan_create_2fa(
+        &self,
+        mode: crate::structs::TwoFactorMode,
+        key_format: KeyFormat,
+        kdf: &KdfParams,
+        key_check: bool,
+    ) -> Result<Vec<Operation>> {
+        let properties = properties_2fa(
+            &mode,
+            PLANNED_SALT,
+            key_format,
+            kdf,
+            key_check.then_some(PLANNED_KEY_CHECK),
+        );
+
+        if !self.exists()? {
+            if let Some((parent, _)) = self.name.rsplit_once('/')
+                && !self.backend.exists(parent)?
+            {
+                return Err(Error::Zfs(format!(
+                    "Failed to create dataset {}: parent does not exist",
+                    self.name
+                )));
+            }
+            return Ok(vec![Operation::Create {
+                dataset: self.name.clone(),
+                key_format,
+                properties,
+            }]);
+        }
+
+        match self.get_property("keystatus")?.as_deref() {
+            Some("available") => self.plan_change_key_2fa(key_format, properties),
+            Some(_) => Err(Error::Zfs(format!(
+                "Failed to update key for {}: the key must be loaded",
+                self.name
+            ))),
+            None => Err(Error::InvalidInput(format!(
+                "Dataset {} is not encrypted",
+                self.name
+            ))),
+        }
+    }
+
+    /// Returns the operations `rekey` would perform to re-wrap the dataset for `mode`, without
+    /// deriving keys or changing the pool (see `plan_create_2fa`).
+    pub fn plan_rekey(
+        &self,
+        mode: crate::structs::TwoFactorMode,
+        key_format: KeyFormat,
+        kdf: &KdfParams,
+        key_check: bool,
+    ) -> Result<Vec<Operation>> {
+        let dataset = self.name.clone();
+        let mut operations = match self.get_property("keystatus")?.as_deref() {
+            Some("available") => vec![Operation::VerifyKey { dataset }],
+            Some(_) => vec![Operation::LoadKey { dataset }],
+            None => {
+                return Err(Error::InvalidInput(format!(
+                    "Dataset {} is not encrypted",
+                    self.name
+                )));
+            }
+        };
+        let properties = properties_2fa(
+            &mode,
+            PLANNED_SALT,
+            key_format,
+            kdf,
+            key_check.then_some(PLANNED_KEY_CHECK),
+        );
+        operations.extend(self.plan_change_key_2fa(key_format, properties)?);
+        Ok(operations)
+    }
+
+    /// Returns the operations of `change_key_2fa`, clearing only the stored Shavee properties
+    /// missing from `properties`.
+    fn plan_change_key_2fa(
+        &self,
+        key_format: KeyFormat,
+        properties: Vec<(String, String)>,
+    ) -> Result<Vec<Operation>> {
+        let stale = stale_properties(&self.local_shavee_properties()?, &properties);
+        let mut update = properties;
+        update.extend(
+            stale
+                .iter()
+                .map(|name| (name.clone(), CLEARED_VALUE.to_string())),
+        );
+
+        let mut operations = vec![
+            Operation::SetProperties {
+                dataset: self.name.clone(),
+                properties: update,
+            },
+            Operation::ChangeKey {
+                dataset: self.name.clone(),
+                key_format,
+            },
+        ];
+        operations.extend(
+            stale
+                .into_iter()
+                .map(|property| Operation::InheritProperty {
+                    dataset: self.name.clone(),
+                    property,
+                }),
+        );
+        Ok(operations)
+    }
+
+    /// Changes the key of an existing dataset and replaces its Shavee properties, so the stored
+    /// configuration always matches the key in use.
+    ///
+    /// `zfs change-key` doesn't accept user properties, so they are written first in a single
+    /// `zfs set`, where stale ones are set to `CLEARED_VALUE`, and read back before the key is
+    /// changed. If the readback, the key change or the check of the new key fails, the
+    /// previous properties are restored, and `previous_key` as well when the new key was
+    /// already set. Without `previous_key` a new key that can't be checked is left in place
+    /// along with its properties. Once the new key is in use, the cleared properties are
+    /// removed.
+    ///
+    /// `CLEARED_VALUE` is written to ZFS as a real local value. If removing it with
+    /// `inherit_property` fails, which is only logged, it stays set: Shavee reads it as unset,
+    /// but `zfs get` shows "-" with source `local` until the property is inherited again.
+    fn change_key_2fa(
+        &self,
+        key: &[u8],
+        key_format: KeyFormat,
+        properties: &[(String, String)],
+        previous_key: Option<(&[u8], KeyFormat)>,
+    ) -> Result<()> {
+        let previous = self.local_shavee_properties()?;
+        let stale = stale_properties(&previous, properties);
+        let mut update = properties.to_vec();
+        update.extend(
+            stale
+                .iter()
+                .map(|name| (name.clone(), CLEARED_VALUE.to_string())),
+        );
+        let expected: BTreeMap<String, String> = properties.iter().cloned().collect();
+        let mut restore: Vec<(String, String)> = previous.into_iter().collect();
+        restore.extend(
+            stale_properties(&expected, &restore)
+                .into_iter()
+                .map(|name| (name, CLEARED_VALUE.to_string())),
+        );
+
+        self.backend.set_properties(&self.name, &update)?;
+
+        let result = self.local_shavee_properties().and_then(|stored| {
+            if stored != expected {
+                return Err(Error::Zfs(format!(
+                    "Shavee properties of {} do not match the new key",
+                    self.name
+                )));
+            }
+            self.backend.change_key(&self.name, key, key_format)
+        });
+        if let Err(error) = result {
+            crate::error("Failed to change key, restoring previous Shavee properties");
+            return Err(self.rollback_2fa(error, &restore, None));
+        }
+
+        if let Err(error) = self.backend.verify_key(&self.name, key, key_format) {
+            if previous_key.is_some() {
+                crate::error("Failed to check new key, restoring previous key and properties");
+                return Err(self.rollback_2fa(error, &restore, previous_key));
+            }
+            return Err(error);
+        }
 
-            if let Some(val) = value {
-                self.set_property(&property.to_string(), &val)?;
+        // The configuration already matches the key, a leftover cleared value reads as unset
+        for name in stale {
+            if let Err(error) = self.backend.inherit_property(&self.name, &name) {
+                crate::error(&format!(
+                    "Failed to remove cleared property {}: {}",
+                    name, error
+                ));
             }
         }
         Ok(())
     }
 
+    /// Restores the previous key, if given, then the previous Shavee `properties` after a
+    /// failed `change_key_2fa`, and returns the error to report for `error`.
+    fn rollback_2fa(
+        &self,
+        error: Error,
+        properties: &[(String, String)],
+        key: Option<(&[u8], KeyFormat)>,
+    ) -> Error {
+        let result = match key {
+            Some((key, key_format)) => self.backend.change_key(&self.name, key, key_format),
+            None => Ok(()),
+        }
+        .and_then(|()| self.backend.set_properties(&self.name, properties));
+        match result {
+            Ok(()) => error,
+            Err(rollback_error) => Error::Zfs(format!(
+                "{}; restoring the previous key and Shavee properties also failed: {}",
+                error, rollback_error
+            )),
+        }
+    }
+
+    /// Returns the Shavee properties set locally on the dataset.
+    fn local_shavee_properties(&self) -> Result<BTreeMap<String, String>> {
+        let names = ZfsShaveeProperties::names();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        Ok(self
+            .backend
+            .get_properties(&[&self.name], &names, false)?
+            .into_iter()
+            .flat_map(|(_, values)| values)
+            .collect())
+    }
+
     /// Reconstructs the `TwoFactorMode` by reading Shavee properties from the ZFS dataset.
     /// Used for auto-detecting how to unlock a dataset.
     pub fn get_property_2fa(&self) -> Result<crate::structs::TwoFactorMode> {
-        let second_factor = self
-            .get_property(&ZfsShaveeProperties::SecondFactor.to_string())?
-            .ok_or_else(|| {
-                Error::Zfs(format!("Missing second factor property on {}", self.name))
-            })?;
+        self.get_shavee_properties()?.two_factor_mode(&self.name)
+    }
 
-        match second_factor.as_str() {
-            #[cfg(feature = "yubikey")]
-            "Yubikey" => {
-                let yslot = self
-                    .get_property(&ZfsShaveeProperties::YubikeySlot.to_string())?
-                    .and_then(|s| s.parse::<u8>().ok());
-                let serial = self
-                    .get_property(&ZfsShaveeProperties::YubikeySerial.to_string())?
-                    .and_then(|s| s.parse::<u32>().ok());
-                Ok(crate::structs::TwoFactorMode::Yubikey { yslot, serial })
-            }
-            #[cfg(feature = "file")]
-            "File" => {
-                let file = self
-                    .get_property(&ZfsShaveeProperties::FilePath.to_string())?
-                    .ok_or_else(|| {
-                        Error::Zfs(format!("Missing file path property on {}", self.name))
-                    })?;
-                let port = self
-                    .get_property(&ZfsShaveeProperties::FilePort.to_string())?
-                    .and_then(|s| s.parse::<u16>().ok());
-                let size = self
-                    .get_property(&ZfsShaveeProperties::FileSize.to_string())?
-                    .and_then(|s| s.parse::<u64>().ok());
-                Ok(crate::structs::TwoFactorMode::File { file, port, size })
-            }
-            "Password" => Ok(crate::structs::TwoFactorMode::Password),
-            _ => Err(Error::Zfs(format!(
-                "Unknown second factor mode: {}",
-                second_factor
-            ))),
-        }
+    /// Reads all Shavee properties of the dataset with a single backend query.
+    pub fn get_shavee_properties(&self) -> Result<ShaveeProperties> {
+        let names = ZfsShaveeProperties::names();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let values = self
+            .backend
+            .get_properties(&[&self.name], &names, false)?
+            .into_iter()
+            .next()
+            .map(|(_, values)| values)
+            .unwrap_or_default();
+        Ok(ShaveeProperties::from_values(&values))
     }
 
     /// Sets a single ZFS property on the dataset.
     pub fn set_property(&self, property: &str, value: &str) -> Result<()> {
-        let status = Command::new("zfs")
-            .args(["set", &format!("{}={}", property, value), &self.name])
-            .status()?;
-
-        if !status.success() {
-            return Err(Error::Zfs(format!(
-                "Failed to set property {} on {}",
-                property, self.name
-            )));
-        }
-        Ok(())
+        self.backend.set_property(&self.name, property, value)
     }
 
     /// Retrieves a single ZFS property value from the dataset.
     /// Returns `Ok(None)` if the property doesn't exist or is empty.
     pub fn get_property(&self, property: &str) -> Result<Option<String>> {
-        let output = Command::new("zfs")
-            .args(["get", "-H", "-o", "value", property, &self.name])
-            .output()?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            if stderr.contains("dataset does not exist") || stderr.contains("invalid property") {
-                return Ok(None);
-            }
-            return Err(Error::Zfs(format!(
-                "Failed to get property {}: {}",
-                property, stderr
-            )));
-        }
-
-        let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
-        if value == "-" || value.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(value))
-        }
+        self.backend.get_property(&self.name, property)
     }
 
     /// Loads the encryption key for this dataset.
-    /// Passphrase is provided via stdin.
-    pub fn load_key(&self, passphrase: &str) -> Result<()> {
-        let mut child = Command::new("zfs")
-            .args(["load-key", &self.name])
-            .stdin(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .spawn()?;
-
-        // Pipe the passphrase to the command's stdin
-        if let Some(mut stdin) = child.stdin.take() {
-            stdin.write_all(passphrase.as_bytes())?;
-            stdin.write_all(b"\n")?;
-        }
+    ///
+    /// # Arguments
+    /// * `derived_key` - The derived key (base64).
+    /// * `key_format` - The format the dataset was created with, see `ShaveeProperties`.
+    pub fn load_key(&self, derived_key: &str, key_format: KeyFormat) -> Result<()> {
+        let key = crate::logic::encode_key(derived_key, key_format)?;
+        self.backend.load_key(&self.name, &key, key_format)
+    }
 
-        let output = child.wait_with_output()?;
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            // Ignore if key is already loaded
-            if !stderr.contains(ZFS_ERROR_KEY_ALREADY_LOADED) {
-                return Err(Error::Zfs(format!(
-                    "Failed to load key for {}: {}",
-                    self.name, stderr
-                )));
-            }
-        }
-        Ok(())
+    /// Checks that the derived key unlocks the dataset without loading it.
+    pub fn verify_key(&self, derived_key: &str, key_format: KeyFormat) -> Result<()> {
+        let key = crate::logic::encode_key(derived_key, key_format)?;
+        self.backend.verify_key(&self.name, &key, key_format)
     }
 
     /// Unloads the encryption key for this dataset.
@@ -285,148 +871,258 @@ impl Dataset {
     /// # Arguments
     /// * `recursive` - If true, unloads keys for all child datasets as well.
     pub fn unload_key(&self, recursive: bool) -> Result<()> {
-        let mut cmd = Command::new("zfs");
-        cmd.arg("unload-key");
-        if recursive {
-            cmd.arg("-r");
-        }
-        let status = cmd.arg(&self.name).status()?;
-
-        if !status.success() {
-            return Err(Error::Zfs(format!(
-                "Failed to unload key for {}",
-                self.name
-            )));
-        }
-        Ok(())
+        self.backend.unload_key(&self.name, recursive)
     }
 
     /// Mounts the ZFS dataset.
     pub fn mount(&self) -> Result<()> {
-        let output = Command::new("zfs").args(["mount", &self.name]).output()?;
+        self.backend.mount(&self.name)
+    }
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            // Ignore if already mounted
-            if !stderr.contains(ZFS_ERROR_ALREADY_MOUNTED) {
-                return Err(Error::Zfs(format!(
-                    "Failed to mount {}: {}",
-                    self.name, stderr
-                )));
-            }
-        }
-        Ok(())
+    /// Mounts the ZFS dataset with `mountpoint=legacy` through mount(8) and /etc/fstab.
+    pub fn mount_legacy(&self) -> Result<()> {
+        self.backend.mount_legacy(&self.name)
     }
 
     /// Unmounts the ZFS dataset.
     pub fn unmount(&self) -> Result<()> {
-        let status = Command::new("zfs").args(["unmount", &self.name]).status()?;
+        self.backend.unmount(&self.name, false)
+    }
 
-        if !status.success() {
-            return Err(Error::Zfs(format!("Failed to unmount {}", self.name)));
-        }
-        Ok(())
+    /// Unmounts the ZFS dataset even if its mountpoint is busy.
+    pub fn force_unmount(&self) -> Result<()> {
+        self.backend.unmount(&self.name, true)
+    }
+
+    /// Unmounts the ZFS dataset with `mountpoint=legacy` through umount(8), with `force` even
+    /// if its mountpoint is busy.
+    pub fn unmount_legacy(&self, force: bool) -> Result<()> {
+        self.backend.unmount_legacy(&self.name, force)
     }
 
     /// Creates a new encrypted dataset or updates the encryption key of an existing one.
+    /// The passphrase is used as is with `keyformat=passphrase`.
     pub fn create(&self, passphrase: &str) -> Result<()> {
+        let key = passphrase.as_bytes();
         match self.exists()? {
             true => {
                 crate::trace("Dataset exists, updating encryption key");
-                let mut child = Command::new("zfs")
-                    .args([
-                        "change-key",
-                        "-o",
-                        "keylocation=prompt",
-                        "-o",
-                        "keyformat=passphrase",
-                        &self.name,
-                    ])
-                    .stdin(std::process::Stdio::piped())
-                    .spawn()?;
-
-                if let Some(mut stdin) = child.stdin.take() {
-                    stdin.write_all(passphrase.as_bytes())?;
-                    stdin.write_all(b"\n")?;
-                }
-
-                if !child.wait()?.success() {
-                    return Err(Error::Zfs(format!(
-                        "Failed to update key for {}",
-                        self.name
-                    )));
-                }
+                self.backend
+                    .change_key(&self.name, key, KeyFormat::Passphrase)
             }
             false => {
                 crate::trace("Dataset does not exist, creating new encrypted dataset");
-                let mut child = Command::new("zfs")
-                    .args([
-                        "create",
-                        "-o",
-                        "encryption=on",
-                        "-o",
-                        "keyformat=passphrase",
-                        "-o",
-                        "keylocation=prompt",
-                        &self.name,
-                    ])
-                    .stdin(std::process::Stdio::piped())
-                    .spawn()?;
-
-                if let Some(mut stdin) = child.stdin.take() {
-                    stdin.write_all(passphrase.as_bytes())?;
-                    stdin.write_all(b"\n")?;
-                }
-
-                if !child.wait()?.success() {
-                    return Err(Error::Zfs(format!(
-                        "Failed to create dataset {}",
-                        self.name
-                    )));
-                }
+                self.backend
+                    .create(&self.name, key, KeyFormat::Passphrase, &[])
             }
         }
-        Ok(())
     }
 
-    /// Checks if the dataset exists using `zfs list`.
+    /// Checks if the dataset exists.
     pub fn exists(&self) -> Result<bool> {
-        let status = Command::new("zfs")
-            .args(["list", "-H", &self.name])
-            .status()?;
-        Ok(status.success())
+        self.backend.exists(&self.name)
     }
 
     /// Lists child datasets recursively.
+    /// The children share the backend of this dataset.
     pub fn list_recursive(&self) -> Result<Vec<Dataset>> {
-        let output = Command::new("zfs")
-            .args(["list", "-H", "-o", "name", "-r", &self.name])
-            .output()?;
-
-        if !output.status.success() {
-            return Err(Error::Zfs(format!(
-                "Failed to list datasets for {}",
-                self.name
-            )));
-        }
-
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let datasets = stdout
-            .lines()
-            .filter_map(|line| Dataset::new(line.to_string()).ok())
+        let datasets = self
+            .backend
+            .list_recursive(&self.name)?
+            .into_iter()
+            .filter_map(|name| Dataset::with_backend(name, self.backend.clone()).ok())
             .collect();
 
         Ok(datasets)
     }
 }
 
-/// Resolves a list of datasets to include all their child datasets recursively.
-pub fn resolve_recursive(datasets: &[Dataset]) -> Result<Arc<[Dataset]>> {
-    let mut resolved = Vec::new();
+/// Value a Shavee property is set to in order to clear it in the same `zfs set` as the new
+/// ones. Backends report it as unset, like a property that was never set.
+const CLEARED_VALUE: &str = "-";
+
+/// Returns the names of the `current` properties missing from `properties`.
+fn stale_properties(
+    current: &BTreeMap<String, String>,
+    properties: &[(String, String)],
+) -> Vec<String> {
+    current
+        .keys()
+        .filter(|name| !properties.iter().any(|(property, _)| property == *name))
+        .cloned()
+        .collect()
+}
+
+/// Returns the Shavee properties and their values describing `mode`, `salt` and `kdf`, for a
+/// key derived with `KdfScheme::CURRENT`.
+fn properties_2fa(
+    mode: &crate::structs::TwoFactorMode,
+    salt: &str,
+    key_format: KeyFormat,
+    kdf: &KdfParams,
+    key_check: Option<&str>,
+) -> Vec<(String, String)> {
+    let mut properties = Vec::new();
+    // Iterate through all possible Shavee properties and keep the relevant ones
+    for property in ZfsShaveeProperties::iter() {
+        let value = match property {
+            ZfsShaveeProperties::Salt => Some(salt.to_string()),
+            ZfsShaveeProperties::Version => Some(crate_version!().to_string()),
+            ZfsShaveeProperties::KeyFormat => Some(key_format.to_string()),
+            ZfsShaveeProperties::KeyCheck => key_check.map(str::to_string),
+            ZfsShaveeProperties::KdfMemory => Some(kdf.memory.to_string()),
+            ZfsShaveeProperties::KdfIterations => Some(kdf.iterations.to_string()),
+            ZfsShaveeProperties::KdfLanes => Some(kdf.lanes.to_string()),
+            ZfsShaveeProperties::Kdf => Some(KdfScheme::CURRENT.to_string()),
+            ZfsShaveeProperties::SecondFactor => match mode {
+                #[cfg(feature = "yubikey")]
+                crate::structs::TwoFactorMode::Yubikey { .. } => Some("Yubikey".to_string()),
+                #[cfg(feature = "file")]
+                crate::structs::TwoFactorMode::File { .. } => Some("File".to_string()),
+                crate::structs::TwoFactorMode::Password => Some("Password".to_string()),
+            },
+            ZfsShaveeProperties::YubikeySerial => {
+                if let crate::structs::TwoFactorMode::Yubikey {
+                    serial: Some(s), ..
+                } = mode
+                {
+                    Some(s.to_string())
+                } else {
+                    None
+                }
+            }
+            #[cfg(feature = "yubikey")]
+            ZfsShaveeProperties::YubikeySlot => {
+                if let crate::structs::TwoFactorMode::Yubikey { yslot: Some(s), .. } = mode {
+                    Some(s.to_string())
+                } else {
+                    None
+                }
+            }
+            ZfsShaveeProperties::FilePath => {
+                if let crate::structs::TwoFactorMode::File { file: f, .. } = mode {
+                    Some(f.clone())
+                } else {
+                    None
+                }
+            }
+            ZfsShaveeProperties::FilePort => {
+                if let crate::structs::TwoFactorMode::File { port: Some(p), .. } = mode {
+                    Some(p.to_string())
+                } else {
+                    None
+                }
+            }
+            ZfsShaveeProperties::FileSize => {
+                if let crate::structs::TwoFactorMode::File { size: Some(s), .. } = mode {
+                    Some(s.to_string())
+                } else {
+                    None
+                }
+            }
+        };
+
+        if let Some(val) = value {
+            properties.push((property.to_string(), val));
+        }
+    }
+    properties
+}
+
+/// Returns the backend used by `Dataset::new`.
+///
+/// With the `libzfs` feature this is a shared `LibZfsBackend` if libzfs can be loaded and
+/// initialized, otherwise (and without the feature) it is `CliBackend`.
+pub fn default_backend() -> Arc<dyn ZfsBackend> {
+    #[cfg(feature = "libzfs")]
+    {
+        static LIBZFS: LazyLock<Option<Arc<LibZfsBackend>>> = LazyLock::new(|| {
+            LibZfsBackend::new()
+                .inspect_err(|e| crate::trace(&format!("Falling back to zfs CLI: {}", e)))
+                .ok()
+                .map(Arc::new)
+        });
+        if let Some(backend) = LIBZFS.as_ref() {
+            return backend.clone();
+        }
+    }
+    Arc::new(CliBackend)
+}
+
+/// Resolves a list of datasets to include all their child datasets recursively, with their
+/// Shavee properties and native encryption (see `resolve_datasets`).
+pub fn resolve_recursive(datasets: &[Dataset]) -> Result<Vec<ResolvedDataset>> {
+    resolve_datasets(datasets, true)
+}
+
+/// Reads the Shavee properties, native encryption and mount properties of a set of datasets,
+/// and of all their children if `recursive`, in a single query per backend.
+///
+/// Returns the datasets parents first, so every encryption root comes before the datasets
+/// sharing its key.
+pub fn resolve_datasets(datasets: &[Dataset], recursive: bool) -> Result<Vec<ResolvedDataset>> {
+    let mut names = ZfsShaveeProperties::names();
+    names.extend(Encryption::PROPERTIES.map(String::from));
+    names.extend(MOUNT_PROPERTIES.map(String::from));
+    Ok(get_properties(datasets, &names, recursive)?
+        .into_iter()
+        .map(|(dataset, values)| ResolvedDataset {
+            encryption: Encryption::from_values(dataset.name(), &values),
+            properties: ShaveeProperties::from_values(&values),
+            canmount: values
+                .get("canmount")
+                .and_then(|value| value.parse().ok())
+                .unwrap_or_default(),
+            mountpoint: Mountpoint::from_value(values.get("mountpoint").map(String::as_str)),
+            mounted: values.get("mounted").is_some_and(|value| value == "yes"),
+            dataset,
+        })
+        .collect())
+}
+
+/// Reads the Shavee properties of a set of datasets, and of all their children if `recursive`.
+///
+/// Datasets sharing a backend are queried together, which for `CliBackend` means a single
+/// `zfs get` invocation. Returns the datasets with their properties, parents first.
+pub fn get_shavee_properties(
+    datasets: &[Dataset],
+    recursive: bool,
+) -> Result<Vec<(Dataset, ShaveeProperties)>> {
+    Ok(
+        get_properties(datasets, &ZfsShaveeProperties::names(), recursive)?
+            .into_iter()
+            .map(|(dataset, values)| (dataset, ShaveeProperties::from_values(&values)))
+            .collect(),
+    )
+}
+
+/// Reads the raw `properties` of a set of datasets, grouping them by backend.
+pub(crate) fn get_properties(
+    datasets: &[Dataset],
+    properties: &[String],
+    recursive: bool,
+) -> Result<Vec<(Dataset, BTreeMap<String, String>)>> {
+    let names: Vec<&str> = properties.iter().map(String::as_str).collect();
+
+    // Group the requested datasets by backend
+    let mut groups: Vec<(Arc<dyn ZfsBackend>, Vec<&str>)> = Vec::new();
     for dataset in datasets {
-        resolved.extend(dataset.list_recursive()?);
+        match groups
+            .iter_mut()
+            .find(|(backend, _)| Arc::ptr_eq(backend, &dataset.backend))
+        {
+            Some((_, group)) => group.push(dataset.name()),
+            None => groups.push((dataset.backend.clone(), vec![dataset.name()])),
+        }
+    }
+
+    let mut result = Vec::new();
+    for (backend, group) in groups {
+        for (name, values) in backend.get_properties(&group, &names, recursive)? {
+            result.push((Dataset::with_backend(name, backend.clone())?, values));
+        }
     }
-    Ok(Arc::from(resolved))
+    Ok(result)
 }
 
 /// Finds the maximum name length among a list of datasets for aligned printing.
@@ -484,4 +1180,523 @@ mod tests {
         let datasets: Vec<Dataset> = vec![];
         assert_eq!(get_max_namesize(&datasets), 0);
     }
+
+    fn memory_dataset(backend: &Arc<InMemoryBackend>, name: &str) -> Dataset {
+        Dataset::with_backend(name.to_string(), backend.clone()).unwrap()
+    }
+
+    #[test]
+    fn test_memory_create_load_mount() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let ds = memory_dataset(&backend, "pool/home");
+
+        assert!(!ds.exists().unwrap());
+        ds.create("secret").unwrap();
+        assert!(ds.exists().unwrap());
+
+        ds.unmount().unwrap();
+        ds.unload_key(false).unwrap();
+        assert_eq!(
+            ds.get_property("keystatus").unwrap().as_deref(),
+            Some("unavailable")
+        );
+
+        assert!(matches!(
+            ds.load_key("wrong", KeyFormat::Passphrase),
+            Err(Error::IncorrectKey(_))
+        ));
+        assert!(
+            ds.mount().is_err(),
+            "Mount must fail while the key is unloaded"
+        );
+
+        ds.load_key("secret", KeyFormat::Passphrase).unwrap();
+        ds.load_key("secret", KeyFormat::Passphrase).unwrap(); // already loaded is not an error
+        ds.mount().unwrap();
+        assert!(backend.dataset("pool/home").unwrap().mounted);
+    }
+
+    #[test]
+    fn test_memory_create_changes_key_of_existing_dataset() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let ds = memory_dataset(&backend, "pool/home");
+        ds.create("old").unwrap();
+        ds.create("new").unwrap();
+        assert_eq!(
+            backend.dataset("pool/home").unwrap().key.as_deref(),
+            Some(b"new".as_slice())
+        );
+
+        let orphan = memory_dataset(&backend, "missing/home");
+        assert!(orphan.create("secret").is_err(), "Parent must exist");
+    }
+
+    #[test]
+    fn test_memory_properties_2fa_roundtrip() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let ds = memory_dataset(&backend, "pool");
+
+        assert!(ds.get_property_2fa().is_err());
+        let kdf = KdfParams::new(65536, 2, 1).unwrap();
+        ds.set_properties_2fa(
+            crate::structs::TwoFactorMode::Password,
+            "c2FsdA",
+            KeyFormat::Passphrase,
+            &kdf,
+        )
+        .unwrap();
+        assert_eq!(
+            ds.get_property_2fa().unwrap(),
+            crate::structs::TwoFactorMode::Password
+        );
+        assert_eq!(ds.get_shavee_properties().unwrap().kdf_params(), kdf);
+        assert_eq!(
+            ds.get_property(&ZfsShaveeProperties::Salt.to_string())
+                .unwrap()
+                .as_deref(),
+            Some("c2FsdA")
+        );
+    }
+
+    #[test]
+    fn test_memory_resolve_recursive() {
+        let backend = Arc::new(InMemoryBackend::new());
+        for name in [
+            "pool",
+            "pool/home",
+            "pool/home/a",
+            "pool/home-b",
+            "pool/home/a/b",
+        ] {
+            backend.add_dataset(name);
+        }
+        let resolved = resolve_recursive(&[memory_dataset(&backend, "pool/home")]).unwrap();
+        let names: Vec<&str> = resolved.iter().map(|d| d.dataset.name()).collect();
+        assert_eq!(names, ["pool/home", "pool/home/a", "pool/home/a/b"]);
+        assert!(resolved.iter().all(|d| d.encryption == Encryption::Off));
+    }
+
+    #[test]
+    fn test_mount_properties() {
+        assert_eq!("noauto".parse::<CanMount>().unwrap(), CanMount::NoAuto);
+        assert_eq!(Mountpoint::from_value(Some("legacy")), Mountpoint::Legacy);
+        assert_eq!(Mountpoint::from_value(Some("none")), Mountpoint::None);
+        assert_eq!(Mountpoint::from_value(None), Mountpoint::None);
+        assert_eq!(Mountpoint::from_value(Some("/")).depth(), 0);
+        assert_eq!(Mountpoint::from_value(Some("/home/alice/")).depth(), 2);
+
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        backend.add_dataset("pool/root");
+        backend
+            .set_properties(
+                "pool/root",
+                &[
+                    ("canmount".to_string(), "noauto".to_string()),
+                    ("mountpoint".to_string(), "/".to_string()),
+                ],
+            )
+            .unwrap();
+        let resolved = resolve_recursive(&[memory_dataset(&backend, "pool")]).unwrap();
+        assert_eq!(resolved[0].canmount, CanMount::On);
+        assert_eq!(
+            resolved[0].mountpoint,
+            Mountpoint::Path("/pool".to_string())
+        );
+        assert!(resolved[0].mounted);
+        assert_eq!(resolved[1].canmount, CanMount::NoAuto);
+        assert_eq!(resolved[1].mountpoint.depth(), 0);
+    }
+
+    #[test]
+    fn test_memory_resolve_encryption_roots() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        backend.add_encrypted_dataset("pool/home", "secret");
+        backend.add_inheriting_dataset("pool/home/a");
+        backend.add_encrypted_dataset("pool/home/b", "other");
+        backend.add_inheriting_dataset("pool/home/b/c");
+
+        let resolved = resolve_recursive(&[memory_dataset(&backend, "pool")]).unwrap();
+        let encryption: Vec<(&str, &Encryption)> = resolved
+            .iter()
+            .map(|d| (d.dataset.name(), &d.encryption))
+            .collect();
+        let inherited = |root: &str| Encryption::Inherited {
+            root: root.to_string(),
+            key_loaded: false,
+        };
+        assert_eq!(
+            encryption,
+            [
+                ("pool", &Encryption::Off),
+                ("pool/home", &Encryption::Root { key_loaded: false }),
+                ("pool/home/a", &inherited("pool/home")),
+                ("pool/home/b", &Encryption::Root { key_loaded: false }),
+                ("pool/home/b/c", &inherited("pool/home/b")),
+            ]
+        );
+
+        // Children can't load their key, but are unlocked with their encryption root
+        let child = memory_dataset(&backend, "pool/home/a");
+        assert!(child.load_key("secret", KeyFormat::Passphrase).is_err());
+        memory_dataset(&backend, "pool/home")
+            .load_key("secret", KeyFormat::Passphrase)
+            .unwrap();
+        assert_eq!(
+            child.get_property("keystatus").unwrap().as_deref(),
+            Some("available")
+        );
+        child.mount().unwrap();
+        assert!(
+            memory_dataset(&backend, "pool/home")
+                .unload_key(false)
+                .is_err(),
+            "The key is in use by a mounted child"
+        );
+    }
+
+    #[test]
+    fn test_memory_get_shavee_properties() {
+        let backend = Arc::new(InMemoryBackend::new());
+        for name in ["pool", "pool/home", "pool/home/a"] {
+            backend.add_dataset(name);
+        }
+        let home = memory_dataset(&backend, "pool/home");
+        home.set_properties_2fa(
+            crate::structs::TwoFactorMode::Password,
+            "c2FsdA",
+            KeyFormat::Passphrase,
+            &KdfParams::default(),
+        )
+        .unwrap();
+
+        let properties = get_shavee_properties(std::slice::from_ref(&home), true).unwrap();
+        assert_eq!(properties.len(), 2);
+        assert_eq!(properties[0].0, home);
+        assert_eq!(properties[0].1.salt.as_deref(), Some("c2FsdA"));
+        assert_eq!(properties[0].1.version.as_deref(), Some(crate_version!()));
+        assert_eq!(properties[1].0.name(), "pool/home/a");
+        assert_eq!(properties[1].1, ShaveeProperties::default());
+        // Datasets without stored KDF parameters use the defaults
+        assert_eq!(properties[1].1.kdf_params(), KdfParams::default());
+        assert!(properties[1].1.two_factor_mode("pool/home/a").is_err());
+
+        let missing = memory_dataset(&backend, "pool/missing");
+        let properties = get_shavee_properties(std::slice::from_ref(&missing), false).unwrap();
+        assert_eq!(
+            properties,
+            vec![(missing.clone(), ShaveeProperties::default())]
+        );
+        assert!(get_shavee_properties(&[missing], true).is_err());
+    }
+
+    #[test]
+    fn test_memory_create_2fa_new_dataset() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let ds = memory_dataset(&backend, "pool/home");
+
+        ds.create_2fa(
+            "secret",
+            crate::structs::TwoFactorMode::Password,
+            "c2FsdA",
+            KeyFormat::Passphrase,
+            &KdfParams::default(),
+            false,
+        )
+        .unwrap();
+        let created = backend.dataset("pool/home").unwrap();
+        assert_eq!(created.key.as_deref(), Some(b"secret".as_slice()));
+        assert_eq!(
+            created
+                .properties
+                .get(&ZfsShaveeProperties::Salt.to_string())
+                .map(String::as_str),
+            Some("c2FsdA")
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "file")]
+    fn test_memory_create_2fa_clears_stale_properties() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let ds = memory_dataset(&backend, "pool/home");
+        let file_mode = |port| crate::structs::TwoFactorMode::File {
+            file: "/key".to_string(),
+            port,
+            size: None,
+        };
+
+        ds.create_2fa(
+            "old",
+            file_mode(Some(22)),
+            "b2xk",
+            KeyFormat::Passphrase,
+            &KdfParams::default(),
+            false,
+        )
+        .unwrap();
+        ds.create_2fa(
+            "new",
+            file_mode(None),
+            "bmV3",
+            KeyFormat::Passphrase,
+            &KdfParams::default(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(ds.get_property_2fa().unwrap(), file_mode(None));
+        assert_eq!(
+            backend.dataset("pool/home").unwrap().key.as_deref(),
+            Some(b"new".as_slice())
+        );
+    }
+
+    #[test]
+    fn test_memory_create_2fa_rolls_back_properties() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let ds = memory_dataset(&backend, "pool/home");
+        ds.create_2fa(
+            "old",
+            crate::structs::TwoFactorMode::Password,
+            "b2xk",
+            KeyFormat::Passphrase,
+            &KdfParams::default(),
+            false,
+        )
+        .unwrap();
+        let before = backend.dataset("pool/home").unwrap();
+
+        // Changing the key fails while it is not loaded
+        ds.unmount().unwrap();
+        ds.unload_key(false).unwrap();
+        let result = ds.create_2fa(
+            "new",
+            crate::structs::TwoFactorMode::Password,
+            "bmV3",
+            KeyFormat::Passphrase,
+            &KdfParams::default(),
+            false,
+        );
+
+        assert!(matches!(result, Err(Error::Zfs(_))));
+        let after = backend.dataset("pool/home").unwrap();
+        assert_eq!(after.properties, before.properties);
+        assert_eq!(after.key.as_deref(), Some(b"old".as_slice()));
+    }
+
+    #[test]
+    fn test_memory_create_2fa_hex_key() {
+        use base64::Engine;
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let ds = memory_dataset(&backend, "pool/home");
+        let derived = crate::logic::BASE64_ENGINE.encode([0xabu8; 64]);
+
+        ds.create_2fa(
+            &derived,
+            crate::structs::TwoFactorMode::Password,
+            "c2FsdA",
+            KeyFormat::Hex,
+            &KdfParams::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            ds.get_property("keyformat").unwrap().as_deref(),
+            Some("hex")
+        );
+        assert_eq!(
+            ds.get_shavee_properties().unwrap().key_format,
+            Some(KeyFormat::Hex)
+        );
+
+        ds.unmount().unwrap();
+        ds.unload_key(false).unwrap();
+        assert!(ds.load_key(&derived, KeyFormat::Passphrase).is_err());
+        ds.load_key(&derived, KeyFormat::Hex).unwrap();
+    }
+
+    #[test]
+    fn test_memory_rekey_locked_dataset() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_encrypted_dataset("pool", "old");
+        let ds = memory_dataset(&backend, "pool");
+
+        // A wrong old key must not change anything
+        assert!(
+            ds.rekey(
+                "wrong",
+                "new",
+                crate::structs::TwoFactorMode::Password,
+                "c2FsdA",
+                KeyFormat::Passphrase,
+                &KdfParams::default(),
+                false,
+            )
+            .is_err()
+        );
+        assert_eq!(
+            backend.dataset("pool").unwrap().key.as_deref(),
+            Some(b"old".as_slice())
+        );
+        assert!(ds.get_shavee_properties().unwrap().salt.is_none());
+
+        ds.rekey(
+            "old",
+            "new",
+            crate::structs::TwoFactorMode::Password,
+            "c2FsdA",
+            KeyFormat::Passphrase,
+            &KdfParams::default(),
+            false,
+        )
+        .unwrap();
+        let after = backend.dataset("pool").unwrap();
+        assert_eq!(after.key.as_deref(), Some(b"new".as_slice()));
+        assert_eq!(
+            ds.get_shavee_properties().unwrap().salt.as_deref(),
+            Some("c2FsdA")
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "file")]
+    fn test_memory_rekey_rolls_back_key_and_properties() {
+        use base64::Engine;
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let ds = memory_dataset(&backend, "pool/home");
+        ds.create_2fa(
+            "old",
+            crate::structs::TwoFactorMode::File {
+                file: "/key".to_string(),
+                port: Some(22),
+                size: None,
+            },
+            "b2xk",
+            KeyFormat::Passphrase,
+            &KdfParams::default(),
+            false,
+        )
+        .unwrap();
+        ds.unmount().unwrap();
+        ds.unload_key(false).unwrap();
+        let before = ds.get_shavee_properties().unwrap();
+
+        // The dataset is locked, so the new key is the first one verified
+        backend.fail_next("verify_key");
+        let result = ds.rekey(
+            "old",
+            &crate::logic::BASE64_ENGINE.encode([0xabu8; 64]),
+            crate::structs::TwoFactorMode::Password,
+            "bmV3",
+            KeyFormat::Hex,
+            &KdfParams::default(),
+            false,
+        );
+
+        assert!(matches!(result, Err(Error::Zfs(_))));
+        let after = backend.dataset("pool/home").unwrap();
+        assert_eq!(after.key.as_deref(), Some(b"old".as_slice()));
+        assert_eq!(after.key_format, KeyFormat::Passphrase);
+        assert_eq!(ds.get_shavee_properties().unwrap(), before);
+        ds.verify_key("old", KeyFormat::Passphrase).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "file")]
+    fn test_memory_rekey_under_managed_parent() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        memory_dataset(&backend, "pool/home")
+            .create_2fa(
+                "parent",
+                crate::structs::TwoFactorMode::File {
+                    file: "/key".to_string(),
+                    port: None,
+                    size: None,
+                },
+                "cGFyZW50",
+                KeyFormat::Passphrase,
+                &KdfParams::default(),
+                false,
+            )
+            .unwrap();
+        backend.add_encrypted_dataset("pool/home/a", "old");
+        let ds = memory_dataset(&backend, "pool/home/a");
+
+        // The file path inherited from the parent is not part of the child's configuration
+        ds.rekey(
+            "old",
+            "new",
+            crate::structs::TwoFactorMode::Password,
+            "c2FsdA",
+            KeyFormat::Passphrase,
+            &KdfParams::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            backend.dataset("pool/home/a").unwrap().key.as_deref(),
+            Some(b"new".as_slice())
+        );
+        assert_eq!(
+            ds.get_property_2fa().unwrap(),
+            crate::structs::TwoFactorMode::Password
+        );
+    }
+
+    #[test]
+    fn test_memory_rekey_verifies_loaded_key() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_encrypted_dataset("pool", "old");
+        let ds = memory_dataset(&backend, "pool");
+        ds.load_key("old", KeyFormat::Passphrase).unwrap();
+
+        // Loading is a no-op for a loaded key, so a wrong old key must still be caught
+        assert!(ds.verify_key("wrong", KeyFormat::Passphrase).is_err());
+        assert!(
+            ds.rekey(
+                "wrong",
+                "new",
+                crate::structs::TwoFactorMode::Password,
+                "c2FsdA",
+                KeyFormat::Passphrase,
+                &KdfParams::default(),
+                false,
+            )
+            .is_err()
+        );
+        assert_eq!(
+            backend.dataset("pool").unwrap().key.as_deref(),
+            Some(b"old".as_slice())
+        );
+    }
+
+    #[test]
+    fn test_memory_rekey_unencrypted_dataset() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        let ds = memory_dataset(&backend, "pool");
+        assert!(
+            ds.rekey(
+                "old",
+                "new",
+                crate::structs::TwoFactorMode::Password,
+                "c2FsdA",
+                KeyFormat::Passphrase,
+                &KdfParams::default(),
+                false,
+            )
+            .is_err()
+        );
+    }
 }
@@ -0,0 +1,530 @@
+//! Pluggable ZFS backends.
+//!
+//! Every operation `Dataset` performs against the pool goes through the `ZfsBackend` trait.
+//! `CliBackend` is the production implementation and shells out to the `zfs` binary.
+
+use crate::structs::KeyFormat;
+use crate::{Error, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::process::Command;
+
+/// ZFS error messages that can be safely ignored under certain conditions.
+pub(super) const ZFS_ERROR_ALREADY_MOUNTED: &str = "filesystem already mounted";
+pub(super) const ZFS_ERROR_KEY_ALREADY_LOADED: &str = "Key already loaded";
+pub(super) const ZFS_ERROR_INCORRECT_KEY: &str = "Incorrect key";
+
+/// Returns true if the value of `property` with the `zfs get` `source` describes the dataset
+/// itself. User properties (`module:property`) are only kept when set locally or received:
+/// Shavee properties describe the key of the dataset they are set on, so values inherited
+/// from a parent must not be attributed to its children.
+pub(super) fn is_own_value(property: &str, source: &str) -> bool {
+    !property.contains(':') || source == "local" || source.starts_with("received")
+}
+
+/// Low-level ZFS operations used by `Dataset`.
+///
+/// Implementations receive the full dataset name (e.g. "zpool/home/user") and are expected
+/// to treat "key already loaded" and "already mounted" as success. User properties are only
+/// reported where they are set locally or received, never when inherited (see
+/// `is_own_value`).
+pub trait ZfsBackend: fmt::Debug + Send + Sync {
+    /// Sets a single property on a dataset.
+    fn set_property(&self, dataset: &str, property: &str, value: &str) -> Result<()>;
+
+    /// Sets several properties on a dataset atomically, either all of them or none
+    /// (`zfs set a=1 b=2`). `Dataset::change_key_2fa` relies on it to never leave a mix of
+    /// old and new Shavee properties behind.
+    fn set_properties(&self, dataset: &str, properties: &[(String, String)]) -> Result<()>;
+
+    /// Removes a locally set property so the value is inherited again (`zfs inherit`).
+    fn inherit_property(&self, dataset: &str, property: &str) -> Result<()>;
+
+    /// Retrieves a single property value.
+    /// Returns `Ok(None)` if the property or the dataset doesn't exist, or the value is empty.
+    fn get_property(&self, dataset: &str, property: &str) -> Result<Option<String>>;
+
+    /// Loads the encryption key of a dataset.
+    /// `key` is the key material in the dataset's `format` (see `logic::encode_key`).
+    fn load_key(&self, dataset: &str, key: &[u8], format: KeyFormat) -> Result<()>;
+
+    /// Checks that `key` unlocks a dataset without loading it (`zfs load-key -n`).
+    /// Unlike `load_key` this also fails for a wrong key when the key is already loaded.
+    fn verify_key(&self, dataset: &str, key: &[u8], format: KeyFormat) -> Result<()>;
+
+    /// Unloads the encryption key of a dataset, optionally for all its children.
+    fn unload_key(&self, dataset: &str, recursive: bool) -> Result<()>;
+
+    /// Mounts a dataset.
+    fn mount(&self, dataset: &str) -> Result<()>;
+
+    /// Mounts a dataset with `mountpoint=legacy` through mount(8), which looks up its
+    /// mountpoint in /etc/fstab. The default implementation runs `mount <dataset>`.
+    fn mount_legacy(&self, dataset: &str) -> Result<()> {
+        let output = Command::new("mount").arg(dataset).output()?;
+
+        if !output.status.success() {
+            return Err(Error::Zfs(format!(
+                "Failed to mount {}: {}",
+                dataset,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Unmounts a dataset, with `force` even if its mountpoint is busy (`zfs unmount -f`).
+    fn unmount(&self, dataset: &str, force: bool) -> Result<()>;
+
+    /// Unmounts a dataset with `mountpoint=legacy` through umount(8), which `zfs unmount`
+    /// refuses, with `force` even if its mountpoint is busy. The default implementation runs
+    /// `umount [-f] <dataset>`.
+    fn unmount_legacy(&self, dataset: &str, force: bool) -> Result<()> {
+        let mut command = Command::new("umount");
+        if force {
+            command.arg("-f");
+        }
+        let output = command.arg(dataset).output()?;
+
+        if !output.status.success() {
+            return Err(Error::Zfs(format!(
+                "Failed to unmount {}: {}",
+                dataset,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Creates a new encrypted dataset protected by `key` in the given `format`, with
+    /// `properties` set as part of the creation.
+    fn create(
+        &self,
+        dataset: &str,
+        key: &[u8],
+        format: KeyFormat,
+        properties: &[(String, String)],
+    ) -> Result<()>;
+
+    /// Replaces the encryption key of an existing dataset with `key` in the given `format`.
+    fn change_key(&self, dataset: &str, key: &[u8], format: KeyFormat) -> Result<()>;
+
+    /// Checks whether a dataset exists.
+    fn exists(&self, dataset: &str) -> Result<bool>;
+
+    /// Lists a dataset and all of its children, parents first.
+    fn list_recursive(&self, dataset: &str) -> Result<Vec<String>>;
+
+    /// Retrieves several properties of several datasets at once.
+    ///
+    /// Returns one entry per dataset (including children if `recursive`), parents first.
+    /// Unset or empty properties are left out of the values. Without `recursive`, a dataset
+    /// that doesn't exist yields an entry with no values; with `recursive` it is an error.
+    ///
+    /// The default implementation issues one `get_property` call per dataset and property.
+    fn get_properties(
+        &self,
+        datasets: &[&str],
+        properties: &[&str],
+        recursive: bool,
+    ) -> Result<Vec<(String, BTreeMap<String, String>)>> {
+        let mut result = Vec::new();
+        for dataset in datasets {
+            let names = if recursive {
+                self.list_recursive(dataset)?
+            } else {
+                vec![dataset.to_string()]
+            };
+            for name in names {
+                let mut values = BTreeMap::new();
+                for property in properties {
+                    if let Some(value) = self.get_property(&name, property)? {
+                        values.insert(property.to_string(), value);
+                    }
+                }
+                result.push((name, values));
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Backend that executes the `zfs` command line tool via `std::process::Command`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CliBackend;
+
+impl CliBackend {
+    /// Spawns `zfs` with the given arguments and writes the key to its stdin.
+    /// Passphrases and hex keys are terminated by a newline like at an interactive prompt,
+    /// raw keys are written as exactly their bytes.
+    fn run_with_key(args: &[&str], key: &[u8], format: KeyFormat) -> Result<std::process::Output> {
+        let mut child = Command::new("zfs")
+            .args(args)
+            .stdin(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+
+        // Pipe the key to the command's stdin
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(key)?;
+            if format != KeyFormat::Raw {
+                stdin.write_all(b"\n")?;
+            }
+        }
+
+        Ok(child.wait_with_output()?)
+    }
+}
+
+impl ZfsBackend for CliBackend {
+    fn set_property(&self, dataset: &str, property: &str, value: &str) -> Result<()> {
+        let status = Command::new("zfs")
+            .args(["set", &format!("{}={}", property, value), dataset])
+            .status()?;
+
+        if !status.success() {
+            return Err(Error::Zfs(format!(
+                "Failed to set property {} on {}",
+                property, dataset
+            )));
+        }
+        Ok(())
+    }
+
+    /// Sets all properties with a single `zfs set`, which applies them atomically.
+    fn set_properties(&self, dataset: &str, properties: &[(String, String)]) -> Result<()> {
+        if properties.is_empty() {
+            return Ok(());
+        }
+        let output = Command::new("zfs")
+            .arg("set")
+            .args(
+                properties
+                    .iter()
+                    .map(|(property, value)| format!("{}={}", property, value)),
+            )
+            .arg(dataset)
+            .output()?;
+
+        if !output.status.success() {
+            return Err(Error::Zfs(format!(
+                "Failed to set properties on {}: {}",
+                dataset,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+
+    fn inherit_property(&self, dataset: &str, property: &str) -> Result<()> {
+        let status = Command::new("zfs")
+            .args(["inherit", property, dataset])
+            .status()?;
+
+        if !status.success() {
+            return Err(Error::Zfs(format!(
+                "Failed to inherit property {} on {}",
+                property, dataset
+            )));
+        }
+        Ok(())
+    }
+
+    fn get_property(&self, dataset: &str, property: &str) -> Result<Option<String>> {
+        let output = Command::new("zfs")
+            .args(["get", "-H", "-o", "value,source", property, dataset])
+            .output()?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if stderr.contains("dataset does not exist") || stderr.contains("invalid property") {
+                return Ok(None);
+            }
+            return Err(Error::Zfs(format!(
+                "Failed to get property {}: {}",
+                property, stderr
+            )));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let line = stdout.trim_end_matches('\n');
+        let (value, source) = line.rsplit_once('\t').unwrap_or((line, "-"));
+        if value == "-" || value.is_empty() || !is_own_value(property, source) {
+            Ok(None)
+        } else {
+            Ok(Some(value.to_string()))
+        }
+    }
+
+    fn load_key(&self, dataset: &str, key: &[u8], format: KeyFormat) -> Result<()> {
+        let output = Self::run_with_key(&["load-key", dataset], key, format)?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if stderr.contains(ZFS_ERROR_INCORRECT_KEY) {
+                return Err(Error::IncorrectKey(format!(
+                    "Failed to load key for {}: {}",
+                    dataset, stderr
+                )));
+            }
+            // Ignore if key is already loaded
+            if !stderr.contains(ZFS_ERROR_KEY_ALREADY_LOADED) {
+                return Err(Error::Zfs(format!(
+                    "Failed to load key for {}: {}",
+                    dataset, stderr
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn verify_key(&self, dataset: &str, key: &[u8], format: KeyFormat) -> Result<()> {
+        let output = Self::run_with_key(&["load-key", "-n", dataset], key, format)?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let message = format!("Failed to verify key for {}: {}", dataset, stderr);
+            if stderr.contains(ZFS_ERROR_INCORRECT_KEY) {
+                return Err(Error::IncorrectKey(message));
+            }
+            return Err(Error::Zfs(message));
+        }
+        Ok(())
+    }
+
+    fn unload_key(&self, dataset: &str, recursive: bool) -> Result<()> {
+        let mut cmd = Command::new("zfs");
+        cmd.arg("unload-key");
+        if recursive {
+            cmd.arg("-r");
+        }
+        let status = cmd.arg(dataset).status()?;
+
+        if !status.success() {
+            return Err(Error::Zfs(format!("Failed to unload key for {}", dataset)));
+        }
+        Ok(())
+    }
+
+    fn mount(&self, dataset: &str) -> Result<()> {
+        let output = Command::new("zfs").args(["mount", dataset]).output()?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // Ignore if already mounted
+            if !stderr.contains(ZFS_ERROR_ALREADY_MOUNTED) {
+                return Err(Error::Zfs(format!(
+                    "Failed to mount {}: {}",
+                    dataset, stderr
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn unmount(&self, dataset: &str, force: bool) -> Result<()> {
+        let mut cmd = Command::new("zfs");
+        cmd.arg("unmount");
+        if force {
+            cmd.arg("-f");
+        }
+        let status = cmd.arg(dataset).status()?;
+
+        if !status.success() {
+            return Err(Error::Zfs(format!("Failed to unmount {}", dataset)));
+        }
+        Ok(())
+    }
+
+    fn create(
+        &self,
+        dataset: &str,
+        key: &[u8],
+        format: KeyFormat,
+        properties: &[(String, String)],
+    ) -> Result<()> {
+        let mut args: Vec<String> = vec![
+            "create".to_string(),
+            "-o".to_string(),
+            "encryption=on".to_string(),
+            "-o".to_string(),
+            format!("keyformat={}", format),
+            "-o".to_string(),
+            "keylocation=prompt".to_string(),
+        ];
+        for (property, value) in properties {
+            args.push("-o".to_string());
+            args.push(format!("{}={}", property, value));
+        }
+        args.push(dataset.to_string());
+
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let output = Self::run_with_key(&args, key, format)?;
+
+        if !output.status.success() {
+            return Err(Error::Zfs(format!(
+                "Failed to create dataset {}: {}",
+                dataset,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+
+    fn change_key(&self, dataset: &str, key: &[u8], format: KeyFormat) -> Result<()> {
+        let keyformat = format!("keyformat={}", format);
+        let output = Self::run_with_key(
+            &[
+                "change-key",
+                "-o",
+                "keylocation=prompt",
+                "-o",
+                &keyformat,
+                dataset,
+            ],
+            key,
+            format,
+        )?;
+
+        if !output.status.success() {
+            return Err(Error::Zfs(format!(
+                "Failed to update key for {}: {}",
+                dataset,
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+
+    fn exists(&self, dataset: &str) -> Result<bool> {
+        let status = Command::new("zfs").args(["list", "-H", dataset]).status()?;
+        Ok(status.success())
+    }
+
+    fn list_recursive(&self, dataset: &str) -> Result<Vec<String>> {
+        let output = Command::new("zfs")
+            .args(["list", "-H", "-o", "name", "-r", dataset])
+            .output()?;
+
+        if !output.status.success() {
+            return Err(Error::Zfs(format!(
+                "Failed to list datasets for {}",
+                dataset
+            )));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        Ok(stdout.lines().map(|line| line.to_string()).collect())
+    }
+
+    /// Reads all requested properties with a single `zfs get` invocation.
+    fn get_properties(
+        &self,
+        datasets: &[&str],
+        properties: &[&str],
+        recursive: bool,
+    ) -> Result<Vec<(String, BTreeMap<String, String>)>> {
+        let mut cmd = Command::new("zfs");
+        cmd.args(["get", "-H", "-p", "-o", "name,property,value,source"]);
+        if recursive {
+            cmd.arg("-r");
+        }
+        let output = cmd.arg(properties.join(",")).args(datasets).output()?;
+
+        // `zfs get` still prints the properties of the existing datasets if some don't exist
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() && (recursive || !stderr.contains("dataset does not exist")) {
+            return Err(Error::Zfs(format!("Failed to get properties: {}", stderr)));
+        }
+
+        let result = parse_properties(&String::from_utf8_lossy(&output.stdout));
+
+        if !recursive {
+            // Keep the requested order and report missing datasets without values
+            return Ok(datasets
+                .iter()
+                .map(|dataset| {
+                    let values = result
+                        .iter()
+                        .find(|(name, _)| name == dataset)
+                        .map(|(_, values)| values.clone())
+                        .unwrap_or_default();
+                    (dataset.to_string(), values)
+                })
+                .collect());
+        }
+        Ok(result)
+    }
+}
+
+/// Parses the output of `zfs get -H -p -o name,property,value,source` into properties per
+/// dataset, keeping the order in which the datasets are listed. Inherited user properties are
+/// left out like unset ones.
+fn parse_properties(stdout: &str) -> Vec<(String, BTreeMap<String, String>)> {
+    let mut result: Vec<(String, BTreeMap<String, String>)> = Vec::new();
+    for line in stdout.lines() {
+        // Values may contain tabs, the source is the last field
+        let mut fields = line.splitn(3, '\t');
+        let (Some(name), Some(property), Some((value, source))) = (
+            fields.next(),
+            fields.next(),
+            fields.next().and_then(|rest| rest.rsplit_once('\t')),
+        ) else {
+            continue;
+        };
+        let index = match result.iter().position(|(n, _)| n == name) {
+            Some(index) => index,
+            None => {
+                result.push((name.to_string(), BTreeMap::new()));
+                result.len() - 1
+            }
+        };
+        if value != "-" && !value.is_empty() && is_own_value(property, source) {
+            result[index]
+                .1
+                .insert(property.to_string(), value.to_string());
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_properties() {
+        let stdout = "pool/a\tcom.github.shavee:salt\tc2FsdA\tlocal\n\
+                      pool/a\tcom.github.shavee:secondfactor\tPassword\treceived\n\
+                      pool/a\tmountpoint\t/a\tinherited from pool\n\
+                      pool/a/b\tcom.github.shavee:salt\t-\t-\n\
+                      pool/a/b\tcom.github.shavee:secondfactor\tPassword\tinherited from pool/a\n";
+        let parsed = parse_properties(stdout);
+
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].0, "pool/a");
+        assert_eq!(
+            parsed[0]
+                .1
+                .get("com.github.shavee:salt")
+                .map(String::as_str),
+            Some("c2FsdA")
+        );
+        assert_eq!(
+            parsed[0]
+                .1
+                .get("com.github.shavee:secondfactor")
+                .map(String::as_str),
+            Some("Password")
+        );
+        // Native properties keep their inherited values
+        assert_eq!(
+            parsed[0].1.get("mountpoint").map(String::as_str),
+            Some("/a")
+        );
+        assert_eq!(parsed[1].0, "pool/a/b");
+        assert!(
+            parsed[1].1.is_empty(),
+            "Unset and inherited user properties must be left out"
+        );
+    }
+}
@@ -0,0 +1,591 @@
+//! Native ZFS backend using libzfs.
+//!
+//! `LibZfsBackend` loads `libzfs` at runtime and talks to it directly for property get/set,
+//! load-key, mount and create, avoiding one `zfs` process per operation. Keys are handed to
+//! libzfs through an anonymous pipe referenced as `file:///proc/self/fd/N`, after which
+//! `keylocation` is reset to `prompt` so datasets stay compatible with the `zfs` CLI.
+//! Operations without a stable libzfs API across OpenZFS releases (listing children) are
+//! delegated to `CliBackend`.
+
+use super::backend::{
+    CliBackend, ZFS_ERROR_ALREADY_MOUNTED, ZFS_ERROR_INCORRECT_KEY, ZFS_ERROR_KEY_ALREADY_LOADED,
+    ZfsBackend, is_own_value,
+};
+use crate::structs::KeyFormat;
+use crate::{Error, Result};
+use libloading::Library;
+use std::ffi::{CStr, CString, c_char, c_int, c_void};
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::sync::Mutex;
+
+/// Shared library names tried in order (OpenZFS 2.2+, 2.0/2.1, unversioned dev symlink).
+const LIBZFS_NAMES: [&str; 3] = ["libzfs.so.6", "libzfs.so.4", "libzfs.so"];
+
+/// `zfs_type_t` values.
+const ZFS_TYPE_FILESYSTEM: c_int = 1 << 0;
+const ZFS_TYPE_VOLUME: c_int = 1 << 2;
+const ZFS_TYPE_DATASET: c_int = ZFS_TYPE_FILESYSTEM | ZFS_TYPE_VOLUME;
+
+/// `nvlist_alloc` flag requiring unique pair names.
+const NV_UNIQUE_NAME: u32 = 0x1;
+
+/// `zfs_unmount` flag forcing the unmount of a busy mountpoint.
+const MS_FORCE: c_int = 0x1;
+
+/// Source of user property values received with `zfs receive`.
+const ZPROP_SOURCE_VAL_RECVD: &str = "$recvd";
+
+/// Size of the buffer receiving native property values.
+const ZFS_MAXPROPLEN: usize = 4096;
+
+type LibzfsHandle = *mut c_void;
+type ZfsHandle = *mut c_void;
+type NvList = *mut c_void;
+
+/// Function pointers resolved from libzfs and its libnvpair dependency.
+struct Symbols {
+    libzfs_init: unsafe extern "C" fn() -> LibzfsHandle,
+    libzfs_fini: unsafe extern "C" fn(LibzfsHandle),
+    libzfs_print_on_error: unsafe extern "C" fn(LibzfsHandle, c_int),
+    libzfs_error_description: unsafe extern "C" fn(LibzfsHandle) -> *const c_char,
+    zfs_open: unsafe extern "C" fn(LibzfsHandle, *const c_char, c_int) -> ZfsHandle,
+    zfs_close: unsafe extern "C" fn(ZfsHandle),
+    zfs_dataset_exists: unsafe extern "C" fn(LibzfsHandle, *const c_char, c_int) -> c_int,
+    zfs_name_to_prop: unsafe extern "C" fn(*const c_char) -> c_int,
+    zfs_prop_get: unsafe extern "C" fn(
+        ZfsHandle,
+        c_int,
+        *mut c_char,
+        usize,
+        *mut c_int,
+        *mut c_char,
+        usize,
+        c_int,
+    ) -> c_int,
+    zfs_prop_set: unsafe extern "C" fn(ZfsHandle, *const c_char, *const c_char) -> c_int,
+    zfs_prop_set_list: unsafe extern "C" fn(ZfsHandle, NvList) -> c_int,
+    zfs_prop_inherit: unsafe extern "C" fn(ZfsHandle, *const c_char, c_int) -> c_int,
+    zfs_get_user_props: unsafe extern "C" fn(ZfsHandle) -> NvList,
+    zfs_crypto_load_key: unsafe extern "C" fn(ZfsHandle, c_int, *const c_char) -> c_int,
+    zfs_crypto_unload_key: unsafe extern "C" fn(ZfsHandle) -> c_int,
+    zfs_crypto_rewrap: unsafe extern "C" fn(ZfsHandle, NvList, c_int) -> c_int,
+    zfs_create: unsafe extern "C" fn(LibzfsHandle, *const c_char, c_int, NvList) -> c_int,
+    zfs_mount: unsafe extern "C" fn(ZfsHandle, *const c_char, c_int) -> c_int,
+    zfs_unmount: unsafe extern "C" fn(ZfsHandle, *const c_char, c_int) -> c_int,
+    nvlist_alloc: unsafe extern "C" fn(*mut NvList, u32, c_int) -> c_int,
+    nvlist_free: unsafe extern "C" fn(NvList),
+    nvlist_add_string: unsafe extern "C" fn(NvList, *const c_char, *const c_char) -> c_int,
+    nvlist_lookup_nvlist: unsafe extern "C" fn(NvList, *const c_char, *mut NvList) -> c_int,
+    nvlist_lookup_string: unsafe extern "C" fn(NvList, *const c_char, *mut *const c_char) -> c_int,
+}
+
+/// The libzfs handle. libzfs handles are not thread safe, so it is only used behind a `Mutex`.
+struct Handle(LibzfsHandle);
+
+// SAFETY: the handle is only ever accessed while holding the backend's mutex.
+unsafe impl Send for Handle {}
+
+/// A `ZfsBackend` calling libzfs directly.
+pub struct LibZfsBackend {
+    symbols: Symbols,
+    handle: Mutex<Handle>,
+    // Keeps the library mapped for as long as the function pointers above are used
+    _library: Library,
+}
+
+impl std::fmt::Debug for LibZfsBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LibZfsBackend").finish_non_exhaustive()
+    }
+}
+
+/// Resolves a symbol from the library as a plain function pointer.
+macro_rules! symbol {
+    ($library:expr, $name:ident) => {
+        // SAFETY: the declared signature matches the libzfs/libnvpair C prototype.
+        *unsafe { $library.get(concat!(stringify!($name), "\0").as_bytes()) }.map_err(|e| {
+            Error::Zfs(format!(
+                "Missing symbol {} in libzfs: {}",
+                stringify!($name),
+                e
+            ))
+        })?
+    };
+}
+
+impl LibZfsBackend {
+    /// Loads libzfs and initializes a library handle.
+    ///
+    /// Fails if the library is not installed or `/dev/zfs` cannot be opened, in which case
+    /// callers should fall back to `CliBackend`.
+    pub fn new() -> Result<Self> {
+        let library = LIBZFS_NAMES
+            .iter()
+            // SAFETY: libzfs has no library constructors with preconditions.
+            .find_map(|name| unsafe { Library::new(name) }.ok())
+            .ok_or_else(|| Error::Zfs("libzfs is not available".to_string()))?;
+
+        let symbols = Symbols {
+            libzfs_init: symbol!(library, libzfs_init),
+            libzfs_fini: symbol!(library, libzfs_fini),
+            libzfs_print_on_error: symbol!(library, libzfs_print_on_error),
+            libzfs_error_description: symbol!(library, libzfs_error_description),
+            zfs_open: symbol!(library, zfs_open),
+            zfs_close: symbol!(library, zfs_close),
+            zfs_dataset_exists: symbol!(library, zfs_dataset_exists),
+            zfs_name_to_prop: symbol!(library, zfs_name_to_prop),
+            zfs_prop_get: symbol!(library, zfs_prop_get),
+            zfs_prop_set: symbol!(library, zfs_prop_set),
+            zfs_prop_set_list: symbol!(library, zfs_prop_set_list),
+            zfs_prop_inherit: symbol!(library, zfs_prop_inherit),
+            zfs_get_user_props: symbol!(library, zfs_get_user_props),
+            zfs_crypto_load_key: symbol!(library, zfs_crypto_load_key),
+            zfs_crypto_unload_key: symbol!(library, zfs_crypto_unload_key),
+            zfs_crypto_rewrap: symbol!(library, zfs_crypto_rewrap),
+            zfs_create: symbol!(library, zfs_create),
+            zfs_mount: symbol!(library, zfs_mount),
+            zfs_unmount: symbol!(library, zfs_unmount),
+            nvlist_alloc: symbol!(library, nvlist_alloc),
+            nvlist_free: symbol!(library, nvlist_free),
+            nvlist_add_string: symbol!(library, nvlist_add_string),
+            nvlist_lookup_nvlist: symbol!(library, nvlist_lookup_nvlist),
+            nvlist_lookup_string: symbol!(library, nvlist_lookup_string),
+        };
+
+        // SAFETY: libzfs_init takes no arguments and returns NULL on failure.
+        let handle = unsafe { (symbols.libzfs_init)() };
+        if handle.is_null() {
+            return Err(Error::Zfs("Failed to initialize libzfs".to_string()));
+        }
+        // Errors are reported through `Error::Zfs`, not printed by the library
+        // SAFETY: handle is a valid libzfs handle.
+        unsafe { (symbols.libzfs_print_on_error)(handle, 0) };
+
+        Ok(Self {
+            symbols,
+            handle: Mutex::new(Handle(handle)),
+            _library: library,
+        })
+    }
+
+    /// Runs `f` with the locked libzfs handle and an open handle to `dataset`.
+    fn with_dataset<T>(
+        &self,
+        dataset: &str,
+        f: impl FnOnce(LibzfsHandle, ZfsHandle) -> Result<T>,
+    ) -> Result<T> {
+        let name = c_string(dataset)?;
+        let handle = self
+            .handle
+            .lock()
+            .map_err(|_| Error::Zfs("Failed to lock libzfs handle".to_string()))?;
+        // SAFETY: handle is valid and name is NUL terminated.
+        let zhp = unsafe { (self.symbols.zfs_open)(handle.0, name.as_ptr(), ZFS_TYPE_DATASET) };
+        if zhp.is_null() {
+            return Err(Error::Zfs(format!(
+                "cannot open '{}': {}",
+                dataset,
+                self.error_description(handle.0)
+            )));
+        }
+        let result = f(handle.0, zhp);
+        // SAFETY: zhp was returned by zfs_open and is not used afterwards.
+        unsafe { (self.symbols.zfs_close)(zhp) };
+        result
+    }
+
+    /// Returns the description of the last error raised on the libzfs handle.
+    fn error_description(&self, handle: LibzfsHandle) -> String {
+        // SAFETY: handle is valid; the returned string is owned by libzfs.
+        let description = unsafe { (self.symbols.libzfs_error_description)(handle) };
+        if description.is_null() {
+            return "unknown error".to_string();
+        }
+        // SAFETY: libzfs returns a NUL terminated string.
+        unsafe { CStr::from_ptr(description) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Builds an nvlist of string properties. The caller must free it with `nvlist_free`.
+    fn string_nvlist(&self, pairs: &[(&str, &str)]) -> Result<NvList> {
+        let mut nvl: NvList = std::ptr::null_mut();
+        // SAFETY: nvl is a valid out pointer.
+        if unsafe { (self.symbols.nvlist_alloc)(&mut nvl, NV_UNIQUE_NAME, 0) } != 0 {
+            return Err(Error::Zfs("Failed to allocate nvlist".to_string()));
+        }
+        for (name, value) in pairs {
+            let (name, value) = (c_string(name)?, c_string(value)?);
+            // SAFETY: nvl was allocated above; the strings are copied by libnvpair.
+            if unsafe { (self.symbols.nvlist_add_string)(nvl, name.as_ptr(), value.as_ptr()) } != 0
+            {
+                // SAFETY: nvl is not used afterwards.
+                unsafe { (self.symbols.nvlist_free)(nvl) };
+                return Err(Error::Zfs("Failed to build nvlist".to_string()));
+            }
+        }
+        Ok(nvl)
+    }
+
+    /// Reads a user property (e.g. "com.github.shavee:salt") of the open dataset `dataset`,
+    /// `None` if it is inherited from a parent.
+    fn get_user_property(&self, zhp: ZfsHandle, dataset: &str, property: &CStr) -> Option<String> {
+        // SAFETY: zhp is an open dataset; the returned nvlist is owned by the handle.
+        let props = unsafe { (self.symbols.zfs_get_user_props)(zhp) };
+        if props.is_null() {
+            return None;
+        }
+        let mut propval: NvList = std::ptr::null_mut();
+        let mut value: *const c_char = std::ptr::null();
+        let mut setpoint: *const c_char = std::ptr::null();
+        // SAFETY: props is a valid nvlist and the out pointers are valid.
+        unsafe {
+            if (self.symbols.nvlist_lookup_nvlist)(props, property.as_ptr(), &mut propval) != 0
+                || (self.symbols.nvlist_lookup_string)(propval, c"value".as_ptr(), &mut value) != 0
+                || (self.symbols.nvlist_lookup_string)(propval, c"source".as_ptr(), &mut setpoint)
+                    != 0
+            {
+                return None;
+            }
+            // The source is the dataset the value is set on, like `zfs get` reports it
+            let setpoint = CStr::from_ptr(setpoint).to_string_lossy();
+            let source = if setpoint == dataset {
+                "local"
+            } else if setpoint == ZPROP_SOURCE_VAL_RECVD {
+                "received"
+            } else {
+                "inherited"
+            };
+            is_own_value(&property.to_string_lossy(), source)
+                .then(|| CStr::from_ptr(value).to_string_lossy().into_owned())
+        }
+    }
+
+    /// Resets `keylocation` to `prompt` after a key was provided through a pipe.
+    fn reset_keylocation(&self, handle: LibzfsHandle, zhp: ZfsHandle, dataset: &str) -> Result<()> {
+        // SAFETY: zhp is an open dataset and the strings are NUL terminated.
+        if unsafe { (self.symbols.zfs_prop_set)(zhp, c"keylocation".as_ptr(), c"prompt".as_ptr()) }
+            != 0
+        {
+            return Err(Error::Zfs(format!(
+                "Failed to set keylocation on {}: {}",
+                dataset,
+                self.error_description(handle)
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LibZfsBackend {
+    fn drop(&mut self) {
+        if let Ok(handle) = self.handle.get_mut() {
+            // SAFETY: the handle is valid and never used after this point.
+            unsafe { (self.symbols.libzfs_fini)(handle.0) };
+        }
+    }
+}
+
+impl ZfsBackend for LibZfsBackend {
+    fn set_property(&self, dataset: &str, property: &str, value: &str) -> Result<()> {
+        let (property_c, value_c) = (c_string(property)?, c_string(value)?);
+        self.with_dataset(dataset, |handle, zhp| {
+            // SAFETY: zhp is an open dataset and the strings are NUL terminated.
+            if unsafe { (self.symbols.zfs_prop_set)(zhp, property_c.as_ptr(), value_c.as_ptr()) }
+                != 0
+            {
+                return Err(Error::Zfs(format!(
+                    "Failed to set property {} on {}: {}",
+                    property,
+                    dataset,
+                    self.error_description(handle)
+                )));
+            }
+            Ok(())
+        })
+    }
+
+    fn set_properties(&self, dataset: &str, properties: &[(String, String)]) -> Result<()> {
+        let pairs: Vec<(&str, &str)> = properties
+            .iter()
+            .map(|(property, value)| (property.as_str(), value.as_str()))
+            .collect();
+        let props = self.string_nvlist(&pairs)?;
+        let result = self.with_dataset(dataset, |handle, zhp| {
+            // SAFETY: zhp is an open dataset and props is a valid nvlist.
+            if unsafe { (self.symbols.zfs_prop_set_list)(zhp, props) } != 0 {
+                return Err(Error::Zfs(format!(
+                    "Failed to set properties on {}: {}",
+                    dataset,
+                    self.error_description(handle)
+                )));
+            }
+            Ok(())
+        });
+        // SAFETY: props is not used afterwards.
+        unsafe { (self.symbols.nvlist_free)(props) };
+        result
+    }
+
+    fn inherit_property(&self, dataset: &str, property: &str) -> Result<()> {
+        let property_c = c_string(property)?;
+        self.with_dataset(dataset, |handle, zhp| {
+            // SAFETY: zhp is an open dataset and property_c is NUL terminated.
+            if unsafe { (self.symbols.zfs_prop_inherit)(zhp, property_c.as_ptr(), 0) } != 0 {
+                return Err(Error::Zfs(format!(
+                    "Failed to inherit property {} on {}: {}",
+                    property,
+                    dataset,
+                    self.error_description(handle)
+                )));
+            }
+            Ok(())
+        })
+    }
+
+    fn get_property(&self, dataset: &str, property: &str) -> Result<Option<String>> {
+        if !self.exists(dataset)? {
+            return Ok(None);
+        }
+        let property_c = c_string(property)?;
+        let value = self.with_dataset(dataset, |_, zhp| {
+            // SAFETY: property_c is NUL terminated.
+            let prop = unsafe { (self.symbols.zfs_name_to_prop)(property_c.as_ptr()) };
+            if prop < 0 {
+                // Not a native property, look it up among the user properties
+                return Ok(self.get_user_property(zhp, dataset, &property_c));
+            }
+            let mut buffer = vec![0 as c_char; ZFS_MAXPROPLEN];
+            // SAFETY: buffer is ZFS_MAXPROPLEN long; source and statbuf are optional.
+            let ret = unsafe {
+                (self.symbols.zfs_prop_get)(
+                    zhp,
+                    prop,
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    std::ptr::null_mut(),
+                    std::ptr::null_mut(),
+                    0,
+                    0,
+                )
+            };
+            if ret != 0 {
+                // Property doesn't apply to this dataset type
+                return Ok(None);
+            }
+            // SAFETY: libzfs NUL terminates the value within the buffer.
+            Ok(Some(
+                unsafe { CStr::from_ptr(buffer.as_ptr()) }
+                    .to_string_lossy()
+                    .into_owned(),
+            ))
+        })?;
+        Ok(value.filter(|v| v != "-" && !v.is_empty()))
+    }
+
+    fn load_key(&self, dataset: &str, key: &[u8], format: KeyFormat) -> Result<()> {
+        let (reader, keylocation) = key_pipe(key, format)?;
+        self.with_dataset(dataset, |handle, zhp| {
+            // SAFETY: zhp is an open dataset and keylocation is NUL terminated.
+            let ret = unsafe { (self.symbols.zfs_crypto_load_key)(zhp, 0, keylocation.as_ptr()) };
+            drop(reader);
+            if ret != 0 {
+                let description = self.error_description(handle);
+                if description.contains(ZFS_ERROR_INCORRECT_KEY) {
+                    return Err(Error::IncorrectKey(format!(
+                        "Failed to load key for {}: {}",
+                        dataset, description
+                    )));
+                }
+                // Ignore if key is already loaded
+                if !description.contains(ZFS_ERROR_KEY_ALREADY_LOADED) {
+                    return Err(Error::Zfs(format!(
+                        "Failed to load key for {}: {}",
+                        dataset, description
+                    )));
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn verify_key(&self, dataset: &str, key: &[u8], format: KeyFormat) -> Result<()> {
+        let (reader, keylocation) = key_pipe(key, format)?;
+        self.with_dataset(dataset, |handle, zhp| {
+            // SAFETY: zhp is an open dataset and keylocation is NUL terminated.
+            let ret = unsafe { (self.symbols.zfs_crypto_load_key)(zhp, 1, keylocation.as_ptr()) };
+            drop(reader);
+            if ret != 0 {
+                let description = self.error_description(handle);
+                let message = format!("Failed to verify key for {}: {}", dataset, description);
+                if description.contains(ZFS_ERROR_INCORRECT_KEY) {
+                    return Err(Error::IncorrectKey(message));
+                }
+                return Err(Error::Zfs(message));
+            }
+            Ok(())
+        })
+    }
+
+    fn unload_key(&self, dataset: &str, recursive: bool) -> Result<()> {
+        if recursive {
+            // libzfs has no recursive unload, the CLI walks the children for us
+            return CliBackend.unload_key(dataset, recursive);
+        }
+        self.with_dataset(dataset, |handle, zhp| {
+            // SAFETY: zhp is an open dataset.
+            if unsafe { (self.symbols.zfs_crypto_unload_key)(zhp) } != 0 {
+                return Err(Error::Zfs(format!(
+                    "Failed to unload key for {}: {}",
+                    dataset,
+                    self.error_description(handle)
+                )));
+            }
+            Ok(())
+        })
+    }
+
+    fn mount(&self, dataset: &str) -> Result<()> {
+        self.with_dataset(dataset, |handle, zhp| {
+            // SAFETY: zhp is an open dataset; NULL options use the dataset properties.
+            if unsafe { (self.symbols.zfs_mount)(zhp, std::ptr::null(), 0) } != 0 {
+                let description = self.error_description(handle);
+                // Ignore if already mounted
+                if !description.contains(ZFS_ERROR_ALREADY_MOUNTED) {
+                    return Err(Error::Zfs(format!(
+                        "Failed to mount {}: {}",
+                        dataset, description
+                    )));
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn unmount(&self, dataset: &str, force: bool) -> Result<()> {
+        let flags = if force { MS_FORCE } else { 0 };
+        self.with_dataset(dataset, |handle, zhp| {
+            // SAFETY: zhp is an open dataset; NULL unmounts its own mountpoint.
+            if unsafe { (self.symbols.zfs_unmount)(zhp, std::ptr::null(), flags) } != 0 {
+                return Err(Error::Zfs(format!(
+                    "Failed to unmount {}: {}",
+                    dataset,
+                    self.error_description(handle)
+                )));
+            }
+            Ok(())
+        })
+    }
+
+    fn create(
+        &self,
+        dataset: &str,
+        key: &[u8],
+        format: KeyFormat,
+        properties: &[(String, String)],
+    ) -> Result<()> {
+        let name = c_string(dataset)?;
+        let (reader, keylocation) = key_pipe(key, format)?;
+        let keyformat = format.to_string();
+        let mut pairs = vec![
+            ("encryption", "on"),
+            ("keyformat", keyformat.as_str()),
+            ("keylocation", keylocation.to_str().unwrap_or_default()),
+        ];
+        pairs.extend(
+            properties
+                .iter()
+                .map(|(property, value)| (property.as_str(), value.as_str())),
+        );
+        let props = self.string_nvlist(&pairs)?;
+        let ret = {
+            let handle = self
+                .handle
+                .lock()
+                .map_err(|_| Error::Zfs("Failed to lock libzfs handle".to_string()))?;
+            // SAFETY: handle is valid, name is NUL terminated and props is a valid nvlist.
+            let ret = unsafe {
+                (self.symbols.zfs_create)(handle.0, name.as_ptr(), ZFS_TYPE_FILESYSTEM, props)
+            };
+            // SAFETY: props is not used afterwards.
+            unsafe { (self.symbols.nvlist_free)(props) };
+            if ret != 0 {
+                Err(Error::Zfs(format!(
+                    "Failed to create dataset {}: {}",
+                    dataset,
+                    self.error_description(handle.0)
+                )))
+            } else {
+                Ok(())
+            }
+        };
+        drop(reader);
+        ret?;
+
+        // `zfs create` leaves the new dataset mounted, so do the same
+        self.with_dataset(dataset, |handle, zhp| {
+            self.reset_keylocation(handle, zhp, dataset)
+        })?;
+        self.mount(dataset)
+    }
+
+    fn change_key(&self, dataset: &str, key: &[u8], format: KeyFormat) -> Result<()> {
+        let (reader, keylocation) = key_pipe(key, format)?;
+        let props = self.string_nvlist(&[
+            ("keyformat", &format.to_string()),
+            ("keylocation", keylocation.to_str().unwrap_or_default()),
+        ])?;
+        let result = self.with_dataset(dataset, |handle, zhp| {
+            // SAFETY: zhp is an open dataset and props is a valid nvlist.
+            if unsafe { (self.symbols.zfs_crypto_rewrap)(zhp, props, 0) } != 0 {
+                return Err(Error::Zfs(format!(
+                    "Failed to update key for {}: {}",
+                    dataset,
+                    self.error_description(handle)
+                )));
+            }
+            self.reset_keylocation(handle, zhp, dataset)
+        });
+        // SAFETY: props is not used afterwards.
+        unsafe { (self.symbols.nvlist_free)(props) };
+        drop(reader);
+        result
+    }
+
+    fn exists(&self, dataset: &str) -> Result<bool> {
+        let name = c_string(dataset)?;
+        let handle = self
+            .handle
+            .lock()
+            .map_err(|_| Error::Zfs("Failed to lock libzfs handle".to_string()))?;
+        // SAFETY: handle is valid and name is NUL terminated.
+        Ok(
+            unsafe { (self.symbols.zfs_dataset_exists)(handle.0, name.as_ptr(), ZFS_TYPE_DATASET) }
+                != 0,
+        )
+    }
+
+    fn list_recursive(&self, dataset: &str) -> Result<Vec<String>> {
+        // The dataset iteration API differs between OpenZFS releases
+        CliBackend.list_recursive(dataset)
+    }
+}
+
+/// Converts a Rust string to a C string, rejecting interior NUL bytes.
+fn c_string(value: &str) -> Result<CString> {
+    CString::new(value).map_err(|e| Error::InvalidInput(e.to_string()))
+}
+
+/// Writes the key into a pipe and returns the read end along with a `keylocation` URI
+/// pointing at it. The read end must be kept open until libzfs has consumed the key.
+fn key_pipe(key: &[u8], format: KeyFormat) -> Result<(std::io::PipeReader, CString)> {
+    let (reader, mut writer) = std::io::pipe()?;
+    writer.write_all(key)?;
+    if format != KeyFormat::Raw {
+        writer.write_all(b"\n")?;
+    }
+    drop(writer);
+    let keylocation = c_string(&format!("file:///proc/self/fd/{}", reader.as_raw_fd()))?;
+    Ok((reader, keylocation))
+}
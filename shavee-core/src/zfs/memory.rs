@@ -0,0 +1,519 @@
+//! In-memory ZFS backend for tests.
+//!
+//! `InMemoryBackend` models datasets, user and native properties, key status and mount state
+//! without touching a real pool, so create/mount/print flows can be exercised on machines
+//! without ZFS. It is available to downstream crates through the `testing` feature.
+
+use super::backend::{ZfsBackend, is_own_value};
+use crate::structs::KeyFormat;
+use crate::{Error, Result};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// State of a single dataset held by `InMemoryBackend`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemoryDataset {
+    /// User properties set on the dataset (e.g. "com.github.shavee:salt").
+    pub properties: BTreeMap<String, String>,
+    /// The key material protecting the dataset, `None` if it is not encrypted.
+    pub key: Option<Vec<u8>>,
+    /// The format of `key`.
+    pub key_format: KeyFormat,
+    /// Whether the encryption key is currently loaded.
+    pub key_loaded: bool,
+    /// The encryption root whose key the dataset shares, `None` if it holds its own key or
+    /// is not encrypted.
+    pub encryption_root: Option<String>,
+    /// Whether the dataset is currently mounted.
+    pub mounted: bool,
+    /// Whether the mountpoint is in use, so only a forced unmount succeeds.
+    pub busy: bool,
+}
+
+/// A `ZfsBackend` that keeps all datasets in memory.
+#[derive(Debug, Default)]
+pub struct InMemoryBackend {
+    datasets: Mutex<BTreeMap<String, MemoryDataset>>,
+    /// Operation made to fail on its next call, see `fail_next`.
+    failure: Mutex<Option<String>>,
+}
+
+impl InMemoryBackend {
+    /// Creates an empty backend with no datasets.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an unencrypted, mounted dataset such as a pool root or a plain parent dataset.
+    pub fn add_dataset(&self, name: &str) {
+        self.lock().insert(
+            name.to_string(),
+            MemoryDataset {
+                mounted: true,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Adds an encrypted dataset protected by `passphrase` with its key unloaded.
+    pub fn add_encrypted_dataset(&self, name: &str, passphrase: &str) {
+        self.lock().insert(
+            name.to_string(),
+            MemoryDataset {
+                key: Some(passphrase.as_bytes().to_vec()),
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Adds a dataset inheriting the encryption of its parent, like `zfs create` without
+    /// encryption options. It shares the key of the parent's encryption root, if any, and is
+    /// mounted only if it isn't encrypted.
+    pub fn add_inheriting_dataset(&self, name: &str) {
+        let mut datasets = self.lock();
+        let encryption_root = name
+            .rsplit_once('/')
+            .and_then(|(parent, _)| Self::key_owner(&datasets, parent))
+            .map(|(root, _)| root.to_string());
+        datasets.insert(
+            name.to_string(),
+            MemoryDataset {
+                mounted: encryption_root.is_none(),
+                encryption_root,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Marks the mountpoint of a dataset as in use, or releases it.
+    pub fn set_busy(&self, name: &str, busy: bool) {
+        if let Some(ds) = self.lock().get_mut(name) {
+            ds.busy = busy;
+        }
+    }
+
+    /// Makes the next call of a backend operation (e.g. "verify_key") fail without changing
+    /// anything, to exercise error handling. Only `set_properties`, `change_key` and
+    /// `verify_key` can be made to fail.
+    pub fn fail_next(&self, operation: &str) {
+        *self.failure.lock().unwrap_or_else(|p| p.into_inner()) = Some(operation.to_string());
+    }
+
+    /// Fails if `operation` was made to fail with `fail_next`.
+    fn injected_failure(&self, operation: &str, dataset: &str) -> Result<()> {
+        let mut failure = self.failure.lock().unwrap_or_else(|p| p.into_inner());
+        if failure.as_deref() == Some(operation) {
+            *failure = None;
+            return Err(Error::Zfs(format!(
+                "{} failed for {}: injected failure",
+                operation, dataset
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns a snapshot of the state of a dataset.
+    pub fn dataset(&self, name: &str) -> Option<MemoryDataset> {
+        self.lock().get(name).cloned()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, MemoryDataset>> {
+        // A panicking test must not poison the state for the rest of the assertions
+        self.datasets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn not_found(dataset: &str) -> Error {
+        Error::Zfs(format!("cannot open '{}': dataset does not exist", dataset))
+    }
+
+    /// Returns the encryption root of a dataset and its state, `None` if it isn't encrypted.
+    fn key_owner<'a>(
+        datasets: &'a BTreeMap<String, MemoryDataset>,
+        dataset: &'a str,
+    ) -> Option<(&'a str, &'a MemoryDataset)> {
+        let ds = datasets.get(dataset)?;
+        match &ds.encryption_root {
+            Some(root) => datasets.get(root).map(|owner| (root.as_str(), owner)),
+            None => ds.key.as_ref().map(|_| (dataset, ds)),
+        }
+    }
+
+    /// Mounts a dataset like `zfs mount`, or like mount(8) for `legacy` mountpoints.
+    fn mount_dataset(&self, dataset: &str, legacy: bool) -> Result<()> {
+        let mut datasets = self.lock();
+        if !datasets.contains_key(dataset) {
+            return Err(Self::not_found(dataset));
+        }
+        if Self::key_owner(&datasets, dataset).is_some_and(|(_, owner)| !owner.key_loaded) {
+            return Err(Error::Zfs(format!(
+                "Failed to mount {}: encryption key not loaded",
+                dataset
+            )));
+        }
+        let Some(ds) = datasets.get_mut(dataset) else {
+            return Err(Self::not_found(dataset));
+        };
+        let mountpoint = ds.properties.get("mountpoint").map(String::as_str);
+        let mountable = match mountpoint {
+            Some("legacy") => legacy,
+            Some("none") => false,
+            _ => !legacy && ds.properties.get("canmount").map(String::as_str) != Some("off"),
+        };
+        if !mountable {
+            return Err(Error::Zfs(format!(
+                "Failed to mount {}: mountpoint or canmount property forbids it",
+                dataset
+            )));
+        }
+        ds.mounted = true;
+        Ok(())
+    }
+
+    /// Unmounts a dataset, through umount(8) if `legacy`. Like ZFS, `zfs unmount` refuses a
+    /// dataset with `mountpoint=legacy`.
+    fn unmount_dataset(&self, dataset: &str, force: bool, legacy: bool) -> Result<()> {
+        let mut datasets = self.lock();
+        let ds = datasets
+            .get(dataset)
+            .ok_or_else(|| Self::not_found(dataset))?;
+        if !ds.mounted {
+            return Err(Error::Zfs(format!("Failed to unmount {}", dataset)));
+        }
+        if !legacy && ds.properties.get("mountpoint").map(String::as_str) == Some("legacy") {
+            return Err(Error::Zfs(format!(
+                "Failed to unmount {}: legacy mountpoint, use umount(8)",
+                dataset
+            )));
+        }
+        if ds.busy && !force {
+            return Err(Error::Zfs(format!(
+                "Failed to unmount {}: target is busy",
+                dataset
+            )));
+        }
+        // Like umount(8), even forced, a filesystem with another one mounted below it is busy
+        let prefix = format!("{}/", Self::mountpoint(dataset, ds).trim_end_matches('/'));
+        if datasets.iter().any(|(name, other)| {
+            name != dataset && other.mounted && Self::mountpoint(name, other).starts_with(&prefix)
+        }) {
+            return Err(Error::Zfs(format!(
+                "Failed to unmount {}: target is busy",
+                dataset
+            )));
+        }
+        let Some(ds) = datasets.get_mut(dataset) else {
+            return Err(Self::not_found(dataset));
+        };
+        ds.mounted = false;
+        ds.busy = false;
+        Ok(())
+    }
+
+    /// Returns the `mountpoint` of a dataset, `/<name>` unless set like in a pool mounted at
+    /// the root.
+    fn mountpoint(name: &str, ds: &MemoryDataset) -> String {
+        ds.properties
+            .get("mountpoint")
+            .cloned()
+            .unwrap_or_else(|| format!("/{}", name))
+    }
+
+    /// Returns a property set on a dataset with its `zfs get` source. User properties are
+    /// inherited from the closest parent setting them, like in ZFS.
+    fn property_with_source(
+        datasets: &BTreeMap<String, MemoryDataset>,
+        dataset: &str,
+        property: &str,
+    ) -> Option<(String, String)> {
+        if let Some(value) = datasets.get(dataset)?.properties.get(property) {
+            return Some((value.clone(), "local".to_string()));
+        }
+        if !property.contains(':') {
+            return None;
+        }
+        let mut name = dataset;
+        while let Some((parent, _)) = name.rsplit_once('/') {
+            if let Some(value) = datasets
+                .get(parent)
+                .and_then(|ds| ds.properties.get(property))
+            {
+                return Some((value.clone(), format!("inherited from {}", parent)));
+            }
+            name = parent;
+        }
+        None
+    }
+
+    fn not_encryption_root(dataset: &str, root: &str, operation: &str) -> Error {
+        Error::Zfs(format!(
+            "Failed to {} for {}: keys are managed by its encryption root {}",
+            operation, dataset, root
+        ))
+    }
+
+    /// Returns true if `child` is `parent` itself or one of its descendants.
+    fn is_descendant(parent: &str, child: &str) -> bool {
+        child == parent
+            || child
+                .strip_prefix(parent)
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+}
+
+impl ZfsBackend for InMemoryBackend {
+    fn set_property(&self, dataset: &str, property: &str, value: &str) -> Result<()> {
+        let mut datasets = self.lock();
+        let ds = datasets
+            .get_mut(dataset)
+            .ok_or_else(|| Self::not_found(dataset))?;
+        ds.properties
+            .insert(property.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn set_properties(&self, dataset: &str, properties: &[(String, String)]) -> Result<()> {
+        self.injected_failure("set_properties", dataset)?;
+        let mut datasets = self.lock();
+        let ds = datasets
+            .get_mut(dataset)
+            .ok_or_else(|| Self::not_found(dataset))?;
+        ds.properties.extend(properties.iter().cloned());
+        Ok(())
+    }
+
+    fn inherit_property(&self, dataset: &str, property: &str) -> Result<()> {
+        let mut datasets = self.lock();
+        let ds = datasets
+            .get_mut(dataset)
+            .ok_or_else(|| Self::not_found(dataset))?;
+        ds.properties.remove(property);
+        Ok(())
+    }
+
+    fn get_property(&self, dataset: &str, property: &str) -> Result<Option<String>> {
+        let datasets = self.lock();
+        let Some(ds) = datasets.get(dataset) else {
+            return Ok(None);
+        };
+        let owner = Self::key_owner(&datasets, dataset);
+
+        // Native properties are derived from the modelled state
+        let value = match property {
+            "encryption" => Some(
+                if owner.is_some() {
+                    "aes-256-gcm"
+                } else {
+                    "off"
+                }
+                .to_string(),
+            ),
+            "keyformat" => owner.map(|(_, owner)| owner.key_format.to_string()),
+            "keystatus" => owner.map(|(_, owner)| {
+                if owner.key_loaded {
+                    "available"
+                } else {
+                    "unavailable"
+                }
+                .to_string()
+            }),
+            "encryptionroot" => owner.map(|(root, _)| root.to_string()),
+            "mounted" => Some(if ds.mounted { "yes" } else { "no" }.to_string()),
+            // ZFS defaults, which set_property overrides like any other property
+            "canmount" => Some(
+                ds.properties
+                    .get(property)
+                    .cloned()
+                    .unwrap_or_else(|| "on".to_string()),
+            ),
+            "mountpoint" => Some(Self::mountpoint(dataset, ds)),
+            _ => Self::property_with_source(&datasets, dataset, property)
+                .filter(|(_, source)| is_own_value(property, source))
+                .map(|(value, _)| value),
+        };
+        Ok(value.filter(|v| !v.is_empty() && v != "-"))
+    }
+
+    fn load_key(&self, dataset: &str, key: &[u8], _format: KeyFormat) -> Result<()> {
+        let mut datasets = self.lock();
+        let ds = datasets
+            .get_mut(dataset)
+            .ok_or_else(|| Self::not_found(dataset))?;
+        if let Some(root) = &ds.encryption_root {
+            return Err(Self::not_encryption_root(dataset, root, "load key"));
+        }
+        match &ds.key {
+            None => Err(Error::Zfs(format!(
+                "Failed to load key for {}: encryption is not enabled",
+                dataset
+            ))),
+            Some(_) if ds.key_loaded => Ok(()),
+            Some(expected) if expected == key => {
+                ds.key_loaded = true;
+                Ok(())
+            }
+            Some(_) => Err(Error::IncorrectKey(format!(
+                "Failed to load key for {}: Incorrect key provided",
+                dataset
+            ))),
+        }
+    }
+
+    fn verify_key(&self, dataset: &str, key: &[u8], _format: KeyFormat) -> Result<()> {
+        self.injected_failure("verify_key", dataset)?;
+        let datasets = self.lock();
+        let (_, ds) = Self::key_owner(&datasets, dataset).ok_or_else(|| {
+            if datasets.contains_key(dataset) {
+                Error::Zfs(format!(
+                    "Failed to verify key for {}: encryption is not enabled",
+                    dataset
+                ))
+            } else {
+                Self::not_found(dataset)
+            }
+        })?;
+        if ds.key.as_deref() != Some(key) {
+            return Err(Error::IncorrectKey(format!(
+                "Failed to verify key for {}: Incorrect key provided",
+                dataset
+            )));
+        }
+        Ok(())
+    }
+
+    fn unload_key(&self, dataset: &str, recursive: bool) -> Result<()> {
+        let mut datasets = self.lock();
+        let ds = datasets
+            .get(dataset)
+            .ok_or_else(|| Self::not_found(dataset))?;
+        if !recursive && let Some(root) = &ds.encryption_root {
+            return Err(Self::not_encryption_root(dataset, root, "unload key"));
+        }
+
+        let roots: Vec<String> = datasets
+            .iter()
+            .filter(|(name, ds)| {
+                ds.key.is_some()
+                    && if recursive {
+                        Self::is_descendant(dataset, name)
+                    } else {
+                        *name == dataset
+                    }
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        for root in &roots {
+            // The key stays in use while any dataset sharing it is mounted
+            let busy = datasets.iter().any(|(name, ds)| {
+                ds.mounted && (name == root || ds.encryption_root.as_ref() == Some(root))
+            });
+            if busy {
+                return Err(Error::Zfs(format!(
+                    "Failed to unload key for {}: dataset is busy",
+                    root
+                )));
+            }
+        }
+        for root in &roots {
+            if let Some(ds) = datasets.get_mut(root) {
+                ds.key_loaded = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn mount(&self, dataset: &str) -> Result<()> {
+        self.mount_dataset(dataset, false)
+    }
+
+    fn mount_legacy(&self, dataset: &str) -> Result<()> {
+        self.mount_dataset(dataset, true)
+    }
+
+    fn unmount(&self, dataset: &str, force: bool) -> Result<()> {
+        self.unmount_dataset(dataset, force, false)
+    }
+
+    fn unmount_legacy(&self, dataset: &str, force: bool) -> Result<()> {
+        self.unmount_dataset(dataset, force, true)
+    }
+
+    fn create(
+        &self,
+        dataset: &str,
+        key: &[u8],
+        format: KeyFormat,
+        properties: &[(String, String)],
+    ) -> Result<()> {
+        let mut datasets = self.lock();
+        if datasets.contains_key(dataset) {
+            return Err(Error::Zfs(format!(
+                "Failed to create dataset {}: dataset already exists",
+                dataset
+            )));
+        }
+        if let Some((parent, _)) = dataset.rsplit_once('/')
+            && !datasets.contains_key(parent)
+        {
+            return Err(Error::Zfs(format!(
+                "Failed to create dataset {}: parent does not exist",
+                dataset
+            )));
+        }
+        // `zfs create` leaves the new dataset unlocked and mounted
+        datasets.insert(
+            dataset.to_string(),
+            MemoryDataset {
+                properties: properties.iter().cloned().collect(),
+                key: Some(key.to_vec()),
+                key_format: format,
+                key_loaded: true,
+                encryption_root: None,
+                mounted: true,
+                busy: false,
+            },
+        );
+        Ok(())
+    }
+
+    fn change_key(&self, dataset: &str, key: &[u8], format: KeyFormat) -> Result<()> {
+        self.injected_failure("change_key", dataset)?;
+        let mut datasets = self.lock();
+        let ds = datasets
+            .get_mut(dataset)
+            .ok_or_else(|| Self::not_found(dataset))?;
+        if let Some(root) = &ds.encryption_root {
+            return Err(Self::not_encryption_root(dataset, root, "update key"));
+        }
+        if ds.key.is_none() || !ds.key_loaded {
+            return Err(Error::Zfs(format!(
+                "Failed to update key for {}: encryption must be enabled and the key loaded",
+                dataset
+            )));
+        }
+        ds.key = Some(key.to_vec());
+        ds.key_format = format;
+        Ok(())
+    }
+
+    fn exists(&self, dataset: &str) -> Result<bool> {
+        Ok(self.lock().contains_key(dataset))
+    }
+
+    fn list_recursive(&self, dataset: &str) -> Result<Vec<String>> {
+        let datasets = self.lock();
+        if !datasets.contains_key(dataset) {
+            return Err(Error::Zfs(format!(
+                "Failed to list datasets for {}",
+                dataset
+            )));
+        }
+        // BTreeMap ordering lists parents before their children
+        Ok(datasets
+            .keys()
+            .filter(|name| Self::is_descendant(dataset, name))
+            .cloned()
+            .collect())
+    }
+}
@@ -2,93 +2,351 @@
 extern crate pamsm;
 
 use pamsm::{Pam, PamError, PamFlags, PamLibExt, PamServiceModule};
+use shavee_core::trace::{Level, LogSink, Logger};
+use shavee_core::unlock::{self, UnlockAction, Unlocker};
+use shavee_core::yubikey;
 use shavee_core::zfs::Dataset;
-use std::{error::Error, io::Write, process::Command};
+use std::fmt;
+use std::time::{Duration, Instant};
 struct PamShavee;
 
+/// `pam_chauthtok` flags from `<security/_pam_types.h>`, not exposed by pamsm.
+/// The first pass only checks that the password can be changed, the second one changes it.
+const PAM_PRELIM_CHECK: std::os::raw::c_int = 0x4000;
+const PAM_UPDATE_AUTHTOK: std::os::raw::c_int = 0x2000;
+
+/// Identifier of the module's log events.
+const LOG_IDENT: &str = "shavee-pam";
+
 impl PamShavee {
-    fn perform_authentication(pam: &Pam, args: &[String]) -> Result<(), Box<dyn Error>> {
-        let dataset_name = get_user_dataset_name(pam, args).map_err(|e| e.to_string())?;
-        let password = unwrap_pam_user_pass(pam.get_authtok(None), PamError::AUTHINFO_UNAVAIL)
-            .map_err(|e| format!("PAM error getting password: {}", e))?;
-
-        let mut child = Command::new("shavee")
-            .arg("-marz")
-            .arg(&dataset_name)
-            .stdin(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .spawn()?;
-
-        if let Some(mut stdin) = child.stdin.take() {
-            stdin.write_all(password.as_bytes())?;
-        } else {
-            return Err("Failed to open child stdin".into());
-        }
+    fn perform_authentication(pam: &Pam, args: &[String], logger: &Logger) -> Result<(), PamError> {
+        let dataset = get_user_dataset(pam, args, logger)?;
+        let password = unwrap_pam_user_pass(
+            pam,
+            pam.get_authtok(None),
+            PamError::AUTHINFO_UNAVAIL,
+            logger,
+        )?;
 
-        let output = child.wait_with_output()?;
+        let started = Instant::now();
+        let report = Unlocker::new(std::slice::from_ref(&dataset), password.as_bytes())
+            .recursive(true)
+            .action(UnlockAction::Mount)
+            .run();
+        let duration = started.elapsed();
 
-        if output.status.success() {
-            Ok(())
-        } else {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            Err(format!(
-                "shavee command failed with status {} and stderr: {}",
-                output.status, stderr
-            )
-            .into())
+        match &report {
+            Ok(report) => {
+                for outcome in &report.outcomes {
+                    log_outcome(
+                        logger,
+                        "authentication",
+                        pam,
+                        &outcome.dataset,
+                        outcome.second_factor.as_deref(),
+                        duration,
+                        outcome.result.as_ref(),
+                    );
+                }
+            }
+            Err(e) => log_outcome::<&str>(
+                logger,
+                "authentication",
+                pam,
+                &dataset,
+                None,
+                duration,
+                Err(e),
+            ),
         }
+
+        report
+            .and_then(|report| report.into_result())
+            .map_err(|e| pam_error(&e, PamError::AUTH_ERR))
     }
 
-    fn perform_close_session(pam: &Pam, args: &[String]) -> Result<(), Box<dyn Error>> {
-        let dataset_name = get_user_dataset_name(pam, args).map_err(|e| e.to_string())?;
-        let dataset = Dataset::new(dataset_name)?;
-        dataset.unmount()?;
-        dataset.unload_key(true)?;
-        Ok(())
+    fn perform_chauthtok(
+        pam: &Pam,
+        dataset: &Dataset,
+        factor: &str,
+        logger: &Logger,
+    ) -> Result<(), PamError> {
+        // Without the old password (e.g. root setting a user's password) the current key
+        // can't be derived, so the dataset can't follow the change
+        let old_password = unwrap_pam_user_pass(
+            pam,
+            pam.get_cached_oldauthtok(),
+            PamError::AUTHTOK_RECOVERY_ERR,
+            logger,
+        )
+        .inspect_err(|_| {
+            logger
+                .event(
+                    Level::Warning,
+                    "old password unavailable, the dataset keeps its current key",
+                )
+                .field("user", pam_user(pam))
+                .field("dataset", dataset)
+                .field("outcome", "failure")
+                .send()
+        })?;
+        let new_password =
+            unwrap_pam_user_pass(pam, pam.get_authtok(None), PamError::AUTHTOK_ERR, logger)?;
+
+        let started = Instant::now();
+        let yubikeys = yubikey::fetch_yubikeys().ok();
+        let result = unlock::rekey(
+            dataset,
+            old_password.as_bytes(),
+            new_password.as_bytes(),
+            None,
+            None,
+            None,
+            yubikeys.as_deref(),
+        );
+        // The stored second factor and key check setting are kept, see `unlock::rekey`
+        let status = result.as_ref().map(|_| "re-keyed");
+        log_outcome(
+            logger,
+            "password change",
+            pam,
+            dataset,
+            Some(factor),
+            started.elapsed(),
+            status,
+        );
+        result.map_err(|e| pam_error(&e, PamError::AUTHTOK_ERR))
+    }
+
+    fn perform_close_session(pam: &Pam, args: &[String], logger: &Logger) -> Result<(), PamError> {
+        let dataset = get_user_dataset(pam, args, logger).map_err(|_| PamError::SESSION_ERR)?;
+
+        // Locks the dataset and its children like `shavee -l -r`, legacy mountpoints included
+        let started = Instant::now();
+        let report = unlock::lock(std::slice::from_ref(&dataset), true, false);
+        let duration = started.elapsed();
+
+        match &report {
+            Ok(report) => {
+                for outcome in &report.outcomes {
+                    log_outcome(
+                        logger,
+                        "session close",
+                        pam,
+                        &outcome.dataset,
+                        outcome.second_factor.as_deref(),
+                        duration,
+                        outcome.result.as_ref(),
+                    );
+                }
+            }
+            Err(e) => log_outcome::<&str>(
+                logger,
+                "session close",
+                pam,
+                &dataset,
+                None,
+                duration,
+                Err(e),
+            ),
+        }
+
+        report
+            .and_then(|report| report.into_result())
+            .map_err(|_| PamError::SESSION_ERR)
     }
 }
 
-// TODO: Need unit tests implemented for the PAM module functions
+// The entry points need a PAM handle, the helpers they are built on are tested in `tests`
 impl PamServiceModule for PamShavee {
     fn open_session(_: Pam, _: PamFlags, _: Vec<String>) -> PamError {
         PamError::SUCCESS
     }
 
     fn close_session(pam: Pam, _flags: PamFlags, args: Vec<String>) -> PamError {
-        match Self::perform_close_session(&pam, &args) {
+        let logger = module_logger(&args);
+        match Self::perform_close_session(&pam, &args, &logger) {
             Ok(_) => PamError::SUCCESS,
-            Err(e) => {
-                eprintln!("shavee-pam: session close error: {}", e);
-                PamError::SESSION_ERR
-            }
+            Err(e) => e,
         }
     }
 
     fn authenticate(pam: Pam, _flags: PamFlags, args: Vec<String>) -> PamError {
-        match Self::perform_authentication(&pam, &args) {
+        let logger = module_logger(&args);
+        match Self::perform_authentication(&pam, &args, &logger) {
             Ok(_) => PamError::SUCCESS,
-            Err(e) => {
-                eprintln!("shavee-pam: authentication error: {}", e);
-                PamError::AUTH_ERR
-            }
+            Err(e) => e,
         }
     }
 
     fn setcred(_: Pam, _: PamFlags, _: Vec<String>) -> PamError {
         PamError::SUCCESS
     }
+
+    fn chauthtok(pam: Pam, flags: PamFlags, args: Vec<String>) -> PamError {
+        let logger = module_logger(&args);
+        let dataset = match get_user_dataset(&pam, &args, &logger) {
+            Ok(dataset) => dataset,
+            Err(e) => return e,
+        };
+
+        // Users without a Shavee home dataset must still be able to change their password
+        let factor = match managed_second_factor(&dataset) {
+            Ok(Some(factor)) => factor,
+            Ok(None) => return PamError::IGNORE,
+            Err(e) => {
+                logger
+                    .event(Level::Warning, format!("failed to read dataset: {}", e))
+                    .field("user", pam_user(&pam))
+                    .field("dataset", &dataset)
+                    .field("outcome", "ignored")
+                    .send();
+                return PamError::IGNORE;
+            }
+        };
+
+        if flags.bits() & PAM_PRELIM_CHECK != 0 {
+            return PamError::SUCCESS;
+        }
+        if flags.bits() & PAM_UPDATE_AUTHTOK == 0 {
+            return PamError::SERVICE_ERR;
+        }
+        match Self::perform_chauthtok(&pam, &dataset, &factor, &logger) {
+            Ok(_) => PamError::SUCCESS,
+            Err(e) => e,
+        }
+    }
 }
 
-fn get_user_dataset_name(pam: &Pam, args: &[String]) -> Result<String, PamError> {
+/// Returns the logger selected by the module options following the base dataset.
+///
+/// `log=stderr` (the default), `log=syslog` or `log=journald` picks the sink. Invalid and
+/// unknown options are reported and ignored, so a typo doesn't break logins.
+fn module_logger(args: &[String]) -> Logger {
+    let mut sink = LogSink::default();
+    let mut invalid = Vec::new();
+    for option in args.iter().skip(1) {
+        match option.split_once('=') {
+            Some(("log", value)) => match value.parse() {
+                Ok(value) => sink = value,
+                Err(_) => invalid.push(option),
+            },
+            _ => invalid.push(option),
+        }
+    }
+
+    let logger = Logger::new(LOG_IDENT, sink);
+    for option in invalid {
+        logger
+            .event(Level::Warning, "ignoring invalid module option")
+            .field("option", option)
+            .send();
+    }
+    logger
+}
+
+/// Logs the outcome of `operation` on `dataset`, with the user and second factor.
+fn log_outcome<T: fmt::Display>(
+    logger: &Logger,
+    operation: &str,
+    pam: &Pam,
+    dataset: &Dataset,
+    factor: Option<&str>,
+    duration: Duration,
+    result: Result<T, &shavee_core::Error>,
+) {
+    let event = match &result {
+        Ok(status) => logger.event(Level::Info, format!("{} succeeded: {}", operation, status)),
+        Err(e) => logger.event(Level::Error, format!("{} error: {}", operation, e)),
+    };
+    event
+        .field("user", pam_user(pam))
+        .field("dataset", dataset)
+        .field("factor", factor.unwrap_or("unknown"))
+        .field(
+            "outcome",
+            if result.is_ok() { "success" } else { "failure" },
+        )
+        .field("duration", format_duration(duration))
+        .send();
+}
+
+/// Returns the user PAM already resolved for logging, without prompting.
+fn pam_user(pam: &Pam) -> String {
+    match pam.get_cached_user() {
+        Ok(Some(user)) => user.to_string_lossy().into_owned(),
+        _ => "unknown".to_string(),
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{}ms", duration.as_millis())
+}
+
+/// Returns the second factor of `dataset` if it exists and was created or re-keyed by Shavee,
+/// `None` otherwise.
+fn managed_second_factor(dataset: &Dataset) -> shavee_core::Result<Option<String>> {
+    if !dataset.exists()? {
+        return Ok(None);
+    }
+    Ok(dataset.get_shavee_properties()?.second_factor)
+}
+
+/// Logs an error of the module setup for the user, which fails the PAM call.
+fn log_failure(logger: &Logger, pam: &Pam, message: String) {
+    logger
+        .event(Level::Error, message)
+        .field("user", pam_user(pam))
+        .field("outcome", "failure")
+        .send();
+}
+
+/// Maps a Shavee error to the PAM error reported for it.
+///
+/// Missing second factors and unreadable configuration mean the authentication information is
+/// unavailable, a rejected key means the password was wrong, everything else uses `fallback`.
+fn pam_error(error: &shavee_core::Error, fallback: PamError) -> PamError {
+    match error {
+        shavee_core::Error::Yubikey(_)
+        | shavee_core::Error::Io(_)
+        | shavee_core::Error::Curl(_)
+        | shavee_core::Error::InvalidInput(_)
+        | shavee_core::Error::Properties(_) => PamError::AUTHINFO_UNAVAIL,
+        shavee_core::Error::Zfs(_) => PamError::SERVICE_ERR,
+        _ => fallback,
+    }
+}
+
+fn get_user_dataset(pam: &Pam, args: &[String], logger: &Logger) -> Result<Dataset, PamError> {
+    let dataset_name = get_user_dataset_name(pam, args, logger)?;
+    Dataset::new(dataset_name).map_err(|e| {
+        log_failure(logger, pam, format!("invalid dataset name: {}", e));
+        PamError::SERVICE_ERR
+    })
+}
+
+fn get_user_dataset_name(pam: &Pam, args: &[String], logger: &Logger) -> Result<String, PamError> {
     if args.is_empty() {
-        eprintln!("PAM module arguments are missing: no base dataset provided.");
+        log_failure(
+            logger,
+            pam,
+            "PAM module arguments are missing: no base dataset provided".to_string(),
+        );
         return Err(PamError::SERVICE_ERR);
     }
 
     let mut dataset_name = args[0].clone();
     dataset_name.truncate(dataset_name.trim_end_matches('/').len());
 
-    let user = unwrap_pam_user_pass(pam.get_user(Some("Username: ")), PamError::USER_UNKNOWN)?;
+    let user = pam
+        .get_user(Some("Username: "))
+        .ok()
+        .flatten()
+        .and_then(|user| user.to_str().ok())
+        .ok_or_else(|| {
+            log_failure(logger, pam, "failed to get the user name".to_string());
+            PamError::USER_UNKNOWN
+        })?;
 
     dataset_name.push('/');
     dataset_name.push_str(user);
@@ -96,18 +354,17 @@ fn get_user_dataset_name(pam: &Pam, args: &[String]) -> Result<String, PamError>
 }
 
 fn unwrap_pam_user_pass<'a>(
+    pam: &Pam,
     pam_key: Result<Option<&'a std::ffi::CStr>, PamError>,
     pam_error: PamError,
+    logger: &Logger,
 ) -> Result<&'a str, PamError> {
     let c_str = pam_key
-        .map_err(|e| {
-            eprintln!("Error getting PAM item: {}", e);
-            e
-        })?
+        .inspect_err(|&e| log_failure(logger, pam, format!("error getting PAM item: {}", e)))?
         .ok_or(pam_error)?;
 
     c_str.to_str().map_err(|e| {
-        eprintln!("Error converting PAM string: {}", e);
+        log_failure(logger, pam, format!("error converting PAM string: {}", e));
         pam_error
     })
 }
@@ -117,3 +374,92 @@ mod pam_entry {
     use super::*;
     pam_module!(PamShavee);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shavee_core::structs::{KeyFormat, TwoFactorMode};
+    use shavee_core::zfs::InMemoryBackend;
+    use std::sync::Arc;
+
+    #[test]
+    fn pam_error_mapping() {
+        use shavee_core::Error;
+        let cases = [
+            (
+                Error::Yubikey("Device Not Found".into()),
+                PamError::AUTHINFO_UNAVAIL,
+            ),
+            (
+                Error::InvalidInput("no salt".into()),
+                PamError::AUTHINFO_UNAVAIL,
+            ),
+            (Error::IncorrectKey("wrong".into()), PamError::AUTH_ERR),
+            (Error::Zfs("busy".into()), PamError::SERVICE_ERR),
+            (Error::Crypto("argon2".into()), PamError::AUTH_ERR),
+        ];
+        for (error, expected) in cases {
+            assert_eq!(pam_error(&error, PamError::AUTH_ERR), expected);
+        }
+    }
+
+    #[test]
+    fn module_log_option() {
+        let args = |options: &[&str]| -> Vec<String> {
+            std::iter::once("zroot/data/home")
+                .chain(options.iter().copied())
+                .map(String::from)
+                .collect()
+        };
+        assert_eq!(module_logger(&args(&[])).sink(), LogSink::Stderr);
+        assert_eq!(
+            module_logger(&args(&["log=syslog"])).sink(),
+            LogSink::Syslog
+        );
+        assert_eq!(
+            module_logger(&args(&["log=journald"])).sink(),
+            LogSink::Journald
+        );
+        // Invalid options are ignored
+        assert_eq!(
+            module_logger(&args(&["log=eventlog", "debug"])).sink(),
+            LogSink::Stderr
+        );
+    }
+
+    #[test]
+    fn manages_only_shavee_datasets() {
+        let backend = Arc::new(InMemoryBackend::new());
+        backend.add_dataset("pool");
+        backend.add_encrypted_dataset("pool/plain", "secret");
+        let dataset =
+            |name: &str| Dataset::with_backend(name.to_string(), backend.clone()).unwrap();
+
+        assert_eq!(
+            managed_second_factor(&dataset("pool/missing")).unwrap(),
+            None
+        );
+        assert_eq!(managed_second_factor(&dataset("pool/plain")).unwrap(), None);
+
+        dataset("pool/home")
+            .create_2fa(
+                "c2VjcmV0",
+                TwoFactorMode::Password,
+                "c2FsdA",
+                KeyFormat::Passphrase,
+                &Default::default(),
+                false,
+            )
+            .unwrap();
+        assert_eq!(
+            managed_second_factor(&dataset("pool/home")).unwrap(),
+            Some("Password".to_string())
+        );
+        // A separately keyed child doesn't take the configuration of its parent
+        backend.add_encrypted_dataset("pool/home/other", "secret");
+        assert_eq!(
+            managed_second_factor(&dataset("pool/home/other")).unwrap(),
+            None
+        );
+    }
+}